@@ -0,0 +1,35 @@
+//! Compares the proof generation throughput of `MerkleTree` (a vector per layer) and `FlatMerkleTree` (all the
+//! layers in one vector). Run with `cargo bench --bench proofs`.
+//! Without `--bench` (e.g. under `cargo test --all-targets`, which runs it unoptimized), it only runs on a few leaves
+//! as a smoke test.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use code_test::{db::serialize_user, flat::FlatMerkleTree, MerkleProof, MerkleTree, Sha256Algorithm};
+
+const NUM_LEAVES: u64 = 1 << 18;
+const SMOKE_LEAVES: u64 = 1 << 8;
+const ROUNDS: usize = 5;
+
+// the best time over a few rounds of proving every leaf in a scattered order, to defeat the prefetcher
+fn time_proofs(num_leaves: usize, get_proof_at: impl Fn(usize) -> Option<MerkleProof<32>>) -> Duration {
+    (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        for i in 0..num_leaves {
+            black_box(get_proof_at(i.wrapping_mul(7919) % num_leaves));
+        }
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    // cargo bench passes --bench to the benchmarks
+    let num_leaves = if std::env::args().any(|arg| arg == "--bench") { NUM_LEAVES } else { SMOKE_LEAVES };
+    let values: Vec<Vec<u8>> = (0..num_leaves).map(|i| serialize_user(i, i * 3)).collect();
+    let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), b"Leaf".to_vec(), b"Branch".to_vec());
+    let flat = FlatMerkleTree::<32, Sha256Algorithm>::build(values, b"Leaf".to_vec(), b"Branch".to_vec());
+    assert_eq!(tree.get_root(), flat.get_root());
+    for (name, elapsed) in [("MerkleTree", time_proofs(num_leaves as usize, |i| tree.get_proof_at(i))), ("FlatMerkleTree", time_proofs(num_leaves as usize, |i| flat.get_proof_at(i)))] {
+        let throughput = num_leaves as f64 / elapsed.as_secs_f64();
+        println!("{:<16} {:>10.0} proofs/s ({:?} for {} proofs)", name, throughput, elapsed, num_leaves);
+    }
+}
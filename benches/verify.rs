@@ -0,0 +1,69 @@
+//! Compares the proof verification throughput of `verify_root`, which hashes the leaf tag and the branch tag once per
+//! proof (see `HashAlgorithm::prepare_tag`), with hashing the tag at every step of the fold, and counts the tag
+//! hashes of each. Run with `cargo bench --bench verify`.
+//! Without `--bench` (e.g. under `cargo test --all-targets`, which runs it unoptimized), it only runs on a few leaves
+//! as a smoke test.
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use code_test::{db::serialize_user, merkle::{verify_root, PreparedTag}, HashAlgorithm, MerkleProof, MerkleTree, Padding, Sha256Algorithm};
+
+const NUM_LEAVES: u64 = 1 << 18;
+const SMOKE_LEAVES: u64 = 1 << 8;
+const ROUNDS: usize = 5;
+const LEAF_TAG: &[u8] = b"Leaf";
+const BRANCH_TAG: &[u8] = b"Branch";
+
+static TAG_HASHES: AtomicUsize = AtomicUsize::new(0);
+
+// SHA256 with BIP340 tags, counting how many times a tag is hashed: once per prepared tag
+struct Prepared;
+impl HashAlgorithm<32> for Prepared {
+    const NAME: &'static str = "sha256";
+    const OUTPUT_SIZE: usize = 32;
+    fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+        TAG_HASHES.fetch_add(1, Ordering::Relaxed);
+        Sha256Algorithm::tagged_hash(tag, data)
+    }
+    fn prepare_tag(tag: &[u8]) -> PreparedTag<32> {
+        TAG_HASHES.fetch_add(1, Ordering::Relaxed);
+        Sha256Algorithm::prepare_tag(tag)
+    }
+}
+
+// the same, but with the default prepare_tag, so the tag is hashed at every step of the fold
+struct Unprepared;
+impl HashAlgorithm<32> for Unprepared {
+    const NAME: &'static str = "sha256";
+    const OUTPUT_SIZE: usize = 32;
+    fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+        TAG_HASHES.fetch_add(1, Ordering::Relaxed);
+        Sha256Algorithm::tagged_hash(tag, data)
+    }
+}
+
+// the best time over a few rounds of verifying every proof, and the number of tag hashes per proof
+fn time_verify<H: HashAlgorithm<32>>(tree: &MerkleTree<32, Sha256Algorithm>, values: &[Vec<u8>], proofs: &[MerkleProof<32>]) -> (Duration, f64) {
+    let root = tree.get_root();
+    TAG_HASHES.store(0, Ordering::Relaxed);
+    let elapsed = (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        for (index, (value, proof)) in values.iter().zip(proofs).enumerate() {
+            assert!(black_box(verify_root::<32, H>(value, proof, index, values.len(), &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate)));
+        }
+        start.elapsed()
+    }).min().unwrap();
+    (elapsed, TAG_HASHES.load(Ordering::Relaxed) as f64 / (ROUNDS * values.len()) as f64)
+}
+
+fn main() {
+    // cargo bench passes --bench to the benchmarks
+    let num_leaves = if std::env::args().any(|arg| arg == "--bench") { NUM_LEAVES } else { SMOKE_LEAVES };
+    let values: Vec<Vec<u8>> = (0..num_leaves).map(|i| serialize_user(i, i * 3)).collect();
+    let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+    let proofs: Vec<MerkleProof<32>> = (0..values.len()).map(|i| tree.get_proof_at(i).unwrap()).collect();
+    for (name, (elapsed, tag_hashes)) in [("prepared tags", time_verify::<Prepared>(&tree, &values, &proofs)), ("unprepared tags", time_verify::<Unprepared>(&tree, &values, &proofs))] {
+        let throughput = num_leaves as f64 / elapsed.as_secs_f64();
+        println!("{:<16} {:>10.0} proofs/s ({:?} for {} proofs), {:.0} tag hashes per proof", name, throughput, elapsed, num_leaves, tag_hashes);
+    }
+}
@@ -0,0 +1,57 @@
+#ifndef MERKLE_H
+#define MERKLE_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The direction of a proof item that is a left sibling (`merkle::LEFT`).
+ */
+#define MERKLE_DIRECTION_LEFT 0
+
+/**
+ * The direction of a proof item that is a right sibling (`merkle::RIGHT`).
+ */
+#define MERKLE_DIRECTION_RIGHT 1
+
+/**
+ * Pads lone nodes by pairing them with themselves (`Padding::Duplicate`).
+ */
+#define MERKLE_PADDING_DUPLICATE 0
+
+/**
+ * Pads lone nodes by promoting them unchanged (`Padding::Promote`).
+ */
+#define MERKLE_PADDING_PROMOTE 1
+
+/**
+ * Verifies the proof for the leaf with the given value, at position `index` in a tree with `num_leaves` leaves
+ * built with the given padding (`MERKLE_PADDING_DUPLICATE` or `MERKLE_PADDING_PROMOTE`), against the 32-byte root.
+ * The proof has `proof_len` items, whose directions are at `directions_ptr` and hashes at `hashes_ptr`. Returns
+ * false if the proof is invalid or the arguments are malformed.
+ *
+ * # Safety
+ *
+ * Each pointer must either be valid for reads of the given number of bytes (`proof_len` for `directions_ptr`,
+ * 32 times `proof_len` for `hashes_ptr` and 32 for `root_ptr`), or be null with a length of zero.
+ */
+bool merkle_verify(const uint8_t *leaf_ptr,
+                   size_t leaf_len,
+                   const uint8_t *leaf_tag_ptr,
+                   size_t leaf_tag_len,
+                   const uint8_t *branch_tag_ptr,
+                   size_t branch_tag_len,
+                   const uint8_t *directions_ptr,
+                   const uint8_t *hashes_ptr,
+                   size_t proof_len,
+                   size_t index,
+                   size_t num_leaves,
+                   uint8_t padding,
+                   const uint8_t *root_ptr);
+
+#endif  /* MERKLE_H */
@@ -0,0 +1,55 @@
+//! An append-only log of the proofs served, configured with `--audit-log <path>`
+//!
+//! Each served proof is recorded as a line of JSON (JSONL) with the time it was served (in seconds since the Unix
+//! epoch), the user it proves, the root it was built from and the SHA256 hash of the proof in its compact binary
+//! form (see `MerkleProof::to_compact`), which gives an after-the-fact record of what was disclosed to whom.
+use std::{fs::{File, OpenOptions}, io::{self, Write}, sync::{Mutex, PoisonError}, time::{SystemTime, UNIX_EPOCH}};
+use code_test::merkle::{MerkleProof, MerkleRoot};
+use data_encoding::HEXLOWER;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct AuditEntry {
+    timestamp: u64,
+    user_id: u64,
+    root: String,
+    proof_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the log at the given path for appending, creating it if it does not exist.
+    pub fn open(path: &str) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    /// Appends the entries of proofs of users against the same root (e.g. those of a batch), and syncs them to the
+    /// disk at once. This blocks until the data is on the disk, so async callers should run it on a blocking thread.
+    pub fn record<'a>(&self, root: &MerkleRoot<32>, proofs: impl IntoIterator<Item = (u64, &'a MerkleProof<32>)>) -> io::Result<()> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        let root = format!("0x{}", HEXLOWER.encode(&root.0));
+        let mut lines = Vec::new();
+        for (user_id, proof) in proofs {
+            let entry = AuditEntry {
+                timestamp,
+                user_id,
+                root: root.clone(),
+                proof_hash: format!("0x{}", HEXLOWER.encode(&Sha256::digest(proof.to_compact())))
+            };
+            serde_json::to_writer(&mut lines, &entry)?;
+            lines.push(b'\n');
+        }
+        // a single write, so that concurrent entries are never interleaved; a failed write leaves the file as it was
+        // for the next one, so a poisoned lock can be recovered
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        file.write_all(&lines)?;
+        // writing only hands the data to the OS, which may lose it in a crash
+        file.sync_data()
+    }
+}
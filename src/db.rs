@@ -1,53 +1,1121 @@
-use crate::merkle::{MerkleTree, HashAlgorithm, MerkleRoot, MerkleProof};
-use std::collections::HashMap;
+use crate::merkle::{self, MerkleTree, HashAlgorithm, MerkleRoot, MerkleProof, Padding};
+use crate::mmr::MmrTree;
+use crate::flat::FlatMerkleTree;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use std::convert::Infallible;
+use std::{fmt, io};
+#[cfg(feature = "serde")]
+use std::{fs, path::Path};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use data_encoding::HEXLOWER;
 
-// This serves as the witness for a particular Merkle tree implementation
-pub trait MerkleTreeImpl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {}
-impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTreeImpl<HASH_SIZE, H> for MerkleTree<HASH_SIZE, H> {}
+// The operations the database needs from a particular Merkle tree implementation
+pub trait MerkleTreeImpl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
+    fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self;
+    fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> Self;
+    // rebuilds the tree from new leaf values, keeping the tags and the other options
+    fn rebuild(&mut self, values: Vec<Vec<u8>>);
+    fn get_root(&self) -> MerkleRoot<HASH_SIZE>;
+    fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>>;
+    fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool;
+    fn leaf_tag(&self) -> &[u8];
+    fn branch_tag(&self) -> &[u8];
+    fn append(&mut self, value: &[u8]) -> usize;
+    fn update_leaf(&mut self, index: usize, value: &[u8]);
+    // the number of hashes stored at each level of the tree, from the leaves up
+    fn layer_sizes(&self) -> Vec<usize>;
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTreeImpl<HASH_SIZE, H> for MerkleTree<HASH_SIZE, H> {
+    fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self {
+        MerkleTree::build(values, leaf_tag, branch_tag)
+    }
+
+    fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> Self {
+        MerkleTree::build_with_options(values, leaf_tag, branch_tag, padding, sorted_pairs)
+    }
+
+    fn rebuild(&mut self, values: Vec<Vec<u8>>) {
+        self.rebuild_in_place(values)
+    }
+
+    fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
+        self.get_root()
+    }
+
+    fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        self.get_proof(value)
+    }
+
+    fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        self.verify(value, proof)
+    }
+
+    fn leaf_tag(&self) -> &[u8] {
+        self.leaf_tag()
+    }
+
+    fn branch_tag(&self) -> &[u8] {
+        self.branch_tag()
+    }
+
+    fn append(&mut self, value: &[u8]) -> usize {
+        self.append(value)
+    }
+
+    fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        self.update_leaf(index, value)
+    }
+
+    fn layer_sizes(&self) -> Vec<usize> {
+        self.layers().iter().map(Vec::len).collect()
+    }
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTreeImpl<HASH_SIZE, H> for FlatMerkleTree<HASH_SIZE, H> {
+    fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self {
+        FlatMerkleTree::build(values, leaf_tag, branch_tag)
+    }
+
+    fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> Self {
+        FlatMerkleTree::build_with_options(values, leaf_tag, branch_tag, padding, sorted_pairs)
+    }
+
+    fn rebuild(&mut self, values: Vec<Vec<u8>>) {
+        self.rebuild_in_place(values)
+    }
+
+    fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
+        self.get_root()
+    }
+
+    fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        self.get_proof(value)
+    }
+
+    fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        self.verify(value, proof)
+    }
+
+    fn leaf_tag(&self) -> &[u8] {
+        self.leaf_tag()
+    }
+
+    fn branch_tag(&self) -> &[u8] {
+        self.branch_tag()
+    }
+
+    fn append(&mut self, value: &[u8]) -> usize {
+        self.append(value)
+    }
+
+    fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        self.update_leaf(index, value)
+    }
+
+    fn layer_sizes(&self) -> Vec<usize> {
+        self.layer_sizes()
+    }
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTreeImpl<HASH_SIZE, H> for MmrTree<HASH_SIZE, H> {
+    fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self {
+        MmrTree::build(values, leaf_tag, branch_tag)
+    }
+
+    // a Merkle mountain range never pads, so the padding strategy does not apply
+    fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, _padding: Padding, sorted_pairs: bool) -> Self {
+        let mut tree = if sorted_pairs { MmrTree::new_sorted(leaf_tag, branch_tag) } else { MmrTree::new(leaf_tag, branch_tag) };
+        for value in values {
+            tree.append(&value);
+        }
+        tree
+    }
+
+    fn rebuild(&mut self, values: Vec<Vec<u8>>) {
+        let tree = if self.sorted_pairs() { MmrTree::new_sorted } else { MmrTree::new };
+        *self = tree(self.leaf_tag().to_vec(), self.branch_tag().to_vec());
+        for value in values {
+            self.append(&value);
+        }
+    }
+
+    fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
+        self.get_root()
+    }
+
+    fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        self.get_proof(value)
+    }
+
+    fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        self.verify(value, proof)
+    }
+
+    fn leaf_tag(&self) -> &[u8] {
+        self.leaf_tag()
+    }
+
+    fn branch_tag(&self) -> &[u8] {
+        self.branch_tag()
+    }
+
+    fn append(&mut self, value: &[u8]) -> usize {
+        self.append(value)
+    }
+
+    fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        self.update_leaf(index, value)
+    }
+
+    fn layer_sizes(&self) -> Vec<usize> {
+        self.layer_sizes()
+    }
+}
 
 /* 
  * The Merkle tree should logically be part of the database. It is generic over the Merkle tree implementation
  * as when the database grows large in production, the Merkle tree might be stored on disk or otherwise. Moreover,
- * one may want to consider an incremental Merkle tree implementation, such as the Merkle mountain range.
+ * one may want to consider an incremental Merkle tree implementation, such as the Merkle mountain range (see MmrTree).
  * 
- * Currently there is no functionality to add users because the Merkle tree is not online, but that could be added
- * by simply inheriting the UserDatabase trait.
+ * Adding, updating and removing users is provided by the MutableUserDatabase trait, which inherits UserDatabase.
+ *
+ * A user may hold several assets, each with its own balance. Databases created from (user ID, balance) pairs hold
+ * DEFAULT_ASSET only, and the operations without an asset (set_balance, remove_user, verify_proof) are about it.
+ * So are the single-asset views of InMemoryDatabase (users, diffs and snapshots), and thus the server.
  */
 pub trait UserDatabase<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> {
     fn create(user_data: Vec<(u64, u64)>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self;
-    fn get_balance(&self, user_id: u64) -> Option<u64>;
-    fn get_root(&self) -> MerkleRoot<HASH_SIZE>;
+    // the balance of the user in the asset, or None if the user does not exist or does not hold the asset
+    fn get_balance(&self, user_id: u64, asset: AssetId) -> Option<u64>;
+    fn contains_user(&self, user_id: u64) -> bool;
+    // the root, or None if the database has no users (e.g. after the last one was removed), since an empty tree has
+    // no root
+    fn get_root(&self) -> Option<MerkleRoot<HASH_SIZE>>;
     fn get_proof(&self, user_id: u64) -> Option<MerkleProof<HASH_SIZE>>;
+    // like get_proof, but tells a user that does not exist apart from one whose leaf is missing from the tree
+    fn try_get_proof(&self, user_id: u64) -> Result<MerkleProof<HASH_SIZE>, ProofError> {
+        if !self.contains_user(user_id) {
+            return Err(ProofError::UserNotFound(user_id));
+        }
+        self.get_proof(user_id).ok_or(ProofError::Desync(user_id))
+    }
+    fn verify_proof(&self, user_id: u64, balance: u64, proof: &MerkleProof<HASH_SIZE>) -> bool;
+    // the sum of all balances in the asset, or None if it overflows
+    fn total_balance(&self, asset: AssetId) -> Option<u64>;
+}
+
+pub trait MutableUserDatabase<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>>: UserDatabase<HASH_SIZE, H, M> {
+    // adds the user if it does not exist yet, otherwise updates (or adds) its balance in the asset
+    fn set_asset_balance(&mut self, user_id: u64, asset: AssetId, balance: u64);
+    fn set_balance(&mut self, user_id: u64, balance: u64) {
+        self.set_asset_balance(user_id, DEFAULT_ASSET, balance)
+    }
+    // removes the user, returning its balances in all assets, or None if the user does not exist
+    fn remove_user(&mut self, user_id: u64) -> Option<HashMap<AssetId, u64>>;
+}
+
+#[derive(Clone)]
+pub struct InMemoryDatabase<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H> = MerkleTree<HASH_SIZE, H>> {
+    users: HashMap<u64, HashMap<AssetId, u64>>,
+    user_ids: Vec<u64>, // user IDs in the order of the leaves
+    indices: HashMap<u64, usize>, // the index of the leaf of each user, i.e. its position in user_ids
+    leaf_hashes: HashMap<[u8; HASH_SIZE], u64>, // the user of each leaf hash
+    removed: HashSet<u64>, // tombstones of the users that were removed (and not added back)
+    encode_leaf: LeafEncoder,
+    leaf_type: Option<u8>, // the type byte prepended to each leaf, if any (see DatabaseBuilder::leaf_type)
+    padding: Padding, // the options the tree was built with, which the tree itself may not record (see DatabaseBuilder)
+    sorted_pairs: bool,
+    tree: M,
+    build_time: Duration, // how long building the tree took
+    _hasher: std::marker::PhantomData<H>
+}
+
+/*
+ * The serialized leaf starts with a version byte, so that the leaf format can change in the future without
+ * silently breaking verification: a verifier can reject proofs built under a leaf version it does not know.
+ */
+pub const LEAF_VERSION: u8 = 1;
+
+pub fn serialize_user(user_id: u64, balance: u64) -> Vec<u8> {
+    let mut serialized = vec![LEAF_VERSION];
+    serialized.extend_from_slice(format!("({},{})", user_id, balance).as_bytes());
+    serialized
+}
+
+/// Serializes a user as a fixed-width binary leaf, the user ID then the balance as 8-byte big-endian integers, so that
+/// every leaf is 16 bytes whatever the balance. Unlike `serialize_user`, there is no version byte. Select it with
+/// `DatabaseBuilder::leaf_encoder(serialize_user_fixed)`.
+pub fn serialize_user_fixed(user_id: u64, balance: u64) -> Vec<u8> {
+    [user_id.to_be_bytes(), balance.to_be_bytes()].concat()
+}
+
+/// Decodes a leaf value produced by `serialize_user` into the user ID and the balance, or returns None if the value
+/// is not such a leaf (e.g. another version, a multi-asset leaf or a typed leaf).
+pub fn deserialize_user(value: &[u8]) -> Option<(u64, u64)> {
+    let rest = value.strip_prefix(&[LEAF_VERSION])?;
+    let (user_id, balance) = std::str::from_utf8(rest).ok()?.strip_prefix('(')?.strip_suffix(')')?.split_once(',')?;
+    // only plain digits, as written by serialize_user (parse would also accept a sign)
+    let parse = |digits: &str| if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) { digits.parse().ok() } else { None };
+    Some((parse(user_id)?, parse(balance)?))
+}
+
+/// The outcome of `verify_with_policy`: the proof either verifies and the balance satisfies the policy, or the leaf
+/// is not in the tree, or it is but its balance cannot be read from it or violates the policy.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyResult {
+    Valid,
+    NotIncluded,
+    MalformedLeaf,
+    OutOfRange(u64)
+}
+
+impl VerifyResult {
+    /// Returns whether both the inclusion and the policy checks passed.
+    pub fn is_valid(&self) -> bool {
+        *self == VerifyResult::Valid
+    }
+}
+
+/// Verifies the inclusion of the leaf value as `merkle::verify_root`, then decodes the balance from the value (see
+/// `deserialize_user`) and checks that it is within `[min, max]`, e.g. `[1, cap]` for a nonzero balance under a cap.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_with_policy<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(value: &[u8], proof: &MerkleProof<HASH_SIZE>, index: usize, num_leaves: usize, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding, min: u64, max: u64) -> VerifyResult {
+    if !merkle::verify_root::<HASH_SIZE, H>(value, proof, index, num_leaves, root, leaf_tag, branch_tag, padding) {
+        return VerifyResult::NotIncluded;
+    }
+    match deserialize_user(value) {
+        Some((_, balance)) if (min..=max).contains(&balance) => VerifyResult::Valid,
+        Some((_, balance)) => VerifyResult::OutOfRange(balance),
+        None => VerifyResult::MalformedLeaf
+    }
+}
+
+/// Encodes a user ID and a balance as a leaf value (`serialize_user` by default).
+pub type LeafEncoder = fn(u64, u64) -> Vec<u8>;
+
+/// Identifies an asset the users hold balances in.
+pub type AssetId = u32;
+
+/// The asset of the balances of single-asset databases.
+pub const DEFAULT_ASSET: AssetId = 0;
+
+/// Serializes a user holding several assets as `(user_id,{asset:balance,...})` after the version byte, with the assets
+/// sorted by ID, so that the leaf does not depend on the order in which the balances are stored.
+pub fn serialize_user_assets(user_id: u64, balances: &HashMap<AssetId, u64>) -> Vec<u8> {
+    let mut assets: Vec<_> = balances.iter().collect();
+    assets.sort_unstable();
+    let assets: Vec<String> = assets.into_iter().map(|(asset, balance)| format!("{}:{}", asset, balance)).collect();
+    let mut serialized = vec![LEAF_VERSION];
+    serialized.extend_from_slice(format!("({},{{{}}})", user_id, assets.join(",")).as_bytes());
+    serialized
+}
+
+/*
+ * A tree may one day hold other leaves than users (e.g. the reserves themselves). To tell them apart, each leaf can
+ * start with a type byte, USER_LEAF_TYPE for users, so that a leaf of another type can never be presented as a user
+ * (its encoding starts with another byte). The type byte comes before the version byte, and is off by default, so
+ * that the leaves (and the roots) of existing databases do not change.
+ */
+/// The type byte of user leaves, when leaves are typed (see `DatabaseBuilder::leaf_type`).
+pub const USER_LEAF_TYPE: u8 = 0x00;
+
+fn with_leaf_type(leaf_type: Option<u8>, value: Vec<u8>) -> Vec<u8> {
+    match leaf_type {
+        Some(leaf_type) => [vec![leaf_type], value].concat(),
+        None => value
+    }
+}
+
+// a user holding DEFAULT_ASSET only is encoded as in a single-asset database, so that its leaf does not change
+fn encode_user(encode_leaf: LeafEncoder, leaf_type: Option<u8>, user_id: u64, balances: &HashMap<AssetId, u64>) -> Vec<u8> {
+    let value = match balances.get(&DEFAULT_ASSET) {
+        Some(&balance) if balances.len() == 1 => encode_leaf(user_id, balance),
+        _ => serialize_user_assets(user_id, balances)
+    };
+    with_leaf_type(leaf_type, value)
+}
+
+// maps each user ID to the index of its (first) leaf
+fn leaf_indices(user_ids: &[u64]) -> HashMap<u64, usize> {
+    let mut indices = HashMap::with_capacity(user_ids.len());
+    for (index, user_id) in user_ids.iter().enumerate() {
+        indices.entry(*user_id).or_insert(index);
+    }
+    indices
 }
 
-pub struct InMemoryDatabase<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
-    users: HashMap<u64, u64>,
-    tree: MerkleTree<HASH_SIZE, H>,
+// the balance of DEFAULT_ASSET, which the single-asset views of a database (e.g. users and diffs) show
+fn default_balance(balances: &HashMap<AssetId, u64>) -> u64 {
+    balances.get(&DEFAULT_ASSET).copied().unwrap_or(0)
 }
 
-fn serialize_user(user_id: u64, balance: u64) -> Vec<u8> {
-    format!("({},{})", user_id, balance).into_bytes()
+/*
+ * The options of a database are set with fluent setters, and the database is built from the user data in the end:
+ *
+ *     let db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+ *         .leaf_tag(b"Leaf".to_vec())
+ *         .branch_tag(b"Branch".to_vec())
+ *         .padding(Padding::Promote)
+ *         .build(user_data);
+ *
+ * The padding strategy only applies to Merkle trees (Merkle mountain ranges never pad). Note that snapshots do not
+ * record the options, so only databases with the default options can be loaded from snapshots.
+ */
+pub struct DatabaseBuilder<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H> = MerkleTree<HASH_SIZE, H>> {
+    leaf_tag: Vec<u8>,
+    branch_tag: Vec<u8>,
+    padding: Padding,
+    encode_leaf: LeafEncoder,
+    leaf_type: Option<u8>,
+    sorted_pairs: bool,
+    reject_zero_balance: bool,
+    enforce_distinct_tags: bool,
+    _tree: std::marker::PhantomData<(H, M)>
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> Default for DatabaseBuilder<HASH_SIZE, H, M> {
+    fn default() -> Self {
+        DatabaseBuilder {
+            leaf_tag: Vec::new(),
+            branch_tag: Vec::new(),
+            padding: Padding::default(),
+            encode_leaf: serialize_user,
+            leaf_type: None,
+            sorted_pairs: false,
+            reject_zero_balance: false,
+            enforce_distinct_tags: false,
+            _tree: std::marker::PhantomData
+        }
+    }
 }
 
-impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> UserDatabase<HASH_SIZE, H, MerkleTree<HASH_SIZE, H>> for InMemoryDatabase<HASH_SIZE, H> {
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> DatabaseBuilder<HASH_SIZE, H, M> {
+    /// Sets the tag used for hashing the leaf nodes (empty by default).
+    pub fn leaf_tag(mut self, leaf_tag: Vec<u8>) -> Self {
+        self.leaf_tag = leaf_tag;
+        self
+    }
+
+    /// Sets the tag used for hashing the branch nodes (empty by default).
+    pub fn branch_tag(mut self, branch_tag: Vec<u8>) -> Self {
+        self.branch_tag = branch_tag;
+        self
+    }
+
+    /// Sets the padding strategy for lone nodes (duplication by default).
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Sets the function encoding a user as a leaf value (`serialize_user` by default).
+    pub fn leaf_encoder(mut self, encode_leaf: LeafEncoder) -> Self {
+        self.encode_leaf = encode_leaf;
+        self
+    }
+
+    /// Sets the type byte prepended to each leaf, e.g. `USER_LEAF_TYPE` (none by default), to tell user leaves apart
+    /// from leaves of other types.
+    pub fn leaf_type(mut self, leaf_type: u8) -> Self {
+        self.leaf_type = Some(leaf_type);
+        self
+    }
+
+    /// Sets whether the children of each node are sorted before hashing them (off by default).
+    pub fn sorted_pairs(mut self, sorted_pairs: bool) -> Self {
+        self.sorted_pairs = sorted_pairs;
+        self
+    }
+
+    /// Sets whether users with a zero balance are rejected when building (off by default), for reserve policies
+    /// under which they should not be in the tree.
+    pub fn reject_zero_balance(mut self, reject_zero_balance: bool) -> Self {
+        self.reject_zero_balance = reject_zero_balance;
+        self
+    }
+
+    /// Sets whether building fails if the leaf tag and the branch tag are the same (off by default). With the same
+    /// tag, a branch node is the hash of a (two-node) leaf value, so it could be presented as a leaf.
+    pub fn enforce_distinct_tags(mut self, enforce_distinct_tags: bool) -> Self {
+        self.enforce_distinct_tags = enforce_distinct_tags;
+        self
+    }
+
+    /// Same as `build`, but fails if the options are violated, i.e., if zero balances are rejected and a user has
+    /// one, or distinct tags are enforced and the tags are the same.
+    pub fn try_build(self, user_data: Vec<(u64, u64)>) -> Result<InMemoryDatabase<HASH_SIZE, H, M>, BuildError> {
+        self.try_build_assets(user_data.into_iter().map(|(id, balance)| (id, HashMap::from([(DEFAULT_ASSET, balance)]))).collect())
+    }
+
+    /// Same as `try_build`, but for users holding several assets (see `build_assets`).
+    pub fn try_build_assets(self, user_data: Vec<(u64, HashMap<AssetId, u64>)>) -> Result<InMemoryDatabase<HASH_SIZE, H, M>, BuildError> {
+        if self.enforce_distinct_tags && self.leaf_tag == self.branch_tag {
+            return Err(BuildError::IdenticalTags);
+        }
+        if self.reject_zero_balance {
+            if let Some((user_id, _)) = user_data.iter().find(|(_, balances)| balances.values().any(|balance| *balance == 0)) {
+                return Err(BuildError::ZeroBalance(*user_id));
+            }
+        }
+        Ok(self.build_unchecked(user_data))
+    }
+
+    /// Builds the database from the given users with their balance in each asset they hold, which become the leaves
+    /// in this order. A user holding DEFAULT_ASSET only gets the same leaf as with `build`, other users are encoded
+    /// with `serialize_user_assets`. Panics if the user data violates the options (see `try_build`).
+    pub fn build_assets(self, user_data: Vec<(u64, HashMap<AssetId, u64>)>) -> InMemoryDatabase<HASH_SIZE, H, M> {
+        self.try_build_assets(user_data).unwrap_or_else(|err| panic!("Cannot build the database: {}", err))
+    }
+
+    /// Builds the database from the given (user ID, balance) pairs, which become the leaves in this order.
+    /// Panics if the user data violates the options (see `try_build`).
+    pub fn build(self, user_data: Vec<(u64, u64)>) -> InMemoryDatabase<HASH_SIZE, H, M> {
+        self.try_build(user_data).unwrap_or_else(|err| panic!("Cannot build the database: {}", err))
+    }
+
+    fn build_unchecked(self, user_data: Vec<(u64, HashMap<AssetId, u64>)>) -> InMemoryDatabase<HASH_SIZE, H, M> {
+        let start = Instant::now();
+        let leaves: Vec<Vec<u8>> = user_data.iter().map(|(id, balances)| encode_user(self.encode_leaf, self.leaf_type, *id, balances)).collect();
+        let leaf_hashes = leaves.iter().zip(&user_data).map(|(leaf, (id, _))| (H::tagged_hash(&self.leaf_tag, leaf), *id)).collect();
+        let tree = M::build_with_options(leaves, self.leaf_tag, self.branch_tag, self.padding, self.sorted_pairs);
+        let build_time = start.elapsed();
+        let user_ids: Vec<u64> = user_data.iter().map(|(id, _)| *id).collect();
+        let indices = leaf_indices(&user_ids);
+        let users = user_data.into_iter().collect();
+        InMemoryDatabase { users, user_ids, indices, leaf_hashes, removed: HashSet::new(), encode_leaf: self.encode_leaf, leaf_type: self.leaf_type, padding: self.padding, sorted_pairs: self.sorted_pairs, tree, build_time, _hasher: std::marker::PhantomData }
+    }
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> UserDatabase<HASH_SIZE, H, M> for InMemoryDatabase<HASH_SIZE, H, M> {
     fn create(user_data: Vec<(u64, u64)>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self {
-        let serialized_user_data: Vec<Vec<u8>> = user_data.iter().map(|(id, balance)| serialize_user(*id, *balance)).collect();
-        let tree = MerkleTree::<HASH_SIZE, H>::build(serialized_user_data, leaf_tag, branch_tag);
-        let user_map: HashMap<_, _> = user_data.into_iter().collect();
-        InMemoryDatabase { users: user_map, tree }
+        DatabaseBuilder::default().leaf_tag(leaf_tag).branch_tag(branch_tag).build(user_data)
     }
 
-    fn get_balance(&self, user_id: u64) -> Option<u64> {
-        self.users.get(&user_id).copied()
+    fn get_balance(&self, user_id: u64, asset: AssetId) -> Option<u64> {
+        self.users.get(&user_id)?.get(&asset).copied()
     }
 
-    fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
-        self.tree.get_root()
+    fn contains_user(&self, user_id: u64) -> bool {
+        self.users.contains_key(&user_id)
     }
-    
+
+    fn get_root(&self) -> Option<MerkleRoot<HASH_SIZE>> {
+        (!self.user_ids.is_empty()).then(|| self.tree.get_root())
+    }
+
     fn get_proof(&self, user_id: u64) -> Option<MerkleProof<HASH_SIZE>> {
-        let balance = self.get_balance(user_id)?;
-        let serialized = serialize_user(user_id, balance);
+        let serialized = encode_user(self.encode_leaf, self.leaf_type, user_id, self.users.get(&user_id)?);
         self.tree.get_proof(serialized)
     }
+
+    fn verify_proof(&self, user_id: u64, balance: u64, proof: &MerkleProof<HASH_SIZE>) -> bool {
+        self.tree.verify(&with_leaf_type(self.leaf_type, (self.encode_leaf)(user_id, balance)), proof)
+    }
+
+    fn total_balance(&self, asset: AssetId) -> Option<u64> {
+        self.users.values().filter_map(|balances| balances.get(&asset)).try_fold(0u64, |total, balance| total.checked_add(*balance))
+    }
+}
+
+/*
+ * Updating a balance only rehashes the path from the user's leaf to the root. Adding a user appends a leaf (which
+ * is cheap for incremental trees such as the Merkle mountain range), and removing one rebuilds the tree.
+ */
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> MutableUserDatabase<HASH_SIZE, H, M> for InMemoryDatabase<HASH_SIZE, H, M> {
+    fn set_asset_balance(&mut self, user_id: u64, asset: AssetId, balance: u64) {
+        let exists = self.users.contains_key(&user_id);
+        if exists {
+            let old_hash = H::tagged_hash(self.tree.leaf_tag(), &self.leaf_value(user_id));
+            self.leaf_hashes.remove(&old_hash);
+        }
+        let balances = self.users.entry(user_id).or_default();
+        balances.insert(asset, balance);
+        let serialized = encode_user(self.encode_leaf, self.leaf_type, user_id, balances);
+        self.leaf_hashes.insert(H::tagged_hash(self.tree.leaf_tag(), &serialized), user_id);
+        match exists {
+            true => self.tree.update_leaf(self.indices[&user_id], &serialized),
+            false => {
+                self.tree.append(&serialized);
+                self.indices.insert(user_id, self.user_ids.len());
+                self.user_ids.push(user_id);
+                self.removed.remove(&user_id);
+            }
+        }
+    }
+
+    fn remove_user(&mut self, user_id: u64) -> Option<HashMap<AssetId, u64>> {
+        let old_hash = H::tagged_hash(self.tree.leaf_tag(), &encode_user(self.encode_leaf, self.leaf_type, user_id, self.users.get(&user_id)?));
+        self.leaf_hashes.remove(&old_hash);
+        let balances = self.users.remove(&user_id).unwrap();
+        self.user_ids.retain(|id| *id != user_id);
+        self.indices = leaf_indices(&self.user_ids);
+        self.removed.insert(user_id);
+        let serialized_user_data = self.user_ids.iter().map(|id| encode_user(self.encode_leaf, self.leaf_type, *id, &self.users[id])).collect();
+        self.tree.rebuild(serialized_user_data);
+        Some(balances)
+    }
+}
+
+/*
+ * A database that can be updated while it is being read. Rather than locking the whole database for every request,
+ * the current state is an immutable snapshot behind an Arc: readers only take the lock to clone the Arc, and then
+ * work on a consistent snapshot without blocking anyone. Updates are serialized by a separate writer lock; a writer
+ * applies its changes to a spare copy of the current snapshot and then publishes it by swapping the Arc, so a reader
+ * sees either the old or the new state, never a partially updated one.
+ *
+ * Copying the whole database for every update would make each update linear, so the writer keeps two copies, as
+ * in a left-right scheme: once the new state is published, the update is applied again to the replaced state,
+ * which becomes the spare copy for the next update. Both copies are updated in place, so updating a balance only
+ * rehashes one path in each. The replaced state can only be reused if no reader still holds it (and it is not kept
+ * in the history); otherwise the next update starts from a fresh copy. Since every update is applied twice, it must
+ * be deterministic. An update that panics is never published, and its copy is dropped.
+ *
+ * Each published state is numbered by an epoch, which starts at 0 and is incremented by every update, so that
+ * states can be ordered (e.g. to tell an outdated attestation of the root from the current one). An update that
+ * fails (see `try_update`) publishes nothing, so it does not use up an epoch. Snapshots record the epoch, so that
+ * it keeps increasing across restarts.
+ */
+pub struct OnlineDatabase<D> {
+    current: RwLock<(Arc<D>, u64)>,
+    // the states replaced by the last updates, oldest first, with their epochs (at most history_len of them)
+    history: RwLock<VecDeque<(Arc<D>, u64)>>,
+    history_len: usize,
+    last_update: RwLock<Option<SystemTime>>,
+    // serializes the updates, and holds the spare copy, equal to the current state, if it could be reused
+    writer: Mutex<Option<D>>,
+}
+
+impl<D: Clone> OnlineDatabase<D> {
+    pub fn new(db: D) -> Self {
+        Self::with_history(db, 0)
+    }
+
+    /// Same as `new`, but keeps the states replaced by the last `history_len` published updates (failed updates
+    /// replace nothing), so that proofs can still be served against their roots (see `get_proof_at`). Each kept state
+    /// is a full copy of the database.
+    pub fn with_history(db: D, history_len: usize) -> Self {
+        Self::at_epoch(db, 0, history_len)
+    }
+
+    fn at_epoch(db: D, epoch: u64, history_len: usize) -> Self {
+        OnlineDatabase {
+            current: RwLock::new((Arc::new(db), epoch)),
+            history: RwLock::new(VecDeque::with_capacity(history_len)),
+            history_len,
+            last_update: RwLock::new(None),
+            writer: Mutex::new(None)
+        }
+    }
+
+    /// Returns the current state of the database.
+    pub fn snapshot(&self) -> Arc<D> {
+        self.current.read().unwrap().0.clone()
+    }
+
+    /// Returns the current state of the database together with its epoch.
+    pub fn snapshot_with_epoch(&self) -> (Arc<D>, u64) {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Returns the epoch of the current state.
+    pub fn epoch(&self) -> u64 {
+        self.current.read().unwrap().1
+    }
+
+    /// Returns when the last update was published, or None if the database was never updated.
+    pub fn last_update(&self) -> Option<SystemTime> {
+        *self.last_update.read().unwrap()
+    }
+
+    /// Applies an update to the database and publishes the new state, in the next epoch. The update is applied to
+    /// both copies of the database, so it must be deterministic.
+    pub fn update<R>(&self, f: impl Fn(&mut D) -> R) -> R {
+        match self.try_update(|db| Ok::<R, Infallible>(f(db))) {
+            Ok(result) => result,
+            Err(never) => match never {}
+        }
+    }
+
+    /// Same as `update`, but only publishes the new state if the update succeeds. If it fails, the current state and
+    /// epoch stay as they are, so the update must not have changed anything before failing.
+    pub fn try_update<T, E>(&self, f: impl Fn(&mut D) -> Result<T, E>) -> Result<T, E> {
+        // a panicking update never published anything, and the spare copy it was applied to is gone
+        let mut spare = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        let (current, epoch) = self.snapshot_with_epoch();
+        let mut next = spare.take().unwrap_or_else(|| D::clone(&current));
+        let result = f(&mut next);
+        if result.is_err() {
+            *spare = Some(next);
+            return result;
+        }
+        // the replaced state joins the history before the new one is published, so that it is always found
+        if self.history_len > 0 {
+            let mut history = self.history.write().unwrap();
+            if history.len() == self.history_len {
+                history.pop_front();
+            }
+            history.push_back((current.clone(), epoch));
+        }
+        let replaced = std::mem::replace(&mut *self.current.write().unwrap(), (Arc::new(next), epoch + 1)).0;
+        *self.last_update.write().unwrap() = Some(SystemTime::now());
+        drop(current);
+        // readers may still hold the replaced state, in which case the next update copies the current one instead
+        if let Ok(mut replaced) = Arc::try_unwrap(replaced) {
+            let _ = f(&mut replaced);
+            *spare = Some(replaced);
+        }
+        result
+    }
+
+    /// Returns the state of the database at the given epoch, or None if it is neither the current state nor one of
+    /// the kept past states (see `with_history`).
+    pub fn snapshot_at(&self, epoch: u64) -> Option<Arc<D>> {
+        let (current, current_epoch) = self.snapshot_with_epoch();
+        if epoch == current_epoch {
+            return Some(current);
+        }
+        self.history.read().unwrap().iter().find(|(_, past)| *past == epoch).map(|(db, _)| db.clone())
+    }
+
+    /// Returns the proof of the user as it was at the given epoch, which verifies against the root of that epoch, or
+    /// None if the state of the epoch is not kept or the user did not exist then.
+    pub fn get_proof_at<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>>(&self, user_id: u64, epoch: u64) -> Option<MerkleProof<HASH_SIZE>>
+    where
+        D: UserDatabase<HASH_SIZE, H, M> {
+        self.snapshot_at(epoch)?.get_proof(user_id)
+    }
+}
+
+/// A change of the balance of a user between two states of a database.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BalanceChange {
+    pub user_id: u64,
+    pub old_balance: u64,
+    pub new_balance: u64,
+}
+
+impl BalanceChange {
+    /// Returns the signed change of the balance.
+    pub fn delta(&self) -> i128 {
+        self.new_balance as i128 - self.old_balance as i128
+    }
+}
+
+/// The differences between an old and a new state of a database (see `InMemoryDatabase::diff`), sorted by user ID.
+#[derive(Debug, PartialEq, Eq, Default)]
+pub struct DbDiff {
+    pub added: Vec<(u64, u64)>,
+    pub removed: Vec<(u64, u64)>,
+    pub changed: Vec<BalanceChange>,
+}
+
+/*
+ * If the leaves are sorted by user ID, the absence of a user can be proven by the two adjacent leaves bracketing its
+ * ID: one with a smaller ID right before one with a larger ID. Adjacency is what matters, so each leaf comes with its
+ * position, which a positional proof commits to (a sorted-pair proof does not, so absence cannot be proven in that
+ * mode). An ID below the smallest (above the largest) ID is bracketed by the first (last) leaf alone.
+ *
+ * Note that the verifier has to trust that the leaves are sorted: the tree itself does not enforce any order.
+ */
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct BracketingLeaf<const HASH_SIZE: usize> {
+    pub index: usize,
+    pub user_id: u64,
+    pub balance: u64,
+    pub proof: MerkleProof<HASH_SIZE>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AbsenceProof<const HASH_SIZE: usize> {
+    pub num_leaves: usize,
+    pub lower: Option<BracketingLeaf<HASH_SIZE>>,
+    pub upper: Option<BracketingLeaf<HASH_SIZE>>,
+}
+
+impl<const HASH_SIZE: usize> AbsenceProof<HASH_SIZE> {
+    /// Verifies that the bracketing leaves are adjacent leaves of the tree with the given root, with IDs on either side
+    /// of `user_id`, so that a tree sorted by user ID cannot contain the user. The leaves are encoded with `encode_leaf`.
+    pub fn verify<H: HashAlgorithm<HASH_SIZE>>(&self, user_id: u64, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding, encode_leaf: LeafEncoder) -> bool {
+        let verifies = |leaf: &BracketingLeaf<HASH_SIZE>| {
+            let value = encode_leaf(leaf.user_id, leaf.balance);
+            merkle::verify_root::<HASH_SIZE, H>(&value, &leaf.proof, leaf.index, self.num_leaves, root, leaf_tag, branch_tag, padding)
+        };
+        let brackets = match (&self.lower, &self.upper) {
+            (Some(lower), Some(upper)) => lower.user_id < user_id && user_id < upper.user_id && upper.index == lower.index + 1,
+            (Some(lower), None) => lower.user_id < user_id && lower.index + 1 == self.num_leaves,
+            (None, Some(upper)) => user_id < upper.user_id && upper.index == 0,
+            (None, None) => false
+        };
+        brackets && self.lower.iter().chain(&self.upper).all(verifies)
+    }
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> InMemoryDatabase<HASH_SIZE, H, MerkleTree<HASH_SIZE, H>> {
+    /// Returns the underlying Merkle tree, e.g. to read its layers. The tree can only be changed through the database,
+    /// which keeps it in sync with the users.
+    pub fn tree(&self) -> &MerkleTree<HASH_SIZE, H> {
+        &self.tree
+    }
+
+    fn bracketing_leaf(&self, index: usize) -> BracketingLeaf<HASH_SIZE> {
+        let user_id = self.user_ids[index];
+        let balance = default_balance(&self.users[&user_id]);
+        let proof = self.tree.get_proof(self.leaf_value(user_id)).unwrap();
+        BracketingLeaf { index, user_id, balance, proof }
+    }
+
+    /// Returns a proof that the user with the given ID is not in the database, or None if the user is present, the
+    /// leaves are not sorted by user ID, the tree is in sorted-pair mode or the database is empty.
+    pub fn get_absence_proof(&self, user_id: u64) -> Option<AbsenceProof<HASH_SIZE>> {
+        if self.users.contains_key(&user_id) || self.user_ids.is_empty() || self.tree.sorted_pairs() || !self.user_ids.is_sorted() {
+            return None;
+        }
+        let upper = self.user_ids.partition_point(|id| *id < user_id);
+        Some(AbsenceProof {
+            num_leaves: self.user_ids.len(),
+            lower: upper.checked_sub(1).map(|index| self.bracketing_leaf(index)),
+            upper: (upper < self.user_ids.len()).then(|| self.bracketing_leaf(upper))
+        })
+    }
+}
+
+/// Why the user data cannot be built into a database with the options of the builder.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    ZeroBalance(u64),
+    IdenticalTags
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroBalance(user_id) => write!(f, "user {} has a zero balance", user_id),
+            BuildError::IdenticalTags => write!(f, "the leaf tag and the branch tag are the same")
+        }
+    }
+}
+
+/// Why there is no proof of a user: either the user does not exist, or the user exists but its leaf (as encoded
+/// from its current balance) is not in the tree, i.e. the tree is out of sync with the user data, which is a bug.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProofError {
+    UserNotFound(u64),
+    Desync(u64)
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::UserNotFound(user_id) => write!(f, "user {} not found", user_id),
+            ProofError::Desync(user_id) => write!(f, "the leaf of user {} is not in the tree", user_id)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    #[cfg(feature = "serde")]
+    Parse(serde_json::Error),
+    BadTag(String),
+    RootMismatch { expected: String, actual: String }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "cannot access snapshot: {}", err),
+            #[cfg(feature = "serde")]
+            SnapshotError::Parse(err) => write!(f, "malformed snapshot: {}", err),
+            SnapshotError::BadTag(tag) => write!(f, "malformed tag in snapshot: {}", tag),
+            SnapshotError::RootMismatch { expected, actual } => {
+                write!(f, "snapshot root mismatch: expected {}, but the data has root {}", expected, actual)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for SnapshotError {
+    fn from(err: serde_json::Error) -> Self {
+        SnapshotError::Parse(err)
+    }
+}
+
+/*
+ * A snapshot stores the user data in leaf order together with the tags, so the tree can be rebuilt
+ * exactly, and the root, so we can detect a corrupted or tampered snapshot upon loading. Tags are
+ * hex-encoded since they are arbitrary bytes. The epoch of the state is stored too (0 for snapshots that
+ * predate it), so that an online database loaded from a snapshot does not restart from epoch 0. So is the
+ * leaf type byte, if any (see DatabaseBuilder::leaf_type), without which a typed tree cannot be rebuilt.
+ * A user holding DEFAULT_ASSET only is stored as [id, balance], as before assets existed; any other user
+ * is stored with all its balances sorted by asset, as [id, [[asset, balance], ...]].
+ */
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    leaf_tag: String,
+    branch_tag: String,
+    #[serde(default)]
+    leaf_type: Option<u8>,
+    users: Vec<SnapshotUser>,
+    root: Option<String>, // null for an empty database, which has no root
+    #[serde(default)]
+    epoch: u64,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SnapshotUser {
+    Default(u64, u64),
+    Assets(u64, Vec<(AssetId, u64)>),
+}
+
+#[cfg(feature = "serde")]
+impl SnapshotUser {
+    fn new(user_id: u64, balances: &HashMap<AssetId, u64>) -> Self {
+        match balances.get(&DEFAULT_ASSET) {
+            Some(&balance) if balances.len() == 1 => SnapshotUser::Default(user_id, balance),
+            _ => {
+                let mut assets: Vec<(AssetId, u64)> = balances.iter().map(|(&asset, &balance)| (asset, balance)).collect();
+                assets.sort_unstable();
+                SnapshotUser::Assets(user_id, assets)
+            }
+        }
+    }
+
+    fn into_balances(self) -> (u64, HashMap<AssetId, u64>) {
+        match self {
+            SnapshotUser::Default(user_id, balance) => (user_id, HashMap::from([(DEFAULT_ASSET, balance)])),
+            SnapshotUser::Assets(user_id, balances) => (user_id, balances.into_iter().collect()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn encode_hex(bytes: &[u8]) -> String {
+    format!("0x{}", HEXLOWER.encode(bytes))
+}
+
+#[cfg(feature = "serde")]
+fn decode_hex(hex: &str) -> Result<Vec<u8>, SnapshotError> {
+    let digits = hex.strip_prefix("0x").ok_or_else(|| SnapshotError::BadTag(hex.to_string()))?;
+    HEXLOWER.decode(digits.as_bytes()).map_err(|_| SnapshotError::BadTag(hex.to_string()))
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> InMemoryDatabase<HASH_SIZE, H, M> {
+    /// Same as `create`, but takes the balances keyed by user ID, which become the leaves in increasing order of the
+    /// IDs, so the root does not depend on the order in which the users were collected (and absence can be proven).
+    pub fn from_sorted_map(user_data: BTreeMap<u64, u64>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Self {
+        Self::create(user_data.into_iter().collect(), leaf_tag, branch_tag)
+    }
+
+    /// Returns the tag used for hashing the leaf nodes.
+    pub fn leaf_tag(&self) -> &[u8] {
+        self.tree.leaf_tag()
+    }
+
+    /// Returns the tag used for hashing the branch nodes.
+    pub fn branch_tag(&self) -> &[u8] {
+        self.tree.branch_tag()
+    }
+
+    /// Returns the type byte prepended to each leaf, or None if the leaves are untyped.
+    pub fn leaf_type(&self) -> Option<u8> {
+        self.leaf_type
+    }
+
+    /// Returns the padding strategy the tree was built with (see `DatabaseBuilder::padding`).
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// Returns whether the children of each node are sorted before hashing them (see `DatabaseBuilder::sorted_pairs`).
+    pub fn sorted_pairs(&self) -> bool {
+        self.sorted_pairs
+    }
+
+    /// Returns the number of leaves of the tree, i.e., the number of users.
+    pub fn num_leaves(&self) -> usize {
+        self.user_ids.len()
+    }
+
+    /// Returns the index of the leaf of the user, or None if the user does not exist.
+    pub fn leaf_index(&self, user_id: u64) -> Option<usize> {
+        self.indices.get(&user_id).copied()
+    }
+
+    /// Returns how long building the tree (including serializing the leaves) took when the database was created.
+    pub fn build_time(&self) -> Duration {
+        self.build_time
+    }
+
+    /// Returns the number of hashes stored at each level of the tree, from the leaves up.
+    pub fn layer_sizes(&self) -> Vec<usize> {
+        self.tree.layer_sizes()
+    }
+
+    /// Returns the balances of the given users (in DEFAULT_ASSET) in the same order, with None for the users that do
+    /// not exist or do not hold it.
+    pub fn get_balance_batch(&self, ids: &[u64]) -> Vec<Option<u64>> {
+        ids.iter().map(|id| self.get_balance(*id, DEFAULT_ASSET)).collect()
+    }
+
+    /// Returns the balance of the user in each asset it holds, or None if the user does not exist.
+    pub fn get_balances(&self, user_id: u64) -> Option<&HashMap<AssetId, u64>> {
+        self.users.get(&user_id)
+    }
+
+    /// Returns the balances the leaf of the user commits to, sorted by asset, if it holds other assets than
+    /// DEFAULT_ASSET (its leaf is then `serialize_user_assets`), or None if its leaf is a single-asset one or the user
+    /// does not exist.
+    pub fn asset_leaf_balances(&self, user_id: u64) -> Option<BTreeMap<AssetId, u64>> {
+        let balances = self.users.get(&user_id)?;
+        match balances.get(&DEFAULT_ASSET) {
+            Some(_) if balances.len() == 1 => None,
+            _ => Some(balances.iter().map(|(&asset, &balance)| (asset, balance)).collect())
+        }
+    }
+
+    /// Verifies the proof of a user with the given balances against the root of the database, the leaf being encoded
+    /// as the database encodes it (as a single-asset leaf if the user only holds DEFAULT_ASSET).
+    pub fn verify_asset_proof(&self, user_id: u64, balances: &HashMap<AssetId, u64>, proof: &MerkleProof<HASH_SIZE>) -> bool {
+        self.tree.verify(&encode_user(self.encode_leaf, self.leaf_type, user_id, balances), proof)
+    }
+
+    // the leaf value of an existing user
+    fn leaf_value(&self, user_id: u64) -> Vec<u8> {
+        encode_user(self.encode_leaf, self.leaf_type, user_id, &self.users[&user_id])
+    }
+
+    /// Generates the proof of the user and verifies it against the root of the database, failing as `try_get_proof`
+    /// does if the user does not exist or its leaf is missing from the tree. This is a self-consistency check: it does
+    /// not vouch for the root itself.
+    pub fn verify_user(&self, user_id: u64) -> Result<bool, ProofError> {
+        let proof = self.try_get_proof(user_id)?;
+        Ok(self.tree.verify(&self.leaf_value(user_id), &proof))
+    }
+
+    /// Iterates over the users and their balances (in DEFAULT_ASSET), in the order of the leaves.
+    pub fn users(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.user_ids.iter().map(|id| (*id, default_balance(&self.users[id])))
+    }
+
+    /// Returns a row (leaf index, user ID, balance, leaf hash) for each leaf, in the order of the leaves, so auditors
+    /// can check the whole tree.
+    pub fn export_leaves_with_values(&self) -> Vec<(usize, u64, u64, [u8; HASH_SIZE])> {
+        self.users().enumerate()
+            .map(|(index, (user_id, balance))| (index, user_id, balance, H::tagged_hash(self.leaf_tag(), &self.leaf_value(user_id))))
+            .collect()
+    }
+
+    /// Returns the ID of the user whose leaf has the given hash, or None if there is no such leaf.
+    /// The users are indexed by leaf hash, so this takes constant time.
+    pub fn find_user_by_leaf_hash(&self, leaf_hash: &[u8; HASH_SIZE]) -> Option<u64> {
+        self.leaf_hashes.get(leaf_hash).copied()
+    }
+
+    /// Compares this (old) state of the database with a newer one, reporting the users that were added or removed,
+    /// and those whose balance (in DEFAULT_ASSET) changed.
+    pub fn diff(&self, other: &InMemoryDatabase<HASH_SIZE, H, M>) -> DbDiff {
+        let mut diff = DbDiff::default();
+        for (&user_id, old_balances) in &self.users {
+            let old_balance = default_balance(old_balances);
+            match other.users.get(&user_id).map(default_balance) {
+                None => diff.removed.push((user_id, old_balance)),
+                Some(new_balance) if new_balance != old_balance => diff.changed.push(BalanceChange { user_id, old_balance, new_balance }),
+                Some(_) => {}
+            }
+        }
+        diff.added = other.users.iter().filter(|(user_id, _)| !self.users.contains_key(user_id)).map(|(&user_id, balances)| (user_id, default_balance(balances))).collect();
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.changed.sort_unstable_by_key(|change| change.user_id);
+        diff
+    }
+
+    /// Returns whether the user once existed but was removed. Tombstones are kept in memory only, so
+    /// they are not part of snapshots.
+    pub fn is_removed(&self, user_id: u64) -> bool {
+        self.removed.contains(&user_id)
+    }
+}
+
+// snapshots are stored as JSON, so they need the serde feature (on by default)
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> InMemoryDatabase<HASH_SIZE, H, M> {
+    /// Saves the database as a JSON snapshot at the given path.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        self.save_snapshot_at_epoch(path, 0)
+    }
+
+    fn save_snapshot_at_epoch(&self, path: impl AsRef<Path>, epoch: u64) -> Result<(), SnapshotError> {
+        let snapshot = Snapshot {
+            leaf_tag: encode_hex(self.tree.leaf_tag()),
+            branch_tag: encode_hex(self.tree.branch_tag()),
+            leaf_type: self.leaf_type,
+            users: self.user_ids.iter().map(|id| SnapshotUser::new(*id, &self.users[id])).collect(),
+            root: self.get_root().map(|root| encode_hex(&root.0)),
+            epoch,
+        };
+        fs::write(path, serde_json::to_vec(&snapshot)?)?;
+        Ok(())
+    }
+
+    /// Loads a database from a JSON snapshot at the given path, rebuilding the Merkle tree.
+    /// Fails if the rebuilt root differs from the root recorded in the snapshot.
+    pub fn load_snapshot(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Ok(Self::load_snapshot_with_epoch(path)?.0)
+    }
+
+    fn load_snapshot_with_epoch(path: impl AsRef<Path>) -> Result<(Self, u64), SnapshotError> {
+        let snapshot: Snapshot = serde_json::from_slice(&fs::read(path)?)?;
+        let leaf_tag = decode_hex(&snapshot.leaf_tag)?;
+        let branch_tag = decode_hex(&snapshot.branch_tag)?;
+        let user_data = snapshot.users.into_iter().map(SnapshotUser::into_balances).collect();
+        let mut builder = DatabaseBuilder::default().leaf_tag(leaf_tag).branch_tag(branch_tag);
+        if let Some(leaf_type) = snapshot.leaf_type {
+            builder = builder.leaf_type(leaf_type);
+        }
+        let db: Self = builder.build_assets(user_data);
+        let actual = db.get_root().map(|root| encode_hex(&root.0));
+        if actual != snapshot.root {
+            let describe = |root: Option<String>| root.unwrap_or_else(|| "none".to_string());
+            return Err(SnapshotError::RootMismatch { expected: describe(snapshot.root), actual: describe(actual) });
+        }
+        Ok((db, snapshot.epoch))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, M: MerkleTreeImpl<HASH_SIZE, H>> OnlineDatabase<InMemoryDatabase<HASH_SIZE, H, M>>
+where
+    InMemoryDatabase<HASH_SIZE, H, M>: Clone {
+    /// Saves the current state as a JSON snapshot at the given path (see `InMemoryDatabase::save_snapshot`),
+    /// together with its epoch.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let (db, epoch) = self.snapshot_with_epoch();
+        db.save_snapshot_at_epoch(path, epoch)
+    }
+
+    /// Loads an online database from a JSON snapshot at the given path (see `InMemoryDatabase::load_snapshot`),
+    /// starting at the epoch recorded in the snapshot.
+    pub fn load_snapshot(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        let (db, epoch) = InMemoryDatabase::load_snapshot_with_epoch(path)?;
+        Ok(Self::at_epoch(db, epoch, 0))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,82 @@
+//! A C API to verify proofs, so that auditors need not use Rust
+//!
+//! Proofs are passed in their flattened form (see `MerkleProof::flatten`): a byte per item giving its direction
+//! (`MERKLE_DIRECTION_LEFT` or `MERKLE_DIRECTION_RIGHT`) and the concatenation of the 32-byte hashes of the items. Only
+//! SHA256 trees (`Sha256Algorithm`) are supported. The header `include/merkle.h` is generated with cbindgen:
+//!
+//! ```sh
+//! cbindgen --config cbindgen.toml --output include/merkle.h
+//! ```
+use std::slice;
+use crate::merkle::{self, MerkleProof, MerkleRoot, Padding, Sha256Algorithm};
+
+/// The direction of a proof item that is a left sibling (`merkle::LEFT`).
+pub const MERKLE_DIRECTION_LEFT: u8 = 0;
+/// The direction of a proof item that is a right sibling (`merkle::RIGHT`).
+pub const MERKLE_DIRECTION_RIGHT: u8 = 1;
+
+// cbindgen cannot evaluate paths, so the values are spelled out above and checked here
+const _: () = assert!(MERKLE_DIRECTION_LEFT == merkle::LEFT && MERKLE_DIRECTION_RIGHT == merkle::RIGHT);
+
+/// Pads lone nodes by pairing them with themselves (`Padding::Duplicate`).
+pub const MERKLE_PADDING_DUPLICATE: u8 = 0;
+/// Pads lone nodes by promoting them unchanged (`Padding::Promote`).
+pub const MERKLE_PADDING_PROMOTE: u8 = 1;
+
+// a null pointer is only allowed for an empty buffer
+unsafe fn buffer<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (ptr.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(ptr, len))
+    }
+}
+
+/// Verifies the proof for the leaf with the given value, at position `index` in a tree with `num_leaves` leaves
+/// built with the given padding (`MERKLE_PADDING_DUPLICATE` or `MERKLE_PADDING_PROMOTE`), against the 32-byte root.
+/// The proof has `proof_len` items, whose directions are at `directions_ptr` and hashes at `hashes_ptr`. Returns
+/// false if the proof is invalid or the arguments are malformed.
+///
+/// # Safety
+///
+/// Each pointer must either be valid for reads of the given number of bytes (`proof_len` for `directions_ptr`,
+/// 32 times `proof_len` for `hashes_ptr` and 32 for `root_ptr`), or be null with a length of zero.
+#[no_mangle]
+pub unsafe extern "C" fn merkle_verify(
+    leaf_ptr: *const u8,
+    leaf_len: usize,
+    leaf_tag_ptr: *const u8,
+    leaf_tag_len: usize,
+    branch_tag_ptr: *const u8,
+    branch_tag_len: usize,
+    directions_ptr: *const u8,
+    hashes_ptr: *const u8,
+    proof_len: usize,
+    index: usize,
+    num_leaves: usize,
+    padding: u8,
+    root_ptr: *const u8
+) -> bool {
+    let padding = match padding {
+        MERKLE_PADDING_DUPLICATE => Padding::Duplicate,
+        MERKLE_PADDING_PROMOTE => Padding::Promote,
+        _ => return false
+    };
+    let Some(hashes_len) = proof_len.checked_mul(32) else {
+        return false;
+    };
+    let (Some(leaf), Some(leaf_tag), Some(branch_tag), Some(directions), Some(hashes), Some(root)) = (
+        buffer(leaf_ptr, leaf_len),
+        buffer(leaf_tag_ptr, leaf_tag_len),
+        buffer(branch_tag_ptr, branch_tag_len),
+        buffer(directions_ptr, proof_len),
+        buffer(hashes_ptr, hashes_len),
+        buffer(root_ptr, 32)
+    ) else {
+        return false;
+    };
+    let (Some(proof), Ok(root)) = (MerkleProof::<32>::from_flattened(directions, hashes), <[u8; 32]>::try_from(root)) else {
+        return false;
+    };
+    merkle::verify_root::<32, Sha256Algorithm>(leaf, &proof, index, num_leaves, &MerkleRoot(root), leaf_tag, branch_tag, padding)
+}
@@ -0,0 +1,226 @@
+//! A Merkle tree stored in a single vector
+//!
+//! `FlatMerkleTree` builds the same tree as `MerkleTree`, with the same roots and proofs, but stores all the nodes
+//! in one contiguous vector instead of a vector per layer, which saves the indirection to each layer and keeps the
+//! nodes of a proof close together in memory.
+use crate::merkle::{assert_output_size, verify_root, HashAlgorithm, MerkleProof, MerkleProofItem, MerkleRoot, Padding, PreparedTag};
+
+/*
+ * The layers are stored one after the other, from the leaves up to the root, and layer i spans
+ * nodes[offsets[i]..offsets[i + 1]]. The width of each layer only depends on the number of leaves (a layer of w
+ * nodes has a parent layer of ceil(w / 2) nodes), so the offsets, and the size of the vector, are computed before
+ * hashing anything. The node at index j of a layer has its parent at index j / 2 of the next layer, as in MerkleTree.
+ */
+#[derive(Debug, Clone)]
+pub struct FlatMerkleTree<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
+    nodes: Vec<[u8; HASH_SIZE]>,
+    offsets: Vec<usize>, // the start of each layer, followed by the end of the last one
+    leaf_tag: Vec<u8>,
+    branch_tag: Vec<u8>,
+    padding: Padding,
+    sorted_pairs: bool, // whether the children are sorted before hashing them (see MerkleProof::verify_sorted)
+    _hasher: std::marker::PhantomData<H>
+}
+
+// the offsets of the layers of a tree with the given number of leaves
+fn layer_offsets(num_leaves: usize) -> Vec<usize> {
+    let mut offsets = vec![0, num_leaves];
+    let mut width = num_leaves;
+    while width > 1 {
+        width = width.div_ceil(2);
+        offsets.push(offsets[offsets.len() - 1] + width);
+    }
+    offsets
+}
+
+// hash two children into their parent with the prepared branch tag, sorting them first in sorted-pair mode
+fn hash_children_with<const HASH_SIZE: usize>(sorted_pairs: bool, branch_tag: &PreparedTag<HASH_SIZE>, left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    if sorted_pairs && left > right {
+        branch_tag.hash_pair(right, left)
+    } else {
+        branch_tag.hash_pair(left, right)
+    }
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> FlatMerkleTree<HASH_SIZE, H> {
+    // lays out the tree over the given leaf hashes and hashes all the branch layers
+    fn build_layers(&mut self, mut leaf_hashes: Vec<[u8; HASH_SIZE]>) {
+        self.offsets = layer_offsets(leaf_hashes.len());
+        leaf_hashes.resize(self.offsets[self.offsets.len() - 1], [0; HASH_SIZE]);
+        self.nodes = leaf_hashes;
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        for layer in 1..self.depth() {
+            let (below, above) = self.nodes.split_at_mut(self.offsets[layer]);
+            let children = &below[self.offsets[layer - 1]..];
+            for (parent, pair) in above.iter_mut().zip(children.chunks(2)) {
+                *parent = match pair {
+                    [left, right] => hash_children_with(self.sorted_pairs, &branch_tag, left, right),
+                    _ => self.padding.lone_parent(pair[0], &branch_tag)
+                };
+            }
+        }
+    }
+
+    /// Builds a tree from the leaf values (unhashed!) with the given tags, duplicating lone nodes, as `MerkleTree::build`.
+    pub fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> FlatMerkleTree<HASH_SIZE, H> {
+        Self::build_with_options(values, leaf_tag, branch_tag, Padding::Duplicate, false)
+    }
+
+    /// Same as `build`, with the padding strategy and the pair ordering of `MerkleTree::build_with_options`.
+    pub fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> FlatMerkleTree<HASH_SIZE, H> {
+        assert_output_size::<HASH_SIZE, H>();
+        let mut tree = FlatMerkleTree {
+            nodes: Vec::new(),
+            offsets: Vec::new(),
+            leaf_tag,
+            branch_tag,
+            padding,
+            sorted_pairs,
+            _hasher: std::marker::PhantomData
+        };
+        tree.rebuild_in_place(values);
+        tree
+    }
+
+    /// Rebuilds the tree from new leaf values (unhashed!), with the same tags, padding strategy and pair ordering.
+    pub fn rebuild_in_place(&mut self, values: Vec<Vec<u8>>) {
+        let leaf_tag = H::prepare_tag(&self.leaf_tag);
+        let mut leaf_hashes = std::mem::take(&mut self.nodes);
+        leaf_hashes.clear();
+        leaf_hashes.extend(values.iter().map(|value| leaf_tag.hash(value)));
+        self.build_layers(leaf_hashes);
+    }
+
+    /// Appends a leaf with the given value and returns its index. As in `MerkleTree::append`, all branch layers are
+    /// rehashed, but the leaf hashes are reused.
+    pub fn append(&mut self, value: &[u8]) -> usize {
+        let mut leaf_hashes = std::mem::take(&mut self.nodes);
+        leaf_hashes.truncate(self.offsets[1]);
+        leaf_hashes.push(H::tagged_hash(&self.leaf_tag, value));
+        self.build_layers(leaf_hashes);
+        self.num_leaves() - 1
+    }
+
+    /// Replaces the value of the leaf with the given index, rehashing only the path up to the root.
+    pub fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        self.nodes[index] = H::tagged_hash(&self.leaf_tag, value);
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        let mut curr_index = index;
+        for layer in 1..self.depth() {
+            let children = self.layer(layer - 1);
+            let left = curr_index - curr_index % 2;
+            let parent = if left + 1 < children.len() {
+                hash_children_with(self.sorted_pairs, &branch_tag, &children[left], &children[left + 1])
+            } else {
+                self.padding.lone_parent(children[left], &branch_tag)
+            };
+            curr_index /= 2;
+            self.nodes[self.offsets[layer] + curr_index] = parent;
+        }
+    }
+
+    /// Returns the Merkle root of the tree.
+    ///
+    /// # Panics
+    ///
+    /// If the tree has no leaves, since it then has no root. Use `try_get_root` for trees that may be empty.
+    pub fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
+        MerkleRoot(*self.root_ref())
+    }
+
+    /// Same as `get_root`, but returns None if the tree has no leaves instead of panicking.
+    pub fn try_get_root(&self) -> Option<MerkleRoot<HASH_SIZE>> {
+        self.layer(self.depth() - 1).first().map(|root| MerkleRoot(*root))
+    }
+
+    /// Same as `get_root`, but borrows the root stored in the tree instead of copying it.
+    ///
+    /// # Panics
+    ///
+    /// If the tree has no leaves, as `get_root`.
+    pub fn root_ref(&self) -> &[u8; HASH_SIZE] {
+        self.layer(self.depth() - 1).first().expect("the Merkle tree is empty")
+    }
+
+    /// Returns the hashes of the given layer, from 0 for the leaves up to `depth() - 1` for the root.
+    pub fn layer(&self, layer: usize) -> &[[u8; HASH_SIZE]] {
+        &self.nodes[self.offsets[layer]..self.offsets[layer + 1]]
+    }
+
+    /// Returns the number of hashes stored at each level of the tree, from the leaves up.
+    pub fn layer_sizes(&self) -> Vec<usize> {
+        self.offsets.windows(2).map(|bounds| bounds[1] - bounds[0]).collect()
+    }
+
+    /// Returns the Merkle proof for the leaf with the given value, or None if the value is not in the tree. If several
+    /// leaves have the value, this is the proof of the first one.
+    pub fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        self.get_leaf_index(&value).and_then(|index| self.get_proof_at(index))
+    }
+
+    /// Returns the Merkle proof for the leaf at the given position, or None if there is no such leaf.
+    pub fn get_proof_at(&self, index: usize) -> Option<MerkleProof<HASH_SIZE>> {
+        if index >= self.num_leaves() {
+            return None;
+        }
+        let mut proof = Vec::with_capacity(self.depth() - 1);
+        let mut curr_index = index;
+        for layer in 0..self.depth() - 1 {
+            let nodes = self.layer(layer);
+            if curr_index % 2 == 1 {
+                proof.push(MerkleProofItem::Left(nodes[curr_index - 1]));
+            } else if curr_index + 1 < nodes.len() {
+                proof.push(MerkleProofItem::Right(nodes[curr_index + 1]));
+            } // otherwise this is a lone node, which contributes no item
+            curr_index /= 2;
+        }
+        Some(MerkleProof(proof))
+    }
+
+    /// Returns the index of the (first) leaf with the given value, or None if the value is not in the tree.
+    pub fn get_leaf_index(&self, value: &[u8]) -> Option<usize> {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, value);
+        self.layer(0).iter().position(|&x| x == hash)
+    }
+
+    /// Returns the number of leaves in the tree.
+    pub fn num_leaves(&self) -> usize {
+        self.offsets[1]
+    }
+
+    /// Returns the depth of the tree, counted in layers: a single leaf has depth 1, and each halving adds a layer.
+    pub fn depth(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns the tag used for hashing the leaf nodes.
+    pub fn leaf_tag(&self) -> &[u8] {
+        &self.leaf_tag
+    }
+
+    /// Returns the tag used for hashing the branch nodes.
+    pub fn branch_tag(&self) -> &[u8] {
+        &self.branch_tag
+    }
+
+    /// Returns the padding strategy used for lone nodes.
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// Returns whether the children of each node are sorted before hashing them.
+    pub fn sorted_pairs(&self) -> bool {
+        self.sorted_pairs
+    }
+
+    /// Verifies a Merkle proof for the leaf with the given value against the root of this tree, as `MerkleTree::verify`.
+    pub fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        if self.sorted_pairs {
+            return self.get_leaf_index(value).is_some() && proof.verify_sorted::<H>(&H::tagged_hash(&self.leaf_tag, value), &self.get_root(), &self.branch_tag);
+        }
+        match self.get_leaf_index(value) {
+            Some(index) => verify_root::<HASH_SIZE, H>(value, proof, index, self.num_leaves(), &self.get_root(), &self.leaf_tag, &self.branch_tag, self.padding),
+            None => false
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Merkle trees and user databases for proofs of reserve
+//!
+//! The `merkle` and `mmr` modules implement the trees and their proofs, and `db` builds a tree of user
+//! balances. The web server lives in the binary, which needs the `server` feature (on by default), so
+//! consumers of the library can use `default-features = false` to avoid compiling axum and tokio. The `ffi`
+//! feature adds a C API to verify proofs (see `ffi`).
+//!
+//! Building a tree and verifying a proof against its root:
+//!
+//! ```
+//! use code_test::{merkle, MerkleTree, Padding, Sha256Algorithm};
+//!
+//! let values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec()];
+//! let tree = MerkleTree::<32, Sha256Algorithm>::build(values, b"Leaf".to_vec(), b"Branch".to_vec());
+//! let proof = tree.get_proof(b"bbb".to_vec()).unwrap();
+//! assert!(tree.verify(b"bbb", &proof));
+//!
+//! // a verifier only needs the root, the position of the leaf, the size of the tree and the parameters
+//! let root = tree.get_root();
+//! assert!(merkle::verify_root::<32, Sha256Algorithm>(b"bbb", &proof, 1, 3, &root, b"Leaf", b"Branch", Padding::Duplicate));
+//! assert!(!merkle::verify_root::<32, Sha256Algorithm>(b"ccc", &proof, 1, 3, &root, b"Leaf", b"Branch", Padding::Duplicate));
+//! ```
+pub mod merkle;
+// known answers to validate the implementations of HashAlgorithm against
+#[cfg(test)]
+mod test_vectors;
+pub mod mmr;
+pub mod flat;
+pub mod db;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+pub use merkle::{DigestAlgorithm, HashAlgorithm, MerkleProof, MerkleProofItem, MerkleRoot, MerkleTree, Padding, Sha256Algorithm};
+pub use db::{InMemoryDatabase, MutableUserDatabase, UserDatabase};
@@ -1,21 +1,55 @@
-mod merkle;
-mod db;
-use merkle::{MerkleTree, MerkleProof, MerkleRoot, Sha256Algorithm};
-use db::{UserDatabase, InMemoryDatabase};
+mod audit;
+use code_test::{db, merkle};
+use merkle::{HashAlgorithm, MerkleError, MerkleTree, MerkleTreeBuilder, MerkleProof, MerkleProofItem, MerkleRoot, Padding, ProofItemError, Sha256Algorithm};
+use db::{UserDatabase, MutableUserDatabase, InMemoryDatabase, OnlineDatabase, DatabaseBuilder, ProofError, AssetId, DEFAULT_ASSET, LEAF_VERSION};
 use axum::{
-    debug_handler, extract::{Json, Path, State}, http::StatusCode, response::{IntoResponse, Response}, routing::get, Router};
-use std::sync::Arc;
-use serde::Serialize;
+    body::{Body, Bytes, HttpBody}, debug_handler, extract::{DefaultBodyLimit, Json, Path, Query, Request, State}, http::{header, HeaderMap, StatusCode}, middleware::{self, Next}, response::{IntoResponse, Response}, routing::{any, get, post, put}, Extension, Router};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use serde::{Deserialize, Serialize};
+use data_encoding::HEXLOWER;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+use ed25519_dalek::{Signer, SigningKey};
+use tokio::sync::Semaphore;
+use tower_http::catch_panic::CatchPanicLayer;
+use audit::AuditLog;
 
 fn test_merkle_root() {
     let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
     let tag = (b"Bitcoin_Transaction").to_vec();
     let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
     let root = tree.get_root();
-    println!("{}", serde_json::to_string(&root).unwrap());
+    tracing::info!("{}", serde_json::to_string(&root).unwrap());
 }
 
-enum Error { UserNotFound(u64) }
+enum Error {
+    UserNotFound(u64),
+    UserRemoved(u64),
+    InvalidUserId(String),
+    TooManyIds(usize),
+    InvalidProofItem(usize, ProofItemError),
+    InvalidLeafHash(String),
+    LeafNotFound(String),
+    InvalidHex(String),
+    InvalidLeaf(usize, String),
+    InvalidCompactProof,
+    NoLeaves,
+    TreeNotFound(u64),
+    LeafIndexOutOfRange(usize),
+    TotalOverflow,
+    TreeDesync(u64),
+    IdempotencyKeyReused(String),
+    IdempotencyKeyInProgress(String),
+    WorkerFailed,
+    EmptyDatabase,
+    ReadOnly,
+    BodyTimeout,
+    BodyTooLarge,
+    BodyUnreadable,
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Merkle(MerkleError)
+}
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
@@ -23,205 +57,4401 @@ impl IntoResponse for Error {
             Error::UserNotFound(user_id) => {
                 (StatusCode::NOT_FOUND, format!("User with ID {} not found.", user_id)).into_response()
             }
+            Error::UserRemoved(user_id) => {
+                (StatusCode::GONE, format!("User with ID {} has been removed.", user_id)).into_response()
+            }
+            Error::InvalidUserId(user_id) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid user ID {}.", user_id)).into_response()
+            }
+            Error::TooManyIds(count) => {
+                (StatusCode::BAD_REQUEST, format!("Too many user IDs ({}), at most {} are allowed.", count, MAX_BATCH_SIZE)).into_response()
+            }
+            Error::InvalidProofItem(index, err) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid proof item at index {}: {}.", index, err)).into_response()
+            }
+            Error::InvalidLeafHash(hash) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid leaf hash {}.", hash)).into_response()
+            }
+            Error::LeafNotFound(hash) => {
+                (StatusCode::NOT_FOUND, format!("No user with leaf hash {}.", hash)).into_response()
+            }
+            Error::InvalidHex(value) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid hex string {}.", value)).into_response()
+            }
+            Error::InvalidLeaf(index, reason) => {
+                (StatusCode::BAD_REQUEST, format!("Invalid leaf at index {}: {}.", index, reason)).into_response()
+            }
+            Error::InvalidCompactProof => {
+                (StatusCode::BAD_REQUEST, "Malformed compact proof.").into_response()
+            }
+            Error::NoLeaves => {
+                (StatusCode::BAD_REQUEST, "A tree needs at least one leaf.").into_response()
+            }
+            Error::TreeNotFound(id) => {
+                (StatusCode::NOT_FOUND, format!("No tree with ID {}, it may have expired.", id)).into_response()
+            }
+            Error::LeafIndexOutOfRange(index) => {
+                (StatusCode::NOT_FOUND, format!("No leaf at index {}.", index)).into_response()
+            }
+            Error::TotalOverflow => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "The total balance overflows.").into_response()
+            }
+            Error::TreeDesync(user_id) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("The Merkle tree is out of sync with the data of user {}.", user_id)).into_response()
+            }
+            Error::IdempotencyKeyReused(key) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, format!("Idempotency key {} was used for another request.", key)).into_response()
+            }
+            Error::IdempotencyKeyInProgress(key) => {
+                (StatusCode::CONFLICT, format!("The request with idempotency key {} is still being applied.", key)).into_response()
+            }
+            Error::WorkerFailed => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "Failed to generate the proofs.").into_response()
+            }
+            Error::EmptyDatabase => {
+                (StatusCode::NOT_FOUND, "The database has no users, so it has no root.").into_response()
+            }
+            Error::ReadOnly => {
+                (StatusCode::FORBIDDEN, "The server is read-only.").into_response()
+            }
+            Error::BodyTimeout => {
+                // the client is too slow (or malicious), so the connection is not kept for another request
+                (StatusCode::REQUEST_TIMEOUT, [(header::CONNECTION, "close")], "Timed out reading the request body.").into_response()
+            }
+            Error::BodyTooLarge => {
+                (StatusCode::PAYLOAD_TOO_LARGE, "The request body is too large.").into_response()
+            }
+            Error::BodyUnreadable => {
+                (StatusCode::BAD_REQUEST, "Failed to read the request body.").into_response()
+            }
+            Error::Io(err) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("I/O error: {}.", err)).into_response()
+            }
+            Error::Json(err) => {
+                (StatusCode::BAD_REQUEST, format!("Malformed JSON: {}.", err)).into_response()
+            }
+            Error::Merkle(err) => {
+                (StatusCode::UNPROCESSABLE_ENTITY, format!("Cannot build the Merkle tree: {}.", err)).into_response()
+            }
+        }
+    }
+}
+
+impl From<u64> for Error {
+    fn from(user_id: u64) -> Self {
+        Error::UserNotFound(user_id)
+    }
+}
+
+// a user that exists without a leaf in the tree is a bug of the server, not of the request
+impl From<ProofError> for Error {
+    fn from(err: ProofError) -> Self {
+        match err {
+            ProofError::UserNotFound(user_id) => Error::UserNotFound(user_id),
+            ProofError::Desync(user_id) => Error::TreeDesync(user_id)
+        }
+    }
+}
+
+// failures of the server itself, such as reading a snapshot
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+// malformed data supplied by the client
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Json(err)
+    }
+}
+
+// data the client supplied is well-formed, but no tree can be built from it
+impl From<MerkleError> for Error {
+    fn from(err: MerkleError) -> Self {
+        Error::Merkle(err)
+    }
+}
+
+/*
+ * The database may be updated while the server is running, so the handlers share an OnlineDatabase and each
+ * works on the snapshot current when it starts.
+ */
+type Connection = Arc<OnlineDatabase<InMemoryDatabase<32, Sha256Algorithm>>>;
+
+// clients may hold the IDs of users that have since been removed, which is not the same as an ID that never existed;
+// a user holding other assets only has a balance of 0 in DEFAULT_ASSET
+fn lookup_balance(db: &InMemoryDatabase<32, Sha256Algorithm>, user_id: u64) -> Result<u64, Error> {
+    match db.get_balances(user_id) {
+        Some(balances) => Ok(balances.get(&DEFAULT_ASSET).copied().unwrap_or(0)),
+        None if db.is_removed(user_id) => Err(Error::UserRemoved(user_id)),
+        None => Err(Error::UserNotFound(user_id))
+    }
+}
+
+// how the root of an empty database is reported: with 204 NO CONTENT (the default), or as a null root
+#[derive(Deserialize, Default, Clone, Copy)]
+enum EmptyMode {
+    #[default]
+    #[serde(rename = "204")]
+    Status,
+    #[serde(rename = "null")]
+    Null,
+}
+
+#[derive(Deserialize)]
+struct RootParams {
+    #[serde(default)]
+    verbose: bool,
+    #[serde(default)]
+    empty: EmptyMode,
+}
+
+// the root together with what a client needs to make sense of it
+#[derive(Serialize)]
+struct VerboseRootResponse {
+    root: Option<MerkleRoot<32>>,
+    num_leaves: usize,
+    algorithm: &'static str,
+    hash_size: usize,
+}
+
+// the bare root by default, for backward compatibility
+async fn get_root(State(connection): State<Connection>, Query(params): Query<RootParams>) -> Response {
+    let db = connection.snapshot();
+    // the database may have been emptied by updates, in which case it has no root
+    let root = db.get_root();
+    if root.is_none() && matches!(params.empty, EmptyMode::Status) {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    if !params.verbose {
+        return Json(root).into_response();
+    }
+    Json(VerboseRootResponse {
+        root,
+        num_leaves: db.num_leaves(),
+        algorithm: Sha256Algorithm::NAME,
+        hash_size: 32,
+    }).into_response()
+}
+
+/*
+ * The position of the leaf and the size of the tree are included, so that clients can check the proof against a
+ * tree size they know (as in RFC 6962), and notice if their leaf was moved.
+ *
+ * The leaf of a user holding other assets than DEFAULT_ASSET commits to all its balances (see
+ * db::serialize_user_assets), so they are included, sorted by asset, for clients to rebuild the leaf. The balance is
+ * then the one in DEFAULT_ASSET (0 if the user does not hold it), which the leaf alone does not prove.
+ */
+#[derive(Serialize)]
+struct ProofResponse<P: Serialize> {
+    user_id: u64,
+    balance: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balances: Option<BTreeMap<AssetId, u64>>,
+    leaf_index: usize,
+    tree_size: usize,
+    proof: P,
+}
+
+impl<P: Serialize> ProofResponse<P> {
+    // the user exists, so its leaf has an index unless the tree is out of sync with its data
+    fn new(db: &InMemoryDatabase<32, Sha256Algorithm>, user_id: u64, balance: u64, proof: P) -> Result<Self, Error> {
+        let leaf_index = db.leaf_index(user_id).ok_or(Error::TreeDesync(user_id))?;
+        Ok(ProofResponse { user_id, balance, balances: db.asset_leaf_balances(user_id), leaf_index, tree_size: db.num_leaves(), proof })
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProofFormat {
+    #[default]
+    Tuple,
+    Object,
+}
+
+// how a missing user is reported: with an error status (the default), or as a 200 response saying it is missing
+#[derive(Deserialize, Default, Clone, Copy)]
+enum MissingMode {
+    #[default]
+    #[serde(rename = "404")]
+    Status,
+    #[serde(rename = "null")]
+    NotFound,
+}
+
+#[derive(Deserialize)]
+struct ProofParams {
+    #[serde(default)]
+    format: ProofFormat,
+    #[serde(default)]
+    missing: MissingMode,
+}
+
+#[derive(Serialize)]
+struct MissingProofResponse {
+    user_id: u64,
+    found: bool,
+    error: &'static str,
+}
+
+#[debug_handler(state = Connection)]
+/*
+ * The router only matches whole path segments, so /proof/{id}.bin is handled by this handler as well:
+ * an ID with the .bin suffix requests the proof in the compact binary form (see MerkleProof::to_compact).
+ */
+async fn get_proof(
+    State(connection): State<Connection>,
+    Extension(audit_log): Extension<Option<Arc<AuditLog>>>,
+    Path(id): Path<String>,
+    Query(params): Query<ProofParams>,
+    headers: HeaderMap
+) -> Result<Response, Error> {
+    let (user_id, binary) = match id.strip_suffix(".bin") {
+        Some(user_id) => (user_id, true),
+        None => (id.as_str(), false)
+    };
+    let user_id: u64 = user_id.parse().map_err(|_| Error::InvalidUserId(id.clone()))?;
+    let db = connection.snapshot();
+    let balance = match (lookup_balance(&db, user_id), params.missing) {
+        (Ok(balance), _) => balance,
+        (Err(Error::UserRemoved(_)), MissingMode::NotFound) => return Ok(Json(MissingProofResponse { user_id, found: false, error: "removed" }).into_response()),
+        (Err(Error::UserNotFound(_)), MissingMode::NotFound) => return Ok(Json(MissingProofResponse { user_id, found: false, error: "not found" }).into_response()),
+        (Err(err), _) => return Err(err)
+    };
+    let representation = match (binary, &params.format) {
+        (true, _) => "bin",
+        (false, ProofFormat::Tuple) => "tuple",
+        (false, ProofFormat::Object) => "object"
+    };
+    let current_root = db.get_root().ok_or(Error::EmptyDatabase)?;
+    let etag = proof_etag(&current_root, user_id, representation);
+    // the root the proof was built from, so clients need not request it separately (it may change in the meantime)
+    let root = format!("0x{}", HEXLOWER.encode(&current_root.0));
+    if if_none_match(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag), (ROOT_HEADER, root)]).into_response());
+    }
+    let proof = db.try_get_proof(user_id)?;
+    record_proofs(&audit_log, &current_root, vec![(user_id, proof.clone())]).await?;
+    let response = if binary {
+        ([(header::CONTENT_TYPE, "application/octet-stream")], proof.to_compact()).into_response()
+    } else {
+        match params.format {
+            ProofFormat::Tuple => Json(ProofResponse::new(&db, user_id, balance, proof)?).into_response(),
+            ProofFormat::Object => Json(ProofResponse::new(&db, user_id, balance, proof.as_objects())?).into_response()
+        }
+    };
+    Ok(([(header::ETAG, etag), (ROOT_HEADER, root)], response).into_response())
+}
+
+const ROOT_HEADER: header::HeaderName = header::HeaderName::from_static("x-merkle-root");
+const LEAVES_HEADER: header::HeaderName = header::HeaderName::from_static("x-merkle-leaves");
+
+// records the proofs about to be served in the audit log, if any; proofs that cannot be recorded are not served.
+// Recording waits for the disk, so it runs on a blocking thread rather than stalling the async worker.
+async fn record_proofs(audit_log: &Option<Arc<AuditLog>>, root: &MerkleRoot<32>, proofs: Vec<(u64, MerkleProof<32>)>) -> Result<(), Error> {
+    let Some(audit_log) = audit_log.clone() else {
+        return Ok(());
+    };
+    let root = MerkleRoot(root.0);
+    tokio::task::spawn_blocking(move || audit_log.record(&root, proofs.iter().map(|(user_id, proof)| (*user_id, proof)))).await
+        .map_err(|_| Error::WorkerFailed)??;
+    Ok(())
+}
+
+/*
+ * A proof is determined by the root it proves against and the user (whose balance is committed to by the root), so
+ * its ETag is derived from both, and from the representation, since the formats of the same proof differ. As long
+ * as the root does not change, clients can keep revalidating cached proofs with 304 NOT MODIFIED responses.
+ */
+fn proof_etag(root: &MerkleRoot<32>, user_id: u64, representation: &str) -> String {
+    format!("\"{}-{}-{}\"", HEXLOWER.encode(&root.0), user_id, representation)
+}
+
+// whether the If-None-Match header of a conditional request matches the given ETag
+fn if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+}
+
+/*
+ * Clients that do not want to expose their numeric ID can look up their proof by the hash of their leaf instead,
+ * i.e. the leaf tag hash of their serialized (ID, balance), given in hex with or without the 0x prefix.
+ */
+async fn get_proof_by_hash(
+    State(connection): State<Connection>,
+    Extension(audit_log): Extension<Option<Arc<AuditLog>>>,
+    Path(leaf_hash): Path<String>
+) -> Result<Json<ProofResponse<MerkleProof<32>>>, Error> {
+    let digits = leaf_hash.strip_prefix("0x").unwrap_or(&leaf_hash);
+    let hash: [u8; 32] = HEXLOWER.decode(digits.as_bytes()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Error::InvalidLeafHash(leaf_hash.clone()))?;
+    let db = connection.snapshot();
+    let user_id = db.find_user_by_leaf_hash(&hash).ok_or(Error::LeafNotFound(leaf_hash))?;
+    let balance = lookup_balance(&db, user_id)?;
+    let proof = db.try_get_proof(user_id)?;
+    record_proofs(&audit_log, &db.get_root().ok_or(Error::EmptyDatabase)?, vec![(user_id, proof.clone())]).await?;
+    Ok(Json(ProofResponse::new(&db, user_id, balance, proof)?))
+}
+
+const MAX_BATCH_SIZE: usize = 1000;
+// the number of blocking tasks a batch of proofs is split into, which is also the number of such tasks that may run
+// at once across all batches
+const BATCH_WORKERS: usize = 8;
+
+#[derive(Deserialize)]
+struct BatchProofParams {
+    ids: String,
+}
+
+// an entry of a batch response: either the proof of the user, or the reason why there is none
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchProofEntry {
+    Proof(ProofResponse<MerkleProof<32>>),
+    Missing { user_id: u64, error: &'static str }
+}
+
+/*
+ * The entries are returned as an array in the order of the requested IDs (duplicates included), as clients
+ * correlate them by position, so the response is deterministic. A missing user does not fail the whole batch.
+ *
+ * Generating proofs is CPU-bound, so a large batch is generated on the blocking threads of the runtime, by a bounded
+ * number of tasks, rather than on the async worker that serves the request (which would stall the other requests).
+ * The tasks of all batches share BATCH_WORKERS permits, so that concurrent batches cannot tie up every blocking
+ * thread of the runtime.
+ */
+async fn get_proofs(
+    State(connection): State<Connection>,
+    Extension(audit_log): Extension<Option<Arc<AuditLog>>>,
+    Extension(workers): Extension<Arc<Semaphore>>,
+    Query(params): Query<BatchProofParams>
+) -> Result<Json<Vec<BatchProofEntry>>, Error> {
+    let user_ids = params.ids.split(',')
+        .map(|id| id.trim().parse::<u64>().map_err(|_| Error::InvalidUserId(id.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if user_ids.len() > MAX_BATCH_SIZE {
+        return Err(Error::TooManyIds(user_ids.len()));
+    }
+    let db = connection.snapshot();
+    // the IDs are split into at most BATCH_WORKERS chunks, and the entries are put back together in the chunk order
+    let chunk_size = user_ids.len().div_ceil(BATCH_WORKERS).max(1);
+    let mut tasks = Vec::with_capacity(BATCH_WORKERS);
+    for chunk in user_ids.chunks(chunk_size) {
+        // the semaphore is never closed, and the permit is released when the task is done
+        let permit = workers.clone().acquire_owned().await.unwrap();
+        let (db, chunk) = (db.clone(), chunk.to_vec());
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            batch_proof_entries(&db, chunk)
+        }));
+    }
+    let mut entries = Vec::with_capacity(user_ids.len());
+    for task in tasks {
+        // a task only fails to join if it panicked
+        entries.extend(task.await.map_err(|_| Error::WorkerFailed)??);
+    }
+    let proofs: Vec<_> = entries.iter().filter_map(|entry| match entry {
+        BatchProofEntry::Proof(response) => Some((response.user_id, response.proof.clone())),
+        BatchProofEntry::Missing { .. } => None
+    }).collect();
+    if !proofs.is_empty() {
+        record_proofs(&audit_log, &db.get_root().ok_or(Error::EmptyDatabase)?, proofs).await?;
+    }
+    Ok(Json(entries))
+}
+
+// the entries of a chunk of a batch, generated on a blocking thread
+fn batch_proof_entries(db: &InMemoryDatabase<32, Sha256Algorithm>, user_ids: Vec<u64>) -> Result<Vec<BatchProofEntry>, Error> {
+    user_ids.into_iter().map(|user_id| Ok(match lookup_balance(db, user_id) {
+        Ok(balance) => BatchProofEntry::Proof(ProofResponse::new(db, user_id, balance, db.try_get_proof(user_id)?)?),
+        Err(Error::UserRemoved(_)) => BatchProofEntry::Missing { user_id, error: "removed" },
+        Err(_) => BatchProofEntry::Missing { user_id, error: "not found" }
+    })).collect()
+}
+
+#[derive(Deserialize)]
+struct UsersParams {
+    balance: Option<u64>,
+}
+
+// the balances of all users (or only those with the given balance), keyed by user ID in increasing order
+async fn get_users(
+    State(connection): State<Connection>,
+    Query(params): Query<UsersParams>
+) -> Json<BTreeMap<u64, u64>> {
+    let db = connection.snapshot();
+    let users = db.users().filter(|(_, balance)| params.balance.is_none_or(|wanted| *balance == wanted)).collect();
+    Json(users)
+}
+
+#[derive(Serialize)]
+struct VerifyResponse {
+    valid: bool,
+    balance: u64,
+    root: MerkleRoot<32>,
+}
+
+/*
+ * This is only a sanity check for clients that cannot verify proofs themselves: the server
+ * vouches for its own proof, so a client that wants real assurance must still verify the proof
+ * returned by /proof/{id} against the published root independently.
+ */
+#[debug_handler(state = Connection)]
+async fn verify_proof(
+    State(connection): State<Connection>,
+    Path(user_id): Path<u64>
+) -> Result<Json<VerifyResponse>, Error> {
+    let db = connection.snapshot();
+    let balance = lookup_balance(&db, user_id)?;
+    let valid = db.verify_user(user_id)?;
+    Ok(Json(VerifyResponse { valid, balance, root: db.get_root().ok_or(Error::EmptyDatabase)? }))
+}
+
+/*
+ * A proof submitted for verification is taken in the same [direction, "0x..."] form that /proof/{id} returns.
+ * The items are parsed one by one, so a non-canonical item (e.g. a direction other than 0 and 1, or a hash of
+ * the wrong length) is rejected with its index rather than failing the whole body with a generic error.
+ * The leaf of a user holding other assets commits to all its balances, which are then given as returned by
+ * /proof/{id}; the leaf is rebuilt from them rather than from the balance.
+ */
+#[derive(Deserialize)]
+struct SubmittedProof {
+    user_id: u64,
+    balance: u64,
+    balances: Option<BTreeMap<AssetId, u64>>,
+    proof: Vec<(u64, String)>,
+}
+
+// an empty database has no root (null), against which no proof is valid
+#[derive(Serialize)]
+struct SubmittedProofResponse {
+    valid: bool,
+    root: Option<MerkleRoot<32>>,
+}
+
+#[debug_handler(state = Connection)]
+async fn verify_submitted_proof(
+    State(connection): State<Connection>,
+    Json(request): Json<SubmittedProof>
+) -> Result<Json<SubmittedProofResponse>, Error> {
+    let items = request.proof.iter().enumerate()
+        .map(|(index, (direction, hash))| MerkleProofItem::from_parts(*direction, hash).map_err(|err| Error::InvalidProofItem(index, err)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let db = connection.snapshot();
+    let proof = MerkleProof(items);
+    let valid = db.get_root().is_some() && match request.balances {
+        Some(balances) => db.verify_asset_proof(request.user_id, &balances.into_iter().collect(), &proof),
+        None => db.verify_proof(request.user_id, request.balance, &proof)
+    };
+    Ok(Json(SubmittedProofResponse { valid, root: db.get_root() }))
+}
+
+/*
+ * The compact binary form of /proof/{id}.bin, for verifiers short on bandwidth. The proof is the body, and the leaf
+ * is given in the query, with optionally the root the client expects: a proof is only valid against the current root,
+ * so it is reported invalid if the client expects another one.
+ */
+#[derive(Deserialize)]
+struct CompactProofParams {
+    user_id: u64,
+    balance: u64,
+    root: Option<String>,
+}
+
+#[debug_handler(state = Connection)]
+async fn verify_compact_proof(
+    State(connection): State<Connection>,
+    Query(params): Query<CompactProofParams>,
+    body: Bytes
+) -> Result<Json<SubmittedProofResponse>, Error> {
+    let proof = MerkleProof::from_compact(&body).ok_or(Error::InvalidCompactProof)?;
+    let expected_root = params.root.as_deref().map(decode_hex_param).transpose()?;
+    let db = connection.snapshot();
+    let root = db.get_root();
+    let valid = root.as_ref().is_some_and(|root| expected_root.is_none_or(|expected| expected == root.0)) && db.verify_proof(params.user_id, params.balance, &proof);
+    Ok(Json(SubmittedProofResponse { valid, root }))
+}
+
+#[derive(Serialize)]
+struct SignedRootResponse {
+    root: MerkleRoot<32>,
+    epoch: u64,
+    timestamp: u64,
+    signature: String,
+    pubkey: String,
+}
+
+// the message signed to attest a root: the root followed by its epoch and the Unix timestamp, as 8 big-endian bytes each
+fn attestation_payload(root: &MerkleRoot<32>, epoch: u64, timestamp: u64) -> Vec<u8> {
+    [&root.0[..], &epoch.to_be_bytes(), &timestamp.to_be_bytes()].concat()
+}
+
+/*
+ * The root signed with the Ed25519 key of the operator, so that it can be published and checked by third parties.
+ * The timestamp is signed as well, so clients know when the attestation was produced and can reject stale ones, and
+ * so is the epoch of the database state (see OnlineDatabase), so that an attestation of an older root cannot be
+ * replayed as the current one: clients reject attestations with an epoch lower than the latest they have seen,
+ * which /params exposes.
+ */
+#[debug_handler(state = Connection)]
+async fn get_signed_root(
+    State(connection): State<Connection>,
+    Extension(key): Extension<Arc<SigningKey>>
+) -> Result<Json<SignedRootResponse>, Error> {
+    let (db, epoch) = connection.snapshot_with_epoch();
+    let root = db.get_root().ok_or(Error::EmptyDatabase)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+    let signature = key.sign(&attestation_payload(&root, epoch, timestamp));
+    Ok(Json(SignedRootResponse {
+        root,
+        epoch,
+        timestamp,
+        signature: format!("0x{}", HEXLOWER.encode(&signature.to_bytes())),
+        pubkey: format!("0x{}", HEXLOWER.encode(key.verifying_key().as_bytes()))
+    }))
+}
+
+// the parameters a client needs to verify proofs independently
+#[derive(Serialize)]
+struct ParamsResponse {
+    hash_algorithm: &'static str,
+    leaf_version: u8,
+    leaf_type: Option<u8>, // null if the leaves are untyped
+    leaf_tag: String,
+    branch_tag: String,
+    padding: Padding,
+    sorted_pairs: bool,
+    epoch: u64,
+}
+
+async fn get_params(State(connection): State<Connection>) -> Json<ParamsResponse> {
+    let (db, epoch) = connection.snapshot_with_epoch();
+    Json(ParamsResponse {
+        hash_algorithm: Sha256Algorithm::NAME,
+        leaf_version: LEAF_VERSION,
+        leaf_type: db.leaf_type(),
+        leaf_tag: format!("0x{}", HEXLOWER.encode(db.leaf_tag())),
+        branch_tag: format!("0x{}", HEXLOWER.encode(db.branch_tag())),
+        padding: db.padding(),
+        sorted_pairs: db.sorted_pairs(),
+        epoch
+    })
+}
+
+// figures that help operators size the machine running the server
+#[derive(Serialize)]
+struct StatsResponse {
+    build_time_ms: f64,
+    last_update: Option<u64>,
+    num_leaves: usize,
+    num_layers: usize,
+    memory_bytes: usize,
+}
+
+async fn get_stats(State(connection): State<Connection>) -> Json<StatsResponse> {
+    let db = connection.snapshot();
+    let layer_sizes = db.layer_sizes();
+    Json(StatsResponse {
+        build_time_ms: db.build_time().as_secs_f64() * 1000.0,
+        last_update: connection.last_update().map(|time| time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())),
+        num_leaves: db.num_leaves(),
+        num_layers: layer_sizes.len(),
+        // only the hashes of the tree are counted, not the user table
+        memory_bytes: layer_sizes.iter().sum::<usize>() * 32
+    })
+}
+
+#[derive(Serialize)]
+struct TransparencyRow {
+    index: usize,
+    user_id: u64,
+    balance: u64,
+    leaf_hash: String,
+}
+
+// every leaf with the user it commits to; this reveals all balances, so it is only served with --transparency
+async fn get_transparency(State(connection): State<Connection>) -> Json<Vec<TransparencyRow>> {
+    let rows = connection.snapshot().export_leaves_with_values().into_iter()
+        .map(|(index, user_id, balance, leaf_hash)| TransparencyRow { index, user_id, balance, leaf_hash: format!("0x{}", HEXLOWER.encode(&leaf_hash)) })
+        .collect();
+    Json(rows)
+}
+
+const AGGREGATE_TAG: &[u8; 24] = b"ProofOfReserve_Aggregate";
+
+#[derive(Serialize)]
+struct AggregateRootResponse {
+    root: MerkleRoot<32>,
+    pool_roots: Vec<MerkleRoot<32>>,
+}
+
+// the commitment to the roots of all pools served, of which there is currently one
+async fn get_aggregate_root(State(connection): State<Connection>) -> Result<Json<AggregateRootResponse>, Error> {
+    let pool_roots = vec![connection.snapshot().get_root().ok_or(Error::EmptyDatabase)?];
+    let root = merkle::aggregate_roots::<32, Sha256Algorithm>(&pool_roots, AGGREGATE_TAG);
+    Ok(Json(AggregateRootResponse { root, pool_roots }))
+}
+
+#[derive(Deserialize)]
+struct CheckTotalRequest {
+    claimed_total: u64,
+}
+
+#[derive(Serialize)]
+struct CheckTotalResponse {
+    computed_total: u64,
+    claimed_total: u64,
+    matches: bool,
+}
+
+// compares the total liabilities in the database against the total claimed by the operator
+#[debug_handler(state = Connection)]
+async fn check_total(
+    State(connection): State<Connection>,
+    Json(request): Json<CheckTotalRequest>
+) -> Result<Json<CheckTotalResponse>, Error> {
+    let computed_total = connection.snapshot().total_balance(DEFAULT_ASSET).ok_or(Error::TotalOverflow)?;
+    Ok(Json(CheckTotalResponse {
+        computed_total,
+        claimed_total: request.claimed_total,
+        matches: computed_total == request.claimed_total
+    }))
+}
+
+#[derive(Deserialize)]
+struct SetBalanceRequest {
+    balance: u64,
+}
+
+// the state of a user after an update, with the root it is now committed to (null if the database is now empty)
+#[derive(Serialize)]
+struct UpdateResponse {
+    user_id: u64,
+    balance: u64,
+    root: Option<MerkleRoot<32>>,
+}
+
+#[debug_handler(state = Connection)]
+async fn set_balance(
+    State(connection): State<Connection>,
+    Path(user_id): Path<u64>,
+    Json(request): Json<SetBalanceRequest>
+) -> Json<UpdateResponse> {
+    // setting the balance the user already has publishes no new state (nor epoch)
+    let root = connection.try_update(|db| {
+        if db.get_balance(user_id, DEFAULT_ASSET) == Some(request.balance) {
+            return Err(db.get_root());
+        }
+        db.set_balance(user_id, request.balance);
+        Ok(db.get_root())
+    }).unwrap_or_else(|root| root);
+    Json(UpdateResponse { user_id, balance: request.balance, root })
+}
+
+// responds with the balance the user had before being removed
+#[debug_handler(state = Connection)]
+async fn remove_user(
+    State(connection): State<Connection>,
+    Path(user_id): Path<u64>
+) -> Result<Json<UpdateResponse>, Error> {
+    connection.try_update(|db| {
+        lookup_balance(db, user_id)?;
+        let balance = db.remove_user(user_id).unwrap().get(&DEFAULT_ASSET).copied().unwrap_or(0);
+        Ok(Json(UpdateResponse { user_id, balance, root: db.get_root() }))
+    })
+}
+
+#[derive(Deserialize)]
+struct BulkInsertRequest {
+    users: Vec<(u64, u64)>,
+}
+
+#[derive(Serialize)]
+struct BulkInsertResponse {
+    inserted: usize,
+    root: Option<MerkleRoot<32>>,
+}
+
+const MAX_IDEMPOTENCY_KEYS: usize = 1000;
+
+// the number of users inserted and the root after inserting them
+type BulkInsertResult = (usize, Option<[u8; 32]>);
+
+/*
+ * The results of the bulk inserts sent with an Idempotency-Key header, so that a retried request is answered with
+ * the result of the first one instead of being applied again. Only the most recent keys are remembered. Each key is
+ * remembered with the hash of its request, so that reusing a key for another request is rejected rather than
+ * answered with the result of the first one.
+ *
+ * A key is reserved before its request is applied, and its result recorded afterwards, so the keys are not locked
+ * while the database is updated; a retry arriving in the meantime is rejected, since its result is not known yet.
+ * A request that fails while being applied (e.g. panics) releases its key, so that it can be retried.
+ */
+#[derive(Default)]
+struct IdempotencyKeys {
+    results: HashMap<String, ([u8; 32], Option<BulkInsertResult>)>, // None while the request is being applied
+    order: VecDeque<String>, // the keys from the oldest to the most recent
+}
+
+impl IdempotencyKeys {
+    // returns the result of the request the key was already used for, or reserves the key and returns None
+    fn reserve(&mut self, key: &str, request_hash: [u8; 32]) -> Result<Option<BulkInsertResult>, Error> {
+        match self.results.get(key) {
+            Some((hash, _)) if *hash != request_hash => Err(Error::IdempotencyKeyReused(key.to_string())),
+            Some((_, None)) => Err(Error::IdempotencyKeyInProgress(key.to_string())),
+            Some((_, Some(result))) => Ok(Some(*result)),
+            None => {
+                self.insert(key.to_string(), request_hash, None);
+                Ok(None)
+            }
+        }
+    }
+
+    // records the result of a reserved key, unless it was forgotten in the meantime
+    fn complete(&mut self, key: &str, result: BulkInsertResult) {
+        if let Some((_, pending)) = self.results.get_mut(key) {
+            *pending = Some(result);
+        }
+    }
+
+    // forgets a reserved key whose request failed, so that it can be retried
+    fn release(&mut self, key: &str) {
+        if let Some((_, None)) = self.results.get(key) {
+            self.results.remove(key);
+            self.order.retain(|reserved| reserved != key);
+        }
+    }
+
+    fn insert(&mut self, key: String, request_hash: [u8; 32], result: Option<BulkInsertResult>) {
+        if self.order.len() == MAX_IDEMPOTENCY_KEYS {
+            let oldest = self.order.pop_front().unwrap();
+            self.results.remove(&oldest);
+        }
+        self.order.push_back(key.clone());
+        self.results.insert(key, (request_hash, result));
+    }
+}
+
+// the keys are consistent after every step, so a panic while they were locked leaves nothing to repair
+fn lock_keys(keys: &Mutex<IdempotencyKeys>) -> std::sync::MutexGuard<'_, IdempotencyKeys> {
+    keys.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// a reserved key, which is released if its request fails (e.g. panics) before its result is recorded
+struct Reservation<'a> {
+    keys: &'a Mutex<IdempotencyKeys>,
+    key: &'a str,
+}
+
+impl Reservation<'_> {
+    fn complete(self, result: BulkInsertResult) {
+        lock_keys(self.keys).complete(self.key, result);
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        lock_keys(self.keys).release(self.key);
+    }
+}
+
+// applies the request with the given key, unless it was already applied, in which case its result is returned
+fn apply_once(keys: &Mutex<IdempotencyKeys>, key: &str, request_hash: [u8; 32], apply: impl FnOnce() -> BulkInsertResult) -> Result<BulkInsertResult, Error> {
+    if let Some(result) = lock_keys(keys).reserve(key, request_hash)? {
+        return Ok(result);
+    }
+    let reservation = Reservation { keys, key };
+    let result = apply();
+    reservation.complete(result);
+    Ok(result)
+}
+
+// the hash identifying a bulk insert, over the user IDs and balances as 8 big-endian bytes each
+fn bulk_insert_hash(users: &[(u64, u64)]) -> [u8; 32] {
+    let mut hasher = <sha2::Sha256 as sha2::Digest>::new();
+    for (user_id, balance) in users {
+        sha2::Digest::update(&mut hasher, user_id.to_be_bytes());
+        sha2::Digest::update(&mut hasher, balance.to_be_bytes());
+    }
+    sha2::Digest::finalize(hasher).into()
+}
+
+// adds or updates several users at once
+#[debug_handler(state = Connection)]
+async fn insert_users(
+    State(connection): State<Connection>,
+    Extension(keys): Extension<Arc<Mutex<IdempotencyKeys>>>,
+    headers: HeaderMap,
+    Json(request): Json<BulkInsertRequest>
+) -> Result<Json<BulkInsertResponse>, Error> {
+    let apply = || connection.try_update(|db| {
+        if request.users.iter().all(|(user_id, balance)| db.get_balance(*user_id, DEFAULT_ASSET) == Some(*balance)) {
+            return Err((request.users.len(), db.get_root().map(|root| root.0)));
+        }
+        for (user_id, balance) in &request.users {
+            db.set_balance(*user_id, *balance);
+        }
+        Ok((request.users.len(), db.get_root().map(|root| root.0)))
+    }).unwrap_or_else(|result| result);
+    let (inserted, root) = match headers.get("idempotency-key").map(|key| String::from_utf8_lossy(key.as_bytes()).into_owned()) {
+        Some(key) => apply_once(&keys, &key, bulk_insert_hash(&request.users), apply)?,
+        None => apply()
+    };
+    Ok(Json(BulkInsertResponse { inserted, root: root.map(MerkleRoot) }))
+}
+
+// whether the leaves of /build are values, hashed with the leaf tag, or leaf hashes already
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum LeafMode {
+    #[default]
+    Raw,
+    Hash,
+}
+
+#[derive(Deserialize)]
+struct BuildRequest {
+    leaves: Vec<String>,
+    leaf_tag: String,
+    branch_tag: String,
+    #[serde(default)]
+    leaf_mode: LeafMode,
+}
+
+#[derive(Serialize)]
+struct BuildResponse {
+    id: u64,
+    root: MerkleRoot<32>,
+    num_leaves: usize,
+}
+
+#[derive(Serialize)]
+struct TreeProofResponse {
+    index: usize,
+    root: MerkleRoot<32>,
+    proof: MerkleProof<32>,
+}
+
+const MAX_EPHEMERAL_TREES: usize = 100;
+const EPHEMERAL_TREE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/*
+ * Besides proving the balances in its database, the server can build trees of arbitrary leaves with the tags of
+ * the client, to serve as a generic Merkle prover. Such trees are only kept for a while, and only the most recent
+ * ones are kept at all. Their IDs are random, so that a client cannot fetch the trees of others by guessing them.
+ */
+#[derive(Default)]
+struct EphemeralTrees {
+    trees: HashMap<u64, (Instant, MerkleTree<32, Sha256Algorithm>)>,
+    order: VecDeque<u64>, // the IDs from the oldest to the most recent
+}
+
+impl EphemeralTrees {
+    fn insert(&mut self, tree: MerkleTree<32, Sha256Algorithm>, now: Instant) -> u64 {
+        // the trees all live equally long, so the expired ones are the oldest
+        while let Some(oldest) = self.order.front() {
+            if self.order.len() < MAX_EPHEMERAL_TREES && !self.is_expired(*oldest, now) {
+                break;
+            }
+            self.trees.remove(oldest);
+            self.order.pop_front();
+        }
+        let id = loop {
+            let mut bytes = [0; 8];
+            getrandom::getrandom(&mut bytes).expect("the system has no source of randomness");
+            let id = u64::from_be_bytes(bytes);
+            if !self.trees.contains_key(&id) {
+                break id;
+            }
+        };
+        self.order.push_back(id);
+        self.trees.insert(id, (now, tree));
+        id
+    }
+
+    fn get(&self, id: u64, now: Instant) -> Option<&MerkleTree<32, Sha256Algorithm>> {
+        if self.is_expired(id, now) {
+            return None;
+        }
+        self.trees.get(&id).map(|(_, tree)| tree)
+    }
+
+    fn is_expired(&self, id: u64, now: Instant) -> bool {
+        self.trees.get(&id).is_some_and(|(created, _)| now.duration_since(*created) >= EPHEMERAL_TREE_TTL)
+    }
+}
+
+// hex strings in requests may or may not have the 0x prefix
+fn decode_hex_param(value: &str) -> Result<Vec<u8>, Error> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    HEXLOWER.decode(digits.as_bytes()).map_err(|_| Error::InvalidHex(value.to_string()))
+}
+
+#[debug_handler(state = Connection)]
+async fn build_tree(
+    Extension(trees): Extension<Arc<Mutex<EphemeralTrees>>>,
+    Json(request): Json<BuildRequest>
+) -> Result<Json<BuildResponse>, Error> {
+    let leaf_tag = decode_hex_param(&request.leaf_tag)?;
+    let branch_tag = decode_hex_param(&request.branch_tag)?;
+    if request.leaves.is_empty() {
+        return Err(Error::NoLeaves);
+    }
+    // every leaf is checked before building, and the first bad one is reported with its index
+    let mut builder = MerkleTreeBuilder::<32, Sha256Algorithm>::new(leaf_tag, branch_tag);
+    for (index, leaf) in request.leaves.iter().enumerate() {
+        let bytes = decode_hex_param(leaf).map_err(|_| Error::InvalidLeaf(index, format!("{} is not a hex string", leaf)))?;
+        match request.leaf_mode {
+            LeafMode::Raw => builder.push_leaf(&bytes),
+            LeafMode::Hash => {
+                let hash = bytes.try_into().map_err(|bytes: Vec<u8>| Error::InvalidLeaf(index, format!("a leaf hash has 32 bytes, not {}", bytes.len())))?;
+                builder.push_leaf_hash(hash)
+            }
+        };
+    }
+    let tree = builder.finish();
+    let (root, num_leaves) = (tree.get_root(), tree.num_leaves());
+    let id = trees.lock().unwrap().insert(tree, Instant::now());
+    Ok(Json(BuildResponse { id, root, num_leaves }))
+}
+
+#[debug_handler(state = Connection)]
+async fn get_tree_proof(
+    Extension(trees): Extension<Arc<Mutex<EphemeralTrees>>>,
+    Path((id, index)): Path<(u64, usize)>
+) -> Result<Json<TreeProofResponse>, Error> {
+    let trees = trees.lock().unwrap();
+    let tree = trees.get(id, Instant::now()).ok_or(Error::TreeNotFound(id))?;
+    let proof = tree.get_proof_at(index).ok_or(Error::LeafIndexOutOfRange(index))?;
+    Ok(Json(TreeProofResponse { index, root: tree.get_root(), proof }))
+}
+
+// answers the mutation endpoints of a read-only server
+async fn read_only() -> Error {
+    Error::ReadOnly
+}
+
+// with RUST_LOG=debug, each request is logged within a span carrying its method and URI
+async fn trace_request(request: Request, next: Next) -> Response {
+    let span = tracing::debug_span!("request", method = %request.method(), uri = %request.uri());
+    async move {
+        tracing::debug!("started processing request");
+        let response = next.run(request).await;
+        tracing::debug!(status = %response.status(), "finished processing request");
+        response
+    }.instrument(span).await
+}
+
+// every response, errors included, carries the current root and number of leaves, unless the handler already set
+// them (e.g. a proof, which carries the root it was built from)
+async fn merkle_headers(State(connection): State<Connection>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let db = connection.snapshot();
+    let headers = response.headers_mut();
+    if let (false, Some(root)) = (headers.contains_key(ROOT_HEADER), db.get_root()) {
+        headers.insert(ROOT_HEADER, format!("0x{}", HEXLOWER.encode(&root.0)).parse().unwrap());
+    }
+    if !headers.contains_key(LEAVES_HEADER) {
+        headers.insert(LEAVES_HEADER, db.num_leaves().into());
+    }
+    response
+}
+
+// the limits on reading request bodies, whichever the endpoint
+#[derive(Debug, Clone, Copy)]
+struct BodyLimits {
+    max_size: usize,
+    max_time: Duration
+}
+
+/*
+ * Bodies are read in full within max_time before the request reaches its handler, so that a client trickling a body
+ * (a slowloris attack) cannot hold a connection, and a task, for longer. The body is also cut at max_size, so that it
+ * is never buffered beyond the limit that the endpoints enforce anyway.
+ */
+async fn read_body_in_time(State(limits): State<BodyLimits>, request: Request, next: Next) -> Result<Response, Error> {
+    if request.body().size_hint().exact() == Some(0) {
+        return Ok(next.run(request).await);
+    }
+    let (parts, mut body) = request.into_parts();
+    let read = async {
+        let mut bytes = Vec::new();
+        while let Some(frame) = std::future::poll_fn(|cx| std::pin::Pin::new(&mut body).poll_frame(cx)).await {
+            if let Ok(data) = frame.map_err(|_| Error::BodyUnreadable)?.into_data() {
+                if bytes.len() + data.len() > limits.max_size {
+                    return Err(Error::BodyTooLarge);
+                }
+                bytes.extend_from_slice(&data);
+            }
+        }
+        Ok(bytes)
+    };
+    let bytes = tokio::time::timeout(limits.max_time, read).await.map_err(|_| Error::BodyTimeout)??;
+    Ok(next.run(Request::from_parts(parts, Body::from(bytes))).await)
+}
+
+// a panicking handler is answered with 500 INTERNAL SERVER ERROR instead of dropping the connection
+fn handle_panic(err: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let message = err.downcast_ref::<String>().map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    tracing::error!("a handler panicked: {}", message);
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error.").into_response()
+}
+
+fn create_app_with_config(connection: Connection, config: &Config) -> Router {
+    layer_routes(app_routes(config), connection, config)
+}
+
+// the routes of the app, without the layers shared by all of them (see layer_routes)
+fn app_routes(config: &Config) -> Router<Connection> {
+    // requests to endpoints taking a body are rejected with 413 PAYLOAD TOO LARGE beyond the limit
+    let body_limit = DefaultBodyLimit::max(config.max_body_size);
+    // read-only replicas serve the same routes, but refuse to update the database
+    let (mutations, users) = match config.mode {
+        Mode::Writable => (put(set_balance).delete(remove_user).layer(body_limit), get(get_users).post(insert_users).layer(body_limit)),
+        Mode::ReadOnly => (any(read_only), get(get_users).post(read_only))
+    };
+    // building trees takes memory and CPU on behalf of any client, so only writable servers offer it
+    let build = match config.mode {
+        Mode::Writable => post(build_tree).layer(body_limit),
+        Mode::ReadOnly => any(read_only)
+    };
+    let router = if config.transparency { Router::new().route("/transparency", get(get_transparency)) } else { Router::new() };
+    let router = match &config.signing_key {
+        Some(key) => router.route("/root/signed", get(get_signed_root)).layer(Extension(Arc::new(key.clone()))),
+        None => router
+    };
+    router
+        .route("/root", get(get_root))
+        .route("/params", get(get_params))
+        .route("/stats", get(get_stats))
+        .route("/aggregate-root", get(get_aggregate_root))
+        .route("/proof/{id}", get(get_proof))
+        .route("/proof/{id}/verify", get(verify_proof))
+        .route("/proof/by-hash/{leaf_hash}", get(get_proof_by_hash))
+        .route("/proofs", get(get_proofs))
+        .route("/users", users)
+        .route("/users/{id}", mutations)
+        .route("/verify", post(verify_submitted_proof).layer(body_limit))
+        .route("/verify.bin", post(verify_compact_proof).layer(body_limit))
+        .route("/audit/check-total", post(check_total).layer(body_limit))
+        .route("/build", build)
+        .route("/build/{id}/proof/{index}", get(get_tree_proof))
+}
+
+// wraps every route in the shared state and middleware, which only apply to the routes added before them
+fn layer_routes(router: Router<Connection>, connection: Connection, config: &Config) -> Router {
+    router
+        .layer(Extension(Arc::new(Mutex::new(IdempotencyKeys::default()))))
+        .layer(Extension(Arc::new(Mutex::new(EphemeralTrees::default()))))
+        .layer(Extension(Arc::new(Semaphore::new(BATCH_WORKERS))))
+        .layer(Extension(config.audit_log.clone()))
+        .layer(middleware::from_fn_with_state(BodyLimits { max_size: config.max_body_size, max_time: config.max_body_time }, read_body_in_time))
+        .layer(CatchPanicLayer::custom(handle_panic))
+        .layer(middleware::from_fn_with_state(connection.clone(), merkle_headers))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(connection)
+}
+
+const TEST_DATA: [(u64, u64); 8] = [(1, 1111), (2, 2222), (3, 3333), (4, 4444), (5, 5555), (6, 6666), (7, 7777), (8, 8888)];
+const LEAF_TAG: &[u8; 19] = b"ProofOfReserve_Leaf";
+const BRANCH_TAG: &[u8; 21] = b"ProofOfReserve_Branch";
+
+fn create_test_db() -> InMemoryDatabase<32, Sha256Algorithm> {
+    InMemoryDatabase::create(TEST_DATA.to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())
+}
+
+fn create_typed_test_db(leaf_type: u8) -> InMemoryDatabase<32, Sha256Algorithm> {
+    DatabaseBuilder::default().leaf_tag(LEAF_TAG.to_vec()).branch_tag(BRANCH_TAG.to_vec()).leaf_type(leaf_type).build(TEST_DATA.to_vec())
+}
+
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_BODY_TIME: Duration = Duration::from_secs(30);
+
+// whether the database can be updated through the API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Mode {
+    #[default]
+    ReadOnly,
+    Writable
+}
+
+#[derive(Debug)]
+struct Config {
+    snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    max_body_size: usize,
+    max_body_time: Duration,
+    worker_threads: usize,
+    mode: Mode,
+    transparency: bool,
+    signing_key: Option<SigningKey>,
+    audit_log: Option<Arc<AuditLog>>,
+    leaf_type: Option<u8>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        // one worker thread per CPU, as tokio does by default
+        let worker_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Config { snapshot: None, save_snapshot: None, max_body_size: DEFAULT_MAX_BODY_SIZE, max_body_time: DEFAULT_MAX_BODY_TIME, worker_threads, mode: Mode::default(), transparency: false, signing_key: None, audit_log: None, leaf_type: None }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" => {
+                config.snapshot = Some(args.next().ok_or("--snapshot requires a path")?);
+            }
+            "--save-snapshot" => {
+                config.save_snapshot = Some(args.next().ok_or("--save-snapshot requires a path")?);
+            }
+            "--max-body-size" => {
+                let size = args.next().ok_or("--max-body-size requires a number of bytes")?;
+                config.max_body_size = size.parse().map_err(|_| format!("Invalid body size: {}", size))?;
+            }
+            "--max-body-time" => {
+                let seconds = args.next().ok_or("--max-body-time requires a number of seconds")?;
+                config.max_body_time = match seconds.parse() {
+                    Ok(0) | Err(_) => return Err(format!("Invalid body time: {}", seconds)),
+                    Ok(seconds) => Duration::from_secs(seconds)
+                };
+            }
+            "--worker-threads" => {
+                let threads = args.next().ok_or("--worker-threads requires a number of threads")?;
+                config.worker_threads = match threads.parse() {
+                    Ok(0) | Err(_) => return Err(format!("Invalid number of worker threads: {}", threads)),
+                    Ok(threads) => threads
+                };
+            }
+            "--read-only" => config.mode = Mode::ReadOnly,
+            "--writable" => config.mode = Mode::Writable,
+            "--transparency" => config.transparency = true,
+            "--signing-key" => {
+                let path = args.next().ok_or("--signing-key requires a path")?;
+                config.signing_key = Some(load_signing_key(&path)?);
+            }
+            "--audit-log" => {
+                let path = args.next().ok_or("--audit-log requires a path")?;
+                let audit_log = AuditLog::open(&path).map_err(|err| format!("Failed to open audit log {}: {}", path, err))?;
+                config.audit_log = Some(Arc::new(audit_log));
+            }
+            "--leaf-type" => {
+                let leaf_type = args.next().ok_or("--leaf-type requires a type byte")?;
+                config.leaf_type = Some(leaf_type.parse().map_err(|_| format!("Invalid leaf type: {}", leaf_type))?);
+            }
+            _ => return Err(format!("Unknown argument: {}", arg))
+        }
+    }
+    Ok(config)
+}
+
+// the key is stored as the hex-encoded 32-byte Ed25519 secret key, optionally followed by a newline
+fn load_signing_key(path: &str) -> Result<SigningKey, String> {
+    let contents = std::fs::read_to_string(path).map_err(|err| format!("Failed to read signing key {}: {}", path, err))?;
+    let bytes: [u8; 32] = HEXLOWER.decode(contents.trim().as_bytes()).ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("Invalid signing key {}: expected 32 hex-encoded bytes", path))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn create_db(config: &Config) -> OnlineDatabase<InMemoryDatabase<32, Sha256Algorithm>> {
+    match &config.snapshot {
+        Some(path) => {
+            tracing::info!("Loading the database from snapshot {}...", path);
+            let db = OnlineDatabase::load_snapshot(path).unwrap_or_else(|err| panic!("Failed to load snapshot {}: {}", path, err));
+            // the snapshot records its leaf type, which a different --leaf-type cannot override without changing the root
+            let leaf_type = db.snapshot().leaf_type();
+            if config.leaf_type.is_some() && config.leaf_type != leaf_type {
+                panic!("Snapshot {} has leaf type {:?}, not {:?}", path, leaf_type, config.leaf_type);
+            }
+            db
+        }
+        None => OnlineDatabase::new(config.leaf_type.map_or_else(create_test_db, create_typed_test_db))
+    }
+}
+
+fn build_runtime(config: &Config) -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.worker_threads)
+        .enable_all()
+        .build()
+}
+
+// RUST_LOG holds the directives of the log filter, or the level is info if it is not set
+fn log_filter() -> EnvFilter {
+    match std::env::var_os(EnvFilter::DEFAULT_ENV) {
+        Some(_) => EnvFilter::try_from_default_env().unwrap_or_else(|err| panic!("Invalid RUST_LOG: {}", err)),
+        None => EnvFilter::new("info")
+    }
+}
+
+fn main() {
+    tracing_subscriber::fmt().with_writer(std::io::stderr).with_env_filter(log_filter()).init();
+    let config = parse_args(std::env::args().skip(1)).unwrap_or_else(|err| panic!("{}", err));
+    let runtime = build_runtime(&config).unwrap_or_else(|err| panic!("Failed to start the runtime: {}", err));
+    runtime.block_on(serve(config));
+}
+
+async fn serve(config: Config) {
+    tracing::info!("Testing the Merkle root implementation...");
+    test_merkle_root();
+    
+    let bind_address = "0.0.0.0:3000";
+    let db = create_db(&config);
+    if let Some(path) = &config.save_snapshot {
+        tracing::info!("Saving the database to snapshot {}...", path);
+        db.save_snapshot(path).unwrap_or_else(|err| panic!("Failed to save snapshot {}: {}", path, err));
+    }
+    let connection = Arc::new(db);
+    let app = create_app_with_config(connection, &config);
+    let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
+
+    tracing::info!("Starting the server at {}...", bind_address);
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::Request, http, body::Body};
+    use merkle::{AuditPath, DoubleSha256Algorithm, verify_root, verify_stream};
+    use code_test::mmr::MmrTree;
+    use code_test::flat::FlatMerkleTree;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+    use http_body_util::BodyExt;
+
+    fn create_app(connection: Connection) -> Router {
+        create_app_with_config(connection, &Config::default())
+    }
+
+    #[test]
+    fn test_tagged_hash_repeated() {
+        /* expected values computed independently as SHA256(SHA256(tag) || SHA256(tag) || data) */
+        let vectors: [(&[u8], &[u8], &str); 3] = [
+            (b"BIP0340/challenge", b"", "c216d352f5818b7b4beacd4ae0a26fe888080823d2a598856661bcd54f1b3713"),
+            (b"ProofOfReserve_Leaf", b"(1,1111)", "58d090c69f3e3ec6592858d6e6e37864a687fd8a29bc2c4a44f9abdadd5d4d55"),
+            (b"TapLeaf", &[0; 64], "dfb0b0f1c82903d7e9d05ec58b8054cfc45553e4791e365cbd7d3fca8ce881b1"),
+        ];
+        /* interleave the vectors many times, so any state leaking between calls would show up */
+        for _ in 0..1000 {
+            for (tag, data, expected) in vectors {
+                assert_eq!(HEXLOWER.encode(&Sha256Algorithm::tagged_hash(tag, data)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        let root = tree.get_root();
+        let root_hex = HEXLOWER.encode(&root.0);
+        assert_eq!(root_hex, "4aa906745f72053498ecc74f79813370a4fe04f85e09421df2d5ef760dfa94b5");
+    }
+
+    /*
+     * The smallest tree with a pairing and no padding. The expected hashes were computed independently of the crate,
+     * with t(data) = SHA256(SHA256(tag) || SHA256(tag) || data) and tag = "Bitcoin_Transaction" (e.g. in Python,
+     * with hashlib):
+     *
+     *     a = t("aaa")  = d2d838724571ff750eb7f498a667c32f522efae2b403eae6f678207ac6f978de
+     *     b = t("bbb")  = 7cdf701413062eaba020af83441a6762ee2910e36b1805bad072103b0257f441
+     *     root = t(a || b) = 631bae42ba587408a741fa7d482a955d059caa471c5d66548d44a6ed234e782c
+     */
+    #[test]
+    fn test_merkle_root_two_leaves() {
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(vec![b"aaa".to_vec(), b"bbb".to_vec()], tag.clone(), tag);
+        let leaves: Vec<String> = tree.layers()[0].iter().map(|hash| HEXLOWER.encode(hash)).collect();
+        assert_eq!(leaves, [
+            "d2d838724571ff750eb7f498a667c32f522efae2b403eae6f678207ac6f978de",
+            "7cdf701413062eaba020af83441a6762ee2910e36b1805bad072103b0257f441"
+        ]);
+        assert_eq!(tree.layers().len(), 2);
+        assert_eq!(HEXLOWER.encode(&tree.get_root().0), "631bae42ba587408a741fa7d482a955d059caa471c5d66548d44a6ed234e782c");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_merkle_build_parallel() {
+        let values: Vec<Vec<u8>> = (0..100_000u64).map(|i| db::serialize_user(i, i * 3)).collect();
+        let sequential = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let parallel = MerkleTree::<32, Sha256Algorithm>::build_parallel(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(parallel.get_root().0, sequential.get_root().0);
+        assert_eq!(parallel.layers(), sequential.layers());
+        /* small trees are built sequentially, with the same result */
+        let parallel = MerkleTree::<32, Sha256Algorithm>::build_parallel(values[..5].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(parallel.get_root().0, MerkleTree::<32, Sha256Algorithm>::build(values[..5].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()).get_root().0);
+    }
+
+    #[test]
+    fn test_prepared_tag() {
+        use code_test::DigestAlgorithm;
+        use sha2::Sha512;
+        let data: [&[u8]; 3] = [b"", b"aaa", &[7; 200]];
+        for value in data {
+            assert_eq!(Sha256Algorithm::prepare_tag(LEAF_TAG).hash(value), Sha256Algorithm::tagged_hash(LEAF_TAG, value));
+            assert_eq!(<DigestAlgorithm<Sha512> as HashAlgorithm<64>>::prepare_tag(LEAF_TAG).hash(value), <DigestAlgorithm<Sha512> as HashAlgorithm<64>>::tagged_hash(LEAF_TAG, value));
+            assert_eq!(DoubleSha256Algorithm::prepare_tag(LEAF_TAG).hash(value), DoubleSha256Algorithm::tagged_hash(LEAF_TAG, value));
+        }
+        let (left, right) = ([1; 32], [2; 32]);
+        assert_eq!(Sha256Algorithm::prepare_tag(BRANCH_TAG).hash_pair(&left, &right), Sha256Algorithm::hash_pair(BRANCH_TAG, &left, &right));
+        /* trees built with the prepared tags have the roots that proofs folded with tagged_hash reach */
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6, 7]);
+        for padding in [Padding::Duplicate, Padding::Promote] {
+            let tree = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding);
+            for (index, value) in values.iter().enumerate() {
+                let proof = tree.get_proof_at(index).unwrap();
+                assert!(merkle::verify_root::<32, Sha256Algorithm>(value, &proof, index, 7, &tree.get_root(), LEAF_TAG, BRANCH_TAG, padding));
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_root_ref() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(*tree.root_ref(), tree.get_root().0);
+        /* the reference points into the top layer rather than to a copy */
+        assert!(std::ptr::eq(tree.root_ref(), &tree.layers().last().unwrap()[0]));
+    }
+
+    #[test]
+    fn test_merkle_root_hash_set() {
+        let tree = |values: &[&[u8]]| MerkleTree::<32, Sha256Algorithm>::build(
+            values.iter().map(|value| value.to_vec()).collect(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let roots: std::collections::HashSet<MerkleRoot<32>> = [
+            tree(&[b"aaa", b"bbb"]).get_root(),
+            tree(&[b"aaa", b"bbb"]).get_root(),
+            tree(&[b"aaa", b"ccc"]).get_root()
+        ].into_iter().collect();
+        assert_eq!(roots.len(), 2);
+        assert!(roots.contains(&tree(&[b"aaa", b"ccc"]).get_root()));
+    }
+
+    #[test]
+    fn test_merkle_root_diff_bits() {
+        let root = create_test_db().get_root().unwrap();
+        assert_eq!(root.diff_bits(&create_test_db().get_root().unwrap()), 0);
+        let mut flipped = MerkleRoot(root.0);
+        flipped.0[7] ^= 0b1011_0000;
+        assert_eq!(root.diff_bits(&flipped), 3);
+        assert_eq!(flipped.diff_bits(&root), 3);
+        assert_eq!(MerkleRoot([0x00; 32]).diff_bits(&MerkleRoot([0xff; 32])), 256);
+    }
+
+    #[test]
+    fn test_merkle_tree_builder() {
+        /* documents are pushed one at a time, each dropped once it is hashed */
+        let documents = || (0..5u8).map(|i| vec![i; 4096]);
+        for (padding, sorted_pairs) in [(Padding::Duplicate, false), (Padding::Promote, false), (Padding::Duplicate, true)] {
+            let mut builder = MerkleTreeBuilder::<32, Sha256Algorithm>::new(LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())
+                .padding(padding)
+                .sorted_pairs(sorted_pairs);
+            for (i, document) in documents().enumerate() {
+                assert_eq!(builder.push_leaf(&document), i);
+            }
+            assert_eq!(builder.num_leaves(), 5);
+            let tree = builder.finish();
+            let full_tree = MerkleTree::<32, Sha256Algorithm>::build_with_options(documents().collect(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding, sorted_pairs);
+            assert_eq!(tree.get_root(), full_tree.get_root());
+            assert_eq!(tree.layers(), full_tree.layers());
+            assert!(tree.verify(&[3; 4096], &tree.get_proof(vec![3; 4096]).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_merkle_prune() {
+        let values = user_leaves(&(1..=7).collect::<Vec<_>>());
+        let hashes = |tree: &MerkleTree<32, Sha256Algorithm>| tree.layers().iter().map(Vec::len).sum::<usize>();
+        for padding in [Padding::Duplicate, Padding::Promote] {
+            let full = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding);
+            let mut tree = full.clone();
+            tree.prune();
+            assert!(tree.is_pruned());
+            /* only the 7 leaves and the root are left */
+            assert_eq!(hashes(&tree), 8);
+            assert!(hashes(&tree) < hashes(&full));
+            assert_eq!(tree.depth(), full.depth());
+            assert_eq!(tree.get_root(), full.get_root());
+            /* the branch layers are rehashed to produce the same proofs */
+            for (index, value) in values.iter().enumerate() {
+                let proof = tree.get_proof(value.clone()).unwrap();
+                assert_eq!(proof, full.get_proof(value.clone()).unwrap());
+                assert!(tree.verify(value, &proof));
+                assert_eq!(tree.verify_with_tree(value, index, &proof), Ok(()));
+            }
+            assert_eq!(tree.get_multiproof(&values[2..5]), full.get_multiproof(&values[2..5]));
+            assert_eq!(tree.pretty_print(), full.pretty_print());
+            /* updating a leaf keeps the tree pruned, and appending restores the branch layers */
+            let mut full = full;
+            tree.update_leaf(3, b"(4,4)");
+            full.update_leaf(3, b"(4,4)");
+            assert!(tree.is_pruned());
+            assert_eq!(tree.get_root(), full.get_root());
+            tree.append(b"(8,8888)");
+            full.append(b"(8,8888)");
+            assert!(!tree.is_pruned());
+            assert_eq!(tree.layers(), full.layers());
+        }
+        /* there is nothing to prune below the root of a tree with one or two leaves */
+        let mut tree = MerkleTree::<32, Sha256Algorithm>::build(values[..2].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        tree.prune();
+        assert!(!tree.is_pruned());
+    }
+
+    #[test]
+    fn test_merkle_root_from_slices() {
+        let buffer = b"aaabbbcccdddeee";
+        let slices: Vec<&[u8]> = buffer.chunks(3).collect();
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_from_slices(&slices, tag.clone(), tag.clone());
+        let owned_values = slices.iter().map(|slice| slice.to_vec()).collect();
+        let owned_tree = MerkleTree::<32, Sha256Algorithm>::build(owned_values, tag.clone(), tag.clone());
+        assert_eq!(tree.get_root().0, owned_tree.get_root().0);
+        assert_eq!(HEXLOWER.encode(&tree.get_root().0), "4aa906745f72053498ecc74f79813370a4fe04f85e09421df2d5ef760dfa94b5");
+    }
+
+    fn decode_hashes(hashes: &[&str]) -> Vec<[u8; 32]> {
+        hashes.iter().map(|hash| HEXLOWER.decode(hash.as_bytes()).unwrap().try_into().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_bitcoin_merkle_root() {
+        /* transactions of block 100000 and the Merkle root from its header, in display byte order */
+        let txids = decode_hashes(&[
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]);
+        let tree = MerkleTree::<32, DoubleSha256Algorithm>::build_bitcoin(&txids);
+        assert_eq!(HEXLOWER.encode(&tree.get_bitcoin_root()), "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766");
+
+        /* block 170, the first block with a transaction other than the coinbase */
+        let txids = decode_hashes(&[
+            "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082",
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+        ]);
+        let tree = MerkleTree::<32, DoubleSha256Algorithm>::build_bitcoin(&txids);
+        assert_eq!(HEXLOWER.encode(&tree.get_bitcoin_root()), "7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff");
+    }
+
+    #[test]
+    fn test_merkle_rebuild_in_place() {
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let mut tree = MerkleTree::<32, Sha256Algorithm>::with_capacity(8, tag.clone(), tag.clone());
+        /* until it is filled, the tree is the same as one built from no values */
+        let empty = MerkleTree::<32, Sha256Algorithm>::build(Vec::new(), tag.clone(), tag.clone());
+        for tree in [&tree, &empty] {
+            assert_eq!((tree.num_leaves(), tree.depth(), tree.layers().iter().map(Vec::len).collect::<Vec<_>>()), (0, 1, vec![0]));
+        }
+        assert!(tree.layers()[0].capacity() >= 8);
+        /* shrinking, growing and keeping the same size all match a fresh build */
+        for num_values in [8, 5, 8, 9, 1, 2] {
+            let values: Vec<Vec<u8>> = (0..num_values).map(|i| format!("value {}", i).into_bytes()).collect();
+            tree.rebuild_in_place(values.clone());
+            let fresh = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), tag.clone(), tag.clone());
+            assert_eq!(tree.get_root().0, fresh.get_root().0);
+            assert_eq!(tree.get_proof(values[0].clone()), fresh.get_proof(values[0].clone()));
+        }
+    }
+
+    #[test]
+    fn test_empty_tree_root() {
+        let tag = LEAF_TAG.to_vec();
+        let mut tree = MerkleTree::<32, Sha256Algorithm>::with_capacity(8, tag.clone(), tag.clone());
+        let flat = FlatMerkleTree::<32, Sha256Algorithm>::build(Vec::new(), tag.clone(), tag.clone());
+        let mut mmr = MmrTree::<32, Sha256Algorithm>::new(tag.clone(), tag.clone());
+        /* empty trees have no root: the checked accessors return None, and the others panic */
+        assert_eq!(MerkleTree::<32, Sha256Algorithm>::build(Vec::new(), tag.clone(), tag.clone()).try_get_root(), None);
+        assert_eq!((tree.try_get_root(), flat.try_get_root(), mmr.try_get_root()), (None, None, None));
+        assert!(std::panic::catch_unwind(|| tree.get_root()).is_err());
+        assert!(std::panic::catch_unwind(|| *tree.root_ref()).is_err());
+        assert!(std::panic::catch_unwind(|| flat.get_root()).is_err());
+        assert!(std::panic::catch_unwind(|| mmr.get_root()).is_err());
+        /* once filled, they agree with the unchecked ones */
+        tree.rebuild_in_place(vec![b"aaa".to_vec()]);
+        mmr.append(b"aaa");
+        assert_eq!(tree.try_get_root(), Some(tree.get_root()));
+        assert_eq!(mmr.try_get_root(), Some(tree.get_root()));
+        let flat = FlatMerkleTree::<32, Sha256Algorithm>::build(vec![b"aaa".to_vec()], tag.clone(), tag);
+        assert_eq!(flat.try_get_root(), Some(tree.get_root()));
+    }
+
+    #[test]
+    fn test_merkle_layers() {
+        let values = user_leaves(&[1, 2, 3, 4, 5]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let layers = tree.layers();
+        assert_eq!(layers.len(), tree.depth());
+        assert_eq!(layers.iter().map(Vec::len).collect::<Vec<_>>(), vec![5, 3, 2, 1]);
+        assert_eq!(layers[0][1], Sha256Algorithm::tagged_hash(LEAF_TAG, &values[1]));
+        assert_eq!(layers.last().unwrap()[0], tree.get_root().0);
+    }
+
+    #[test]
+    fn test_aggregate_roots() {
+        let pool_roots = [create_test_db().get_root().unwrap(), MerkleRoot(Sha256Algorithm::tagged_hash(LEAF_TAG, b"other pool"))];
+        let aggregate = merkle::aggregate_roots::<32, Sha256Algorithm>(&pool_roots, AGGREGATE_TAG);
+        /* the pool roots are the leaves as they are, so the aggregate of two pools is a single branch hash (computed independently) */
+        assert_eq!(aggregate.0, Sha256Algorithm::hash_pair(AGGREGATE_TAG, &pool_roots[0].0, &pool_roots[1].0));
+        assert_eq!(HEXLOWER.encode(&aggregate.0), "f6bc6cb1d03302d98a31327e5913acf3a2752f9e9a267925df4a9767201aeb19");
+        /* a single pool is its own aggregate */
+        assert_eq!(merkle::aggregate_roots::<32, Sha256Algorithm>(&pool_roots[..1], AGGREGATE_TAG).0, pool_roots[0].0);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_root_api() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/aggregate-root")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        let root = "0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a";
+        assert_eq!(body_json, json!({ "root": root, "pool_roots": [root] }));
+    }
+
+    #[test]
+    fn test_digest_algorithm() {
+        use merkle::DigestAlgorithm;
+        use sha2::{Sha256, Sha512};
+        /* with SHA256, the wrapper matches Sha256Algorithm */
+        let tree = MerkleTree::<32, DigestAlgorithm<Sha256>>::build(user_leaves(&[1, 2, 3, 4, 5]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let expected = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2, 3, 4, 5]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(tree.get_root().0, expected.get_root().0);
+        let db: InMemoryDatabase<32, DigestAlgorithm<Sha256>> = InMemoryDatabase::create(TEST_DATA.to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), create_test_db().get_root());
+
+        /* SHA512 gives 64-byte hashes (expected value computed independently) */
+        assert_eq!(HEXLOWER.encode(&<DigestAlgorithm<Sha512> as HashAlgorithm<64>>::tagged_hash(LEAF_TAG, b"(1,1111)")),
+            "d550e0814c44b56069883fa8680070b347fbfaf093aecbb78ebf168a5accecc747cca71c4ad8ff197203e2bf83f0885f52566ecbd91a43ca7c0bead490fd0908");
+        let values = user_leaves(&[1, 2, 3, 4, 5]);
+        let tree = MerkleTree::<64, DigestAlgorithm<Sha512>>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        for value in &values {
+            assert!(tree.verify(value, &tree.get_proof(value.clone()).unwrap()));
+        }
+        assert_eq!(<DigestAlgorithm<Sha512> as HashAlgorithm<64>>::NAME, "sha512");
+    }
+
+    #[test]
+    fn test_merkle_pretty_print() {
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2, 3, 4, 5]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let output = tree.pretty_print();
+        assert!(output.starts_with(&format!("layer 3 (root):\n  0x{}\n", HEXLOWER.encode(&tree.get_root().0))));
+        assert_eq!(output.lines().filter(|line| line.starts_with("layer ")).count(), tree.depth());
+        /* 5 + 3 + 2 + 1 hashes */
+        assert_eq!(output.lines().filter(|line| line.starts_with("  0x")).count(), 11);
+        assert!(output.contains("layer 0 (leaves):"));
+    }
+
+    #[test]
+    fn test_hash_algorithm_names() {
+        assert_eq!(Sha256Algorithm::NAME, "sha256");
+        assert_eq!(DoubleSha256Algorithm::NAME, "sha256d");
+    }
+
+    #[test]
+    fn test_merkle_proof_verify_sorted() {
+        /* 4 leaves "aaa".."ddd", where each parent hashes its two children in increasing order (computed independently) */
+        let hashes = decode_hashes(&[
+            "e84ccd60e6bfcf7378db4695962013fa22b258d2b344dd63be31cf9a079b89c1", // leaf "ccc"
+            "c2256c547135587d0cbecc0f0586a8ffc6e0f64b58c7a69b35367d43c946e0bd", // leaf "ddd"
+            "f9c9a616e2f3321b33b835106ced749fce3fcd3b7d742eb1fa24a7c069b914be", // parent of "aaa" and "bbb"
+            "a2f72b35df2d98bffee64d29bca218b5ffc9886f8ca89afcf60e34f0c2a9487f", // root
+        ]);
+        let (leaf, sibling, uncle, root) = (hashes[0], hashes[1], hashes[2], MerkleRoot(hashes[3]));
+        assert_eq!(leaf, Sha256Algorithm::tagged_hash(LEAF_TAG, b"ccc"));
+
+        /* the directions are ignored */
+        let proof = MerkleProof(vec![MerkleProofItem::Right(sibling), MerkleProofItem::Left(uncle)]);
+        assert!(proof.verify_sorted::<Sha256Algorithm>(&leaf, &root, BRANCH_TAG));
+        let proof = MerkleProof(vec![MerkleProofItem::Left(sibling), MerkleProofItem::Right(uncle)]);
+        assert!(proof.verify_sorted::<Sha256Algorithm>(&leaf, &root, BRANCH_TAG));
+        /* but the order of the items is not */
+        let proof = MerkleProof(vec![MerkleProofItem::Left(uncle), MerkleProofItem::Right(sibling)]);
+        assert!(!proof.verify_sorted::<Sha256Algorithm>(&leaf, &root, BRANCH_TAG));
+        let proof = MerkleProof(vec![MerkleProofItem::Right(sibling), MerkleProofItem::Left(uncle)]);
+        assert!(!proof.verify_sorted::<Sha256Algorithm>(&Sha256Algorithm::tagged_hash(LEAF_TAG, b"ddd"), &root, BRANCH_TAG));
+        /* the sibling sorts before the leaf, so the positional fold gives a different root */
+        assert!(!merkle::verify_root::<32, Sha256Algorithm>(b"ccc", &proof, 2, 4, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+    }
+
+    // keccak256 of the tag followed by the data, which with empty tags hashes as OpenZeppelin does
+    struct Keccak256Algorithm;
+    impl HashAlgorithm<32> for Keccak256Algorithm {
+        const NAME: &'static str = "keccak256";
+        const OUTPUT_SIZE: usize = 32;
+        fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+            use sha3::{Digest, Keccak256};
+            Keccak256::new().chain_update(tag).chain_update(data).finalize().into()
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_verify_sorted_openzeppelin() {
+        /* the example of the @openzeppelin/merkle-tree README: StandardMerkleTree.of(values, ["address", "uint256"]),
+         * whose leaves are keccak256(keccak256(abi.encode(address, amount))) and whose pairs are sorted */
+        let values = [([0x11; 20], 5_000_000_000_000_000_000u128), ([0x22; 20], 2_500_000_000_000_000_000)].map(|(address, amount)| {
+            let mut encoded = [0; 64];
+            encoded[12..32].copy_from_slice(&address);
+            encoded[48..].copy_from_slice(&amount.to_be_bytes());
+            Keccak256Algorithm::tagged_hash(b"", &encoded).to_vec()
+        });
+        let root = MerkleRoot(decode_hashes(&["d4dee0beab2d53f2cc83e567171bd2820e49898130a22622b10ead383e90bd77"])[0]);
+        let leaves = values.clone().map(|value| Keccak256Algorithm::tagged_hash(b"", &value));
+        /* the proof of each leaf is the other leaf, whatever its direction */
+        for (leaf, sibling) in [(leaves[0], leaves[1]), (leaves[1], leaves[0])] {
+            assert!(MerkleProof(vec![MerkleProofItem::Left(sibling)]).verify_sorted::<Keccak256Algorithm>(&leaf, &root, b""));
+            assert!(MerkleProof(vec![MerkleProofItem::Right(sibling)]).verify_sorted::<Keccak256Algorithm>(&leaf, &root, b""));
+        }
+        assert!(!MerkleProof(vec![MerkleProofItem::Left(leaves[0])]).verify_sorted::<Keccak256Algorithm>(&leaves[0], &root, b""));
+        /* and a tree with sorted pairs has the same root, in either order of the leaves */
+        for values in [values.to_vec(), values.iter().rev().cloned().collect()] {
+            let tree = MerkleTree::<32, Keccak256Algorithm>::build_with_options(values, Vec::new(), Vec::new(), Padding::Duplicate, true);
+            assert_eq!(tree.get_root().0, root.0);
+        }
+    }
+
+    #[test]
+    fn test_merkle_get_all_proofs() {
+        let values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"bbb".to_vec()];
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proofs = tree.get_all_proofs(b"bbb");
+        assert_eq!(proofs.len(), 2);
+        assert_ne!(proofs[0], proofs[1]);
+        assert_eq!(proofs[0], tree.get_proof(b"bbb".to_vec()).unwrap());
+        for (proof, index) in proofs.iter().zip([1, 4]) {
+            assert!(merkle::verify_root::<32, Sha256Algorithm>(b"bbb", proof, index, 5, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        }
+        assert_eq!(tree.get_all_proofs(b"ccc").len(), 1);
+        assert!(tree.get_all_proofs(b"eee").is_empty());
+    }
+
+    #[test]
+    fn test_verify_root_prepared_tag() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        /* SHA256 with BIP340 tags, counting how many times a tag is hashed */
+        static TAG_HASHES: AtomicUsize = AtomicUsize::new(0);
+        struct CountingSha256;
+        impl HashAlgorithm<32> for CountingSha256 {
+            const NAME: &'static str = "sha256";
+            const OUTPUT_SIZE: usize = 32;
+            fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+                TAG_HASHES.fetch_add(1, Ordering::SeqCst);
+                Sha256Algorithm::tagged_hash(tag, data)
+            }
+            fn prepare_tag(tag: &[u8]) -> merkle::PreparedTag<32> {
+                TAG_HASHES.fetch_add(1, Ordering::SeqCst);
+                Sha256Algorithm::prepare_tag(tag)
+            }
+        }
+        let values = user_leaves(&(1..=1000).collect::<Vec<_>>());
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let root = tree.get_root();
+        for index in [0, 517, 999] {
+            let proof = tree.get_proof_at(index).unwrap();
+            for (value, index) in [(&values[index], index), (&values[index], index ^ 1), (&values[1], index)] {
+                let expected = verify_root::<32, Sha256Algorithm>(value, &proof, index, 1000, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate);
+                TAG_HASHES.store(0, Ordering::SeqCst);
+                assert_eq!(verify_root::<32, CountingSha256>(value, &proof, index, 1000, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate), expected);
+                /* the leaf tag and the branch tag are each hashed once, however deep the proof */
+                assert_eq!(TAG_HASHES.load(Ordering::SeqCst), 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmr_prepared_tag() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        /* SHA256 with BIP340 tags, counting how many times a tag is hashed */
+        static TAG_HASHES: AtomicUsize = AtomicUsize::new(0);
+        struct CountingSha256;
+        impl HashAlgorithm<32> for CountingSha256 {
+            const NAME: &'static str = "sha256";
+            const OUTPUT_SIZE: usize = 32;
+            fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32] {
+                TAG_HASHES.fetch_add(1, Ordering::SeqCst);
+                Sha256Algorithm::tagged_hash(tag, data)
+            }
+            fn prepare_tag(tag: &[u8]) -> merkle::PreparedTag<32> {
+                TAG_HASHES.fetch_add(1, Ordering::SeqCst);
+                Sha256Algorithm::prepare_tag(tag)
+            }
+        }
+        let values = user_leaves(&(1..=1000).collect::<Vec<_>>());
+        let expected = MmrTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let mut tree = MmrTree::<32, CountingSha256>::build(values[..999].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        /* the 1000th leaf merges three mountains, but each tag is hashed once */
+        TAG_HASHES.store(0, Ordering::SeqCst);
+        tree.append(&values[999]);
+        assert_eq!(TAG_HASHES.load(Ordering::SeqCst), 2);
+        TAG_HASHES.store(0, Ordering::SeqCst);
+        tree.update_leaf(0, &values[0]);
+        /* the leaf tag and the branch tag are each hashed once, however deep the leaf */
+        assert_eq!(TAG_HASHES.load(Ordering::SeqCst), 2);
+        TAG_HASHES.store(0, Ordering::SeqCst);
+        assert_eq!(tree.get_root(), expected.get_root());
+        /* bagging the peaks hashes the branch tag once, however many peaks there are */
+        assert_eq!(TAG_HASHES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_merkle_get_proof_nth() {
+        let values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"bbb".to_vec(), b"ddd".to_vec()];
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let root = tree.get_root();
+        let first = tree.get_proof_nth(b"bbb", 0).unwrap();
+        let second = tree.get_proof_nth(b"bbb", 1).unwrap();
+        assert_eq!(Some(&first), tree.get_proof(b"bbb".to_vec()).as_ref());
+        assert_ne!(first, second);
+        assert!(merkle::verify_root::<32, Sha256Algorithm>(b"bbb", &first, 1, 5, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        assert!(merkle::verify_root::<32, Sha256Algorithm>(b"bbb", &second, 3, 5, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        assert_eq!(tree.get_proof_nth(b"bbb", 2), None);
+        assert_eq!(tree.get_proof_nth(b"eee", 0), None);
+    }
+
+    #[test]
+    fn test_merkle_build_strict() {
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_strict(user_leaves(&[1, 2, 3, 4, 5, 6, 7, 8]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()).unwrap();
+        for value in user_leaves(&[1, 2, 3, 4, 5, 6, 7, 8]) {
+            assert_eq!(tree.get_proof(value).unwrap().0.len(), 3);
+        }
+        let result = MerkleTree::<32, Sha256Algorithm>::build_strict(user_leaves(&[1, 2, 3, 4, 5]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(result.unwrap_err(), MerkleError::NotPowerOfTwo(5));
+        let result = MerkleTree::<32, Sha256Algorithm>::build_strict(Vec::new(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(result.unwrap_err(), MerkleError::NotPowerOfTwo(0));
+    }
+
+    #[test]
+    fn test_merkle_padding_strategies() {
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6]);
+        let duplicated = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Duplicate);
+        let promoted = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote);
+        assert_eq!(duplicated.get_root().0, MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()).get_root().0);
+        assert_ne!(duplicated.get_root().0, promoted.get_root().0);
+
+        /* with 6 leaves, leaves 4 and 5 form a lone node in the second layer, so their proofs only verify with the matching strategy,
+         * while the proofs of the other leaves never pass through a lone node */
+        for (index, value) in values.iter().enumerate() {
+            for (tree, other) in [(&duplicated, Padding::Promote), (&promoted, Padding::Duplicate)] {
+                let proof = tree.get_proof(value.clone()).unwrap();
+                assert!(tree.verify(value, &proof));
+                assert!(merkle::verify_root::<32, Sha256Algorithm>(value, &proof, index, 6, &tree.get_root(), LEAF_TAG, BRANCH_TAG, tree.padding()));
+                assert_eq!(merkle::verify_root::<32, Sha256Algorithm>(value, &proof, index, 6, &tree.get_root(), LEAF_TAG, BRANCH_TAG, other), index < 4);
+            }
+        }
+
+        /* a promoted lone node is the same as its child, so the root of 3 leaves is H(H(a || b) || c) */
+        let hashes: Vec<[u8; 32]> = values[..3].iter().map(|value| Sha256Algorithm::tagged_hash(LEAF_TAG, value)).collect();
+        let left = Sha256Algorithm::tagged_hash(BRANCH_TAG, &[hashes[0], hashes[1]].concat());
+        let expected = Sha256Algorithm::tagged_hash(BRANCH_TAG, &[left, hashes[2]].concat());
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values[..3].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote);
+        assert_eq!(tree.get_root().0, expected);
+
+        /* updates and rebuilds keep the strategy */
+        let mut tree = promoted.clone();
+        tree.update_leaf(5, &values[0]);
+        let mut updated = values.clone();
+        updated[5] = values[0].clone();
+        assert_eq!(tree.get_root().0, MerkleTree::<32, Sha256Algorithm>::build_with_padding(updated.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote).get_root().0);
+        let mut tree = promoted.clone();
+        tree.rebuild_in_place(updated[..5].to_vec());
+        assert_eq!(tree.get_root().0, MerkleTree::<32, Sha256Algorithm>::build_with_padding(updated[..5].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote).get_root().0);
+
+        /* multiproofs fold lone nodes with the same strategy */
+        let proof = promoted.get_multiproof(&values[3..]).unwrap();
+        assert!(merkle::verify_multiproof::<32, Sha256Algorithm>(&values[3..], &proof, &promoted.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Promote));
+        assert!(!merkle::verify_multiproof::<32, Sha256Algorithm>(&values[3..], &proof, &promoted.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+    }
+
+    #[test]
+    fn test_merkle_proof_regular() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        match tree.get_proof(b"aaa".to_vec()) {
+            Some(MerkleProof(items)) => {
+                assert_eq!(items.len(), 3);
+                assert!(matches!(items[0], MerkleProofItem::Right(_)));
+                assert!(matches!(items[1], MerkleProofItem::Right(_)));
+                assert!(matches!(items[2], MerkleProofItem::Right(_)));
+            }
+            None => {
+                panic!("Anomaly! The proof of a leaf in the tree shouldn't be None.")
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_pad() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        match tree.get_proof(b"eee".to_vec()) {
+            Some(MerkleProof(items)) => {
+                assert_eq!(items.len(), 1);
+                assert!(matches!(items[0], MerkleProofItem::Left(_)));
+            }
+            None => {
+                panic!("Anomaly! The proof of a leaf in the tree shouldn't be None.")
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_explicit() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values.clone(), tag.clone(), tag.clone());
+        /* eee is lone on the two lowest layers, so it is paired with itself twice */
+        let proof = tree.get_proof_explicit(b"eee".to_vec()).unwrap();
+        assert_eq!(proof.0.len(), tree.depth() - 1);
+        assert_eq!(proof.0[0], MerkleProofItem::Right(Sha256Algorithm::tagged_hash(&tag, b"eee")));
+        assert!(matches!(proof.0[2], MerkleProofItem::Left(_)));
+        /* every explicit proof folds without skipping any level */
+        for value in &test_values {
+            let proof = tree.get_proof_explicit(value.clone()).unwrap();
+            assert_eq!(proof.0.len(), 3);
+            assert!(code_test::mmr::verify_root::<32, Sha256Algorithm>(value, &proof, &tree.get_root(), &tag, &tag));
+        }
+        assert!(tree.get_proof_explicit(b"ggg".to_vec()).is_none());
+        let promoted = MerkleTree::<32, Sha256Algorithm>::build_with_padding(test_values, tag.clone(), tag.clone(), Padding::Promote);
+        assert!(promoted.get_proof_explicit(b"eee".to_vec()).is_none());
+    }
+
+    #[test]
+    fn test_merkle_proof_implied_index() {
+        /* without lone nodes, the directions are the binary digits of the index */
+        let db = create_test_db();
+        for (user_id, _) in TEST_DATA {
+            let proof = db.get_proof(user_id).unwrap();
+            assert_eq!(proof.implied_index(), Some(user_id as usize - 1));
+            assert_eq!(proof.implied_index_in(8), Some(user_id as usize - 1));
+        }
+        /* with lone nodes, the size of the tree tells which levels were skipped */
+        for num_leaves in [3, 5, 6, 7, 11] {
+            let values = user_leaves(&(1..=num_leaves).collect::<Vec<_>>());
+            let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+            for (index, value) in values.iter().enumerate() {
+                let proof = tree.get_proof(value.clone()).unwrap();
+                assert_eq!(proof.implied_index_in(num_leaves as usize), Some(index), "{} of {}", index, num_leaves);
+                assert!(tree.verify_at_index(value, &proof, index));
+                assert!(!tree.verify_at_index(value, &proof, (index + 1) % num_leaves as usize));
+                assert_eq!(tree.get_proof_explicit(value.clone()).unwrap().implied_index(), Some(index));
+            }
+        }
+        /* 5 leaves: the last leaf skips the two lowest levels, so its proof is a single left item */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2, 3, 4, 5]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = tree.get_proof(user_leaves(&[5])[0].clone()).unwrap();
+        assert_eq!(proof.implied_index(), Some(1));
+        assert_eq!(proof.implied_index_in(5), Some(4));
+        /* no leaf of a tree with 4 leaves has a proof of a single item */
+        assert_eq!(proof.implied_index_in(4), None);
+        /* nor does any tree have a proof longer than an index has bits */
+        for len in [usize::BITS as usize, usize::BITS as usize + 1] {
+            let long = MerkleProof::<32>((0..len).map(|_| MerkleProofItem::Left([0; 32])).collect());
+            assert_eq!(long.implied_index(), (len == usize::BITS as usize).then_some(usize::MAX));
+            assert_eq!(long.implied_index_in(usize::MAX), None);
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_iter() {
+        let values = user_leaves(&[1, 2, 3, 4, 5]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = tree.get_proof(values[1].clone()).unwrap();
+        let mut count = 0;
+        for item in &proof {
+            assert_eq!(item, &proof.0[count]);
+            count += 1;
+        }
+        assert_eq!(count, proof.len());
+        assert_eq!(proof.len(), 3);
+        assert_eq!(proof.iter().filter(|item| matches!(item, MerkleProofItem::Right(_))).count(), 2);
+        /* the lone leaf skips the two layers where it has no sibling, and a single leaf has an empty proof */
+        assert_eq!((&tree.get_proof(values[4].clone()).unwrap()).into_iter().count(), 1);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values[..1].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert!(tree.get_proof(values[0].clone()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merkle_verify_with_tree() {
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = tree.get_proof(values[2].clone()).unwrap();
+        assert_eq!(tree.verify_with_tree(&values[2], 2, &proof), Ok(()));
+        /* a tampered middle sibling makes the parent it is hashed into diverge */
+        let mut tampered = proof.clone();
+        tampered.0[1] = MerkleProofItem::Left([0; 32]);
+        assert_eq!(tree.verify_with_tree(&values[2], 2, &tampered), Err(2));
+        assert!(!tree.verify(&values[2], &tampered));
+        /* a wrong value diverges at the leaf, and a leftover item beyond the root */
+        assert_eq!(tree.verify_with_tree(&values[3], 2, &proof), Err(0));
+        let mut longer = proof.clone();
+        longer.0.push(MerkleProofItem::Right([0; 32]));
+        assert_eq!(tree.verify_with_tree(&values[2], 2, &longer), Err(4));
+        /* lone nodes are padded as when building */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values[..5].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(tree.verify_with_tree(&values[4], 4, &tree.get_proof(values[4].clone()).unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn test_merkle_verify_proof_against_nodes() {
+        let values = user_leaves(&[1, 2, 3, 4, 5]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        /* genuine proofs pass */
+        for value in &values {
+            assert!(tree.verify_proof(value, &tree.get_proof(value.clone()).unwrap()));
+        }
+        assert!(!tree.verify_proof(&values[1], &tree.get_proof(values[0].clone()).unwrap()));
+        assert!(!tree.verify_proof(b"nobody", &tree.get_proof(values[0].clone()).unwrap()));
+        /* a fabricated sibling fails */
+        let mut tampered = tree.get_proof(values[2].clone()).unwrap();
+        tampered.0[0] = MerkleProofItem::Right([0; 32]);
+        assert!(!tree.verify_proof(&values[2], &tampered));
+        /* the lone leaf paired with its own hash folds to the root, but that item is not a stored sibling */
+        let explicit = tree.get_proof_explicit(values[4].clone()).unwrap();
+        assert_eq!(explicit.compute_root::<Sha256Algorithm>(&values[4], 4, 5, LEAF_TAG, BRANCH_TAG, Padding::Duplicate), None);
+        assert!(code_test::mmr::verify_root::<32, Sha256Algorithm>(&values[4], &explicit, &tree.get_root(), LEAF_TAG, BRANCH_TAG));
+        assert!(!tree.verify_proof(&values[4], &explicit));
+        /* in sorted-pair mode, a proof with its directions swapped folds to the root, but is not the path of the leaf */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_with_options(values[..4].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Duplicate, true);
+        let proof = tree.get_proof(values[1].clone()).unwrap();
+        let swapped = MerkleProof(proof.0.iter().map(|item| match item {
+            MerkleProofItem::Left(hash) => MerkleProofItem::Right(*hash),
+            MerkleProofItem::Right(hash) => MerkleProofItem::Left(*hash)
+        }).collect());
+        assert!(tree.verify(&values[1], &swapped));
+        assert!(tree.verify_proof(&values[1], &proof));
+        assert!(!tree.verify_proof(&values[1], &swapped));
+    }
+
+    #[test]
+    fn test_merkle_proof_prefix() {
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = tree.get_proof(values[1].clone()).unwrap();
+        let partial_root = |proof: &MerkleProof<32>| proof.compute_partial_root::<Sha256Algorithm>(&values[1], 1, 6, LEAF_TAG, BRANCH_TAG, Padding::Duplicate);
+        /* folding the first two items of the proof of leaf 1 gives the node above leaves 0 to 3 */
+        let prefix = proof.prefix(2);
+        assert_eq!(prefix.0.len(), 2);
+        assert_eq!(partial_root(&prefix), Some((2, tree.layers()[2][0])));
+        assert_eq!(partial_root(&proof.prefix(0)), Some((0, tree.layers()[0][1])));
+        /* the whole proof reaches the root */
+        assert_eq!(proof.prefix(10), proof);
+        assert_eq!(partial_root(&proof), Some((3, tree.get_root().0)));
+        /* folding stops when the items run out, before the lone node above leaves 4 and 5 is padded */
+        let proof = tree.get_proof(values[5].clone()).unwrap();
+        let partial_root = proof.prefix(1).compute_partial_root::<Sha256Algorithm>(&values[5], 5, 6, LEAF_TAG, BRANCH_TAG, Padding::Duplicate);
+        assert_eq!(partial_root, Some((1, tree.layers()[1][2])));
+    }
+
+    #[test]
+    fn test_merkle_verify_stream() {
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6]);
+        for padding in [Padding::Duplicate, Padding::Promote] {
+            let tree = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding);
+            let root = tree.get_root();
+            for (index, value) in values.iter().enumerate() {
+                let proof = tree.get_proof_at(index).unwrap();
+                let verify = |proof: &MerkleProof<32>, index| verify_root::<32, Sha256Algorithm>(value, proof, index, 6, &root, LEAF_TAG, BRANCH_TAG, padding);
+                /* the items are streamed one at a time from their flattened form */
+                let (directions, hashes) = proof.flatten();
+                let stream = || directions.iter().zip(hashes.chunks(32))
+                    .map(|(&direction, hash)| MerkleProofItem::from_direction(direction, hash.try_into().unwrap()).unwrap());
+                let verify_streamed = |index| verify_stream::<32, Sha256Algorithm>(value, stream(), index, 6, &root, LEAF_TAG, BRANCH_TAG, padding);
+                assert!(verify_streamed(index));
+                assert_eq!(verify_streamed(index), verify(&proof, index));
+                /* the wrong position, a missing item and a leftover item are rejected as well */
+                assert_eq!(verify_streamed(index ^ 1), verify(&proof, index ^ 1));
+                let truncated = verify_stream::<32, Sha256Algorithm>(value, stream().skip(1), index, 6, &root, LEAF_TAG, BRANCH_TAG, padding);
+                assert_eq!(truncated, verify(&MerkleProof(stream().skip(1).collect()), index));
+                assert!(!truncated);
+                let extended = verify_stream::<32, Sha256Algorithm>(value, stream().chain([MerkleProofItem::Left([0; 32])]), index, 6, &root, LEAF_TAG, BRANCH_TAG, padding);
+                assert!(!extended);
+            }
+        }
+    }
+
+    #[test]
+    #[allow(clippy::redundant_pattern_matching)]
+    fn test_merkle_proof_nonexistent() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        assert!(matches!(tree.get_proof(b"ggg".to_vec()), None));
+    }
+
+    #[test]
+    fn test_merkle_proof_equality() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        assert_eq!(tree.get_proof(b"aaa".to_vec()), tree.get_proof(b"aaa".to_vec()));
+        assert_ne!(tree.get_proof(b"aaa".to_vec()), tree.get_proof(b"bbb".to_vec()));
+        assert_ne!(tree.get_proof(b"aaa".to_vec()), tree.get_proof(b"eee".to_vec()));
+    }
+
+    #[test]
+    fn test_merkle_verify() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values.clone(), tag.clone(), tag.clone());
+        for value in &test_values {
+            let proof = tree.get_proof(value.clone()).unwrap();
+            assert!(tree.verify(value, &proof));
+        }
+        /* a proof for one leaf does not verify another */
+        let proof = tree.get_proof(b"aaa".to_vec()).unwrap();
+        assert!(!tree.verify(b"bbb", &proof));
+        /* a tampered proof does not verify */
+        let MerkleProof(mut items) = proof;
+        items[1] = MerkleProofItem::Right([0; 32]);
+        assert!(!tree.verify(b"aaa", &MerkleProof(items)));
+    }
+
+    #[test]
+    fn test_merkle_proof_object_serialization() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        let proof = tree.get_proof(b"eee".to_vec()).unwrap();
+        let MerkleProofItem::Left(hash) = proof.0[0] else { panic!("Anomaly! The only proof item of eee should be a left node.") };
+        let hash_hex = format!("0x{}", HEXLOWER.encode(&hash));
+        assert_eq!(serde_json::to_value(proof.as_objects()).unwrap(), json!([{ "position": "left", "hash": hash_hex }]));
+        /* the default serialization is still the tuple form */
+        assert_eq!(serde_json::to_value(&proof).unwrap(), json!([[0, hash_hex]]));
+    }
+
+    #[test]
+    fn test_bare_hex_serialization() {
+        let db = create_test_db();
+        let root = db.get_root().unwrap();
+        assert_eq!(serde_json::to_value(&root).unwrap(), json!("0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"));
+        assert_eq!(serde_json::to_value(root.as_bare_hex()).unwrap(), json!("10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"));
+        /* proofs keep their shape, only the prefix of the hashes is dropped */
+        let proof = db.get_proof(2).unwrap();
+        let prefixed = serde_json::to_value(&proof).unwrap();
+        let bare = serde_json::to_value(proof.as_bare_hex()).unwrap();
+        assert_eq!(bare.as_array().unwrap().len(), proof.0.len());
+        for (bare_item, prefixed_item) in bare.as_array().unwrap().iter().zip(prefixed.as_array().unwrap()) {
+            assert_eq!(bare_item[0], prefixed_item[0]);
+            assert_eq!(format!("0x{}", bare_item[1].as_str().unwrap()), prefixed_item[1].as_str().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_serde_feature() {
+        /* the server enables the serde feature of the library, so roots, proofs and options serialize */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let root = serde_json::to_value(tree.get_root()).unwrap();
+        assert_eq!(root, json!(format!("0x{}", HEXLOWER.encode(&tree.get_root().0))));
+        let MerkleProofItem::Right(sibling) = tree.get_proof(user_leaves(&[1])[0].clone()).unwrap().0[0] else { panic!("Anomaly! The sibling of the first leaf should be on the right.") };
+        let proof = serde_json::to_value(tree.get_proof(user_leaves(&[1])[0].clone()).unwrap()).unwrap();
+        assert_eq!(proof, json!([[1, format!("0x{}", HEXLOWER.encode(&sibling))]]));
+        assert_eq!(serde_json::to_value(Padding::Promote).unwrap(), json!("promote"));
+    }
+
+    fn user_leaves(ids: &[u64]) -> Vec<Vec<u8>> {
+        ids.iter().map(|id| format!("({},{})", id, id * 1111).into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_multiproof_overlapping() {
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2, 3, 4, 5, 6, 7, 8]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let values = user_leaves(&[3, 1, 2]);
+        let proof = tree.get_multiproof(&values).unwrap();
+        assert_eq!(proof.indices, vec![2, 0, 1]);
+        /* leaves 0, 1 and 2 only need the sibling of 2 and the right half of the tree, instead of 9 hashes */
+        assert_eq!(proof.siblings.len(), 2);
+        assert!(merkle::verify_multiproof::<32, Sha256Algorithm>(&values, &proof, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        /* the values must be given in the order of the indices */
+        let swapped = user_leaves(&[1, 3, 2]);
+        assert!(!merkle::verify_multiproof::<32, Sha256Algorithm>(&swapped, &proof, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+    }
+
+    #[test]
+    fn test_multiproof_disjoint() {
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(user_leaves(&[1, 2, 3, 4, 5, 6, 7, 8]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let values = user_leaves(&[1, 8]);
+        let proof = tree.get_multiproof(&values).unwrap();
+        /* two siblings on each of the two lowest layers, the paths meet at the root */
+        assert_eq!(proof.siblings.len(), 4);
+        assert!(merkle::verify_multiproof::<32, Sha256Algorithm>(&values, &proof, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        /* a wrong balance does not verify */
+        let wrong = vec![values[0].clone(), b"(8,8889)".to_vec()];
+        assert!(!merkle::verify_multiproof::<32, Sha256Algorithm>(&wrong, &proof, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        /* a value that is not in the tree has no multiproof */
+        assert!(tree.get_multiproof(&user_leaves(&[1, 9])).is_none());
+    }
+
+    #[test]
+    fn test_multiproof_padding() {
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values.clone(), tag.clone(), tag.clone());
+        let values = vec![b"eee".to_vec(), b"bbb".to_vec()];
+        let proof = tree.get_multiproof(&values).unwrap();
+        assert!(merkle::verify_multiproof::<32, Sha256Algorithm>(&values, &proof, &tree.get_root(), &tag, &tag, Padding::Duplicate));
+        let proof = tree.get_multiproof(&test_values).unwrap();
+        assert!(proof.siblings.is_empty());
+        assert!(merkle::verify_multiproof::<32, Sha256Algorithm>(&test_values, &proof, &tree.get_root(), &tag, &tag, Padding::Duplicate));
+    }
+
+    #[test]
+    fn test_mmr_append() {
+        let test_values = [b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec(), b"fff".to_vec(), b"ggg".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let mut mmr = MmrTree::<32, Sha256Algorithm>::new(tag.clone(), tag.clone());
+        for (i, value) in test_values.iter().enumerate() {
+            assert_eq!(mmr.append(value), i);
+            assert_eq!(mmr.num_leaves(), i + 1);
+            /* every leaf appended so far has a valid proof against the current root */
+            for prev in &test_values[..=i] {
+                let proof = mmr.get_proof(prev.clone()).unwrap();
+                assert!(mmr.verify(prev, &proof));
+            }
+            /* a leaf that has not been appended yet has no proof */
+            assert!(mmr.get_proof(b"hhh".to_vec()).is_none());
+        }
+        /* 7 leaves = mountains of heights 2, 1, 0; a leaf in the first mountain needs 2 + 1 items */
+        assert_eq!(mmr.get_proof(b"aaa".to_vec()).unwrap().0.len(), 3);
+        assert_eq!(mmr.layer_sizes(), vec![7, 3, 1]);
+        /* proofs from an older root do not verify against the new one */
+        let proof = mmr.get_proof(b"ggg".to_vec()).unwrap();
+        mmr.append(b"hhh");
+        assert!(!mmr.verify(b"ggg", &proof));
+    }
+
+    #[test]
+    fn test_mmr_perfect_tree() {
+        /* with a power of two number of leaves there is a single mountain, which is an ordinary Merkle tree */
+        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec()];
+        let tag = (b"Bitcoin_Transaction").to_vec();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values.clone(), tag.clone(), tag.clone());
+        let mmr = MmrTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
+        assert_eq!(mmr.get_root().0, tree.get_root().0);
+    }
+
+    #[test]
+    fn test_mmr_database() {
+        let db: InMemoryDatabase<32, Sha256Algorithm, MmrTree<32, Sha256Algorithm>> =
+            InMemoryDatabase::create(TEST_DATA.to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        /* 8 users form a single mountain, so the root is the same as with the regular tree */
+        assert_eq!(db.get_root(), create_test_db().get_root());
+        for (user_id, balance) in TEST_DATA {
+            let proof = db.get_proof(user_id).unwrap();
+            assert!(db.verify_proof(user_id, balance, &proof));
+        }
+    }
+
+    #[test]
+    fn test_flat_merkle_tree() {
+        for num_leaves in 1..=33u64 {
+            let values: Vec<Vec<u8>> = (0..num_leaves).map(|i| db::serialize_user(i, i * 7)).collect();
+            for (padding, sorted_pairs) in [(Padding::Duplicate, false), (Padding::Promote, false), (Padding::Duplicate, true)] {
+                let tree = MerkleTree::<32, Sha256Algorithm>::build_with_options(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding, sorted_pairs);
+                let flat = FlatMerkleTree::<32, Sha256Algorithm>::build_with_options(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), padding, sorted_pairs);
+                assert_eq!(flat.get_root(), tree.get_root());
+                assert_eq!(flat.depth(), tree.depth());
+                assert_eq!(flat.layer_sizes(), tree.layers().iter().map(Vec::len).collect::<Vec<_>>());
+                for (index, value) in values.iter().enumerate() {
+                    assert_eq!(flat.get_proof_at(index), tree.get_proof_at(index));
+                    assert_eq!(flat.get_proof(value.clone()), tree.get_proof(value.clone()));
+                    let proof = flat.get_proof(value.clone()).unwrap();
+                    assert_eq!(flat.verify(value, &proof), tree.verify(value, &proof));
+                    assert!(sorted_pairs || flat.verify(value, &proof));
+                }
+                assert_eq!(flat.get_proof_at(values.len()), None);
+            }
+        }
+        /* updates and appends keep the trees identical */
+        let values: Vec<Vec<u8>> = TEST_DATA.iter().map(|(id, balance)| db::serialize_user(*id, *balance)).collect();
+        let mut tree = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let mut flat = FlatMerkleTree::<32, Sha256Algorithm>::build(values, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        tree.update_leaf(5, b"fff");
+        flat.update_leaf(5, b"fff");
+        assert_eq!(flat.get_root(), tree.get_root());
+        assert_eq!(flat.append(b"iii"), tree.append(b"iii"));
+        assert_eq!(flat.get_root(), tree.get_root());
+        assert_eq!(flat.get_proof(b"fff".to_vec()), tree.get_proof(b"fff".to_vec()));
+        /* and so do databases over them */
+        let mut db: InMemoryDatabase<32, Sha256Algorithm, FlatMerkleTree<32, Sha256Algorithm>> =
+            InMemoryDatabase::create(TEST_DATA.to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let mut expected = create_test_db();
+        assert_eq!(db.get_root(), expected.get_root());
+        db.set_balance(9, 9999);
+        db.remove_user(2);
+        expected.set_balance(9, 9999);
+        expected.remove_user(2);
+        assert_eq!(db.get_root(), expected.get_root());
+        assert_eq!(db.get_proof(9), expected.get_proof(9));
+    }
+
+    #[tokio::test]
+    async fn test_root_api() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/root")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!("0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"))
+    }
+
+    #[tokio::test]
+    async fn test_root_api_verbose() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/root?verbose=true")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!({
+            "root": "0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a",
+            "num_leaves": 8,
+            "algorithm": "sha256",
+            "hash_size": 32
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_normal() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/1")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+        
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        match body_json {
+            Value::Object(m) => {
+                /* retrieves balance correctly */
+                assert_eq!(m.get("balance"), Some(json!(1111)).as_ref());
+                let proof = match m.get("proof") {
+                    Some(Value::Array(items)) => { items }
+                    _ => { panic!("Garbage response!") }
+                };
+                /* right proof length */
+                assert_eq!(proof.len(), 3);
+                /* correctly serializes left/right node as 0 or 1 */
+                match &proof[0] {
+                    Value::Array(item_1) => {
+                        assert_eq!(item_1[0], json!(1))
+                    }
+                    _ => { panic!("Bad proof format!") }
+                }
+            }
+            _ => {
+                panic!("Garbage response!")
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_index_and_size() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app(connection.clone());
+        for (user_id, leaf_index) in [(1, 0), (8, 7)] {
+            let response = app.clone().oneshot(Request::builder().uri(format!("/proof/{}", user_id)).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body_json: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body_json["leaf_index"], json!(leaf_index));
+            assert_eq!(body_json["tree_size"], json!(8));
+            /* the index and the size are those the proof verifies with */
+            let db = connection.snapshot();
+            let proof = db.get_proof(user_id).unwrap();
+            assert_eq!(body_json["proof"], json!(proof));
+            let value = db::serialize_user(user_id, body_json["balance"].as_u64().unwrap());
+            let root = db.get_root().unwrap();
+            assert!(verify_root::<32, Sha256Algorithm>(&value, &proof, leaf_index, 8, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_nonexistent() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/10")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+        
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_root_api_empty() {
+        let connection = Arc::new(OnlineDatabase::new(InMemoryDatabase::create(vec![], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())));
+        let app = create_app(connection.clone());
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+                let status = response.status();
+                let leaves = response.headers()[LEAVES_HEADER].clone();
+                assert!(!response.headers().contains_key(ROOT_HEADER));
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, leaves, body)
+            }
+        };
+        /* 204 by default, null with empty=null */
+        let (status, leaves, body) = get("/root").await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(leaves, "0");
+        assert!(body.is_empty());
+        let (status, _, body) = get("/root?empty=null").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), Value::Null);
+        let (status, _, body) = get("/root?verbose=true&empty=null").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "root": null, "num_leaves": 0, "algorithm": "sha256", "hash_size": 32 }));
+        assert_eq!(get("/root?empty=maybe").await.0, StatusCode::BAD_REQUEST);
+        /* a database emptied by updates has no root either, and gets one back with its next user */
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        connection.update(|db| (1..=8).for_each(|user_id| { db.remove_user(user_id); }));
+        assert_eq!(connection.snapshot().get_root(), None);
+        let app = create_app(connection.clone());
+        let response = app.clone().oneshot(Request::builder().uri("/root").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        connection.update(|db| db.set_balance(9, 9999));
+        let response = app.oneshot(Request::builder().uri("/root?empty=null").body(Body::empty()).unwrap()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!(connection.snapshot().get_root().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_merkle_headers() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let root = format!("0x{}", HEXLOWER.encode(&connection.snapshot().get_root().unwrap().0));
+        let app = create_app(connection);
+        for (uri, status) in [("/root", StatusCode::OK), ("/proof/1", StatusCode::OK), ("/proof/10", StatusCode::NOT_FOUND), ("/proof/abc", StatusCode::BAD_REQUEST), ("/nowhere", StatusCode::NOT_FOUND)] {
+            let response = app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+            assert_eq!(response.headers().get_all(ROOT_HEADER).iter().collect::<Vec<_>>(), [root.as_str()], "{}", uri);
+            assert_eq!(response.headers()[LEAVES_HEADER], "8", "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_missing_mode() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        connection.update(|db| db.remove_user(3));
+        let app = create_app(connection);
+        let get = |uri: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, body)
+            }
+        };
+        /* 404 (or 410) by default and with missing=404 */
+        assert_eq!(get("/proof/10").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get("/proof/10?missing=404").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get("/proof/3?missing=404").await.0, StatusCode::GONE);
+        /* 200 saying the user is missing with missing=null */
+        let (status, body) = get("/proof/10?missing=null").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "user_id": 10, "found": false, "error": "not found" }));
+        let (status, body) = get("/proof/3?missing=null").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "user_id": 3, "found": false, "error": "removed" }));
+        /* existing users and invalid IDs are unaffected */
+        let (status, body) = get("/proof/1?missing=null").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["balance"], 1111);
+        assert_eq!(get("/proof/abc?missing=null").await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(get("/proof/10?missing=maybe").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_desync() {
+        /* an encoder that changes after the build, so the leaves of the users are no longer found in the tree */
+        static DESYNCED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+        fn encode_leaf(user_id: u64, balance: u64) -> Vec<u8> {
+            let balance = if DESYNCED.load(std::sync::atomic::Ordering::SeqCst) { balance + 1 } else { balance };
+            db::serialize_user(user_id, balance)
+        }
+        let db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+            .leaf_tag(LEAF_TAG.to_vec())
+            .branch_tag(BRANCH_TAG.to_vec())
+            .leaf_encoder(encode_leaf)
+            .build(TEST_DATA.to_vec());
+        assert!(db.try_get_proof(1).is_ok());
+        DESYNCED.store(true, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(db.try_get_proof(1), Err(ProofError::Desync(1)));
+        assert_eq!(db.try_get_proof(10), Err(ProofError::UserNotFound(10)));
+        assert_eq!(db.get_proof(1), None);
+        assert_eq!(db.verify_user(1), Err(ProofError::Desync(1)));
+        /* the handlers report the desync as an internal error, and a missing user as before */
+        let app = create_app(Arc::new(OnlineDatabase::new(db)));
+        let get = |uri: &'static str| app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap());
+        let response = get("/proof/1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"The Merkle tree is out of sync with the data of user 1.");
+        assert_eq!(get("/proofs?ids=10,1").await.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(get("/proof/10").await.unwrap().status(), StatusCode::NOT_FOUND);
+        let response = get("/proof/1/verify").await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"The Merkle tree is out of sync with the data of user 1.");
+    }
+
+    #[tokio::test]
+    async fn test_verify_api_normal() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/3/verify")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!({
+            "valid": true,
+            "balance": 3333,
+            "root": "0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_verify_api_nonexistent() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/10/verify")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn verify_submitted_request(proof: Value) -> Response {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        app.oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/verify")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(json!({ "user_id": 2, "balance": 2222, "proof": proof }).to_string()))
+                .unwrap()
+        ).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_verify_submitted_proof_api() {
+        let proof = create_test_db().get_proof(2).unwrap();
+        let response = verify_submitted_request(serde_json::to_value(&proof).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!({
+            "valid": true,
+            "root": "0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_verify_submitted_proof_bad_direction() {
+        let mut proof = serde_json::to_value(create_test_db().get_proof(2).unwrap()).unwrap();
+        proof[1][0] = json!(2);
+        let response = verify_submitted_request(proof).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Invalid proof item at index 1: direction 2 is neither 0 (left) nor 1 (right).");
+    }
+
+    #[tokio::test]
+    async fn test_verify_submitted_proof_short_hash() {
+        let mut proof = serde_json::to_value(create_test_db().get_proof(2).unwrap()).unwrap();
+        let short = proof[2][1].as_str().unwrap()[..64].to_string();
+        proof[2][1] = json!(short);
+        let response = verify_submitted_request(proof).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(std::str::from_utf8(&body).unwrap().starts_with("Invalid proof item at index 2: "));
+    }
+
+    #[tokio::test]
+    async fn test_verify_compact_proof_api() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app(connection.clone());
+        let post = |uri: String, body: Vec<u8>| app.clone().oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri(uri)
+                .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(body))
+                .unwrap()
+        );
+        let valid = |response: Response| async move {
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Value>(&body).unwrap()["valid"].as_bool().unwrap()
+        };
+        let compact = connection.snapshot().get_proof(2).unwrap().to_compact();
+        let root = HEXLOWER.encode(&connection.snapshot().get_root().unwrap().0);
+        assert!(valid(post("/verify.bin?user_id=2&balance=2222".to_string(), compact.clone()).await.unwrap()).await);
+        assert!(valid(post(format!("/verify.bin?user_id=2&balance=2222&root=0x{}", root), compact.clone()).await.unwrap()).await);
+        /* a wrong balance, the proof of another user or another expected root */
+        assert!(!valid(post("/verify.bin?user_id=2&balance=2223".to_string(), compact.clone()).await.unwrap()).await);
+        let other = connection.snapshot().get_proof(3).unwrap().to_compact();
+        assert!(!valid(post("/verify.bin?user_id=2&balance=2222".to_string(), other).await.unwrap()).await);
+        assert!(!valid(post(format!("/verify.bin?user_id=2&balance=2222&root=0x{}", "00".repeat(32)), compact.clone()).await.unwrap()).await);
+        /* malformed proofs, roots and queries */
+        let response = post("/verify.bin?user_id=2&balance=2222".to_string(), compact[..compact.len() - 1].to_vec()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Malformed compact proof.");
+        assert_eq!(post("/verify.bin?user_id=2&balance=2222&root=0xzz".to_string(), compact.clone()).await.unwrap().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(post("/verify.bin?user_id=2".to_string(), compact).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_error_conversions() {
+        fn status(result: Result<(), Error>) -> StatusCode {
+            result.unwrap_err().into_response().status()
+        }
+        let io = || -> Result<(), Error> { Err(std::io::Error::from(std::io::ErrorKind::NotFound))? };
+        assert_eq!(status(io()), StatusCode::INTERNAL_SERVER_ERROR);
+        let json = || -> Result<(), Error> { serde_json::from_str::<u64>("five")?; Ok(()) };
+        assert_eq!(status(json()), StatusCode::BAD_REQUEST);
+        let merkle = || -> Result<(), Error> {
+            MerkleTree::<32, Sha256Algorithm>::build_strict(user_leaves(&[1, 2, 3]), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())?;
+            Ok(())
+        };
+        assert_eq!(status(merkle()), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(status(Err(Error::from(9))), StatusCode::NOT_FOUND);
+    }
+
+    async fn mutation_request(app: Router, method: http::Method, uri: &str, body: Option<Value>) -> Response {
+        let request = Request::builder().method(method).uri(uri);
+        let request = match body {
+            Some(body) => request.header(http::header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+            None => request.body(Body::empty())
+        };
+        app.oneshot(request.unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_read_only_mode() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let root = connection.snapshot().get_root().unwrap().0;
+        let config = Config { mode: Mode::ReadOnly, ..Config::default() };
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::PUT, "/users/9", Some(json!({ "balance": 9999 }))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::DELETE, "/users/1", None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::POST, "/users", Some(json!({ "users": [[9, 9999]] }))).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(connection.snapshot().get_root().unwrap().0, root);
+        /* reads are still served */
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::GET, "/proof/1", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_writable_mode() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let config = Config { mode: Mode::Writable, ..Config::default() };
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::PUT, "/users/9", Some(json!({ "balance": 9999 }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["balance"], json!(9999));
+        assert_eq!(body_json["root"], serde_json::to_value(connection.snapshot().get_root().unwrap()).unwrap());
+        assert_eq!(connection.snapshot().get_balance(9, DEFAULT_ASSET), Some(9999));
+        /* setting the same balance again publishes no new state */
+        let epoch = connection.epoch();
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::PUT, "/users/9", Some(json!({ "balance": 9999 }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(connection.epoch(), epoch);
+
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::DELETE, "/users/1", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["balance"], json!(1111));
+        assert!(connection.snapshot().is_removed(1));
+        /* removing a user twice fails without publishing a new state */
+        let (epoch, last_update) = (connection.epoch(), connection.last_update());
+        let response = mutation_request(create_app_with_config(connection.clone(), &config), http::Method::DELETE, "/users/1", None).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+        assert_eq!((connection.epoch(), connection.last_update()), (epoch, last_update));
+    }
+
+    #[tokio::test]
+    async fn test_remove_last_user_api() {
+        let connection = Arc::new(OnlineDatabase::new(InMemoryDatabase::create(vec![(1, 1111)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())));
+        let config = Config { mode: Mode::Writable, signing_key: Some(SigningKey::from_bytes(&[7; 32])), ..Config::default() };
+        let request = |method, uri: &'static str, body| mutation_request(create_app_with_config(connection.clone(), &config), method, uri, body);
+        /* removing the last user leaves the database without a root */
+        let response = request(http::Method::DELETE, "/users/1", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "user_id": 1, "balance": 1111, "root": null }));
+        /* which the endpoints needing a root report instead of failing */
+        assert_eq!(request(http::Method::GET, "/root/signed", None).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(request(http::Method::GET, "/aggregate-root", None).await.status(), StatusCode::NOT_FOUND);
+        let response = request(http::Method::POST, "/verify", Some(json!({ "user_id": 1, "balance": 1111, "proof": [] }))).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), json!({ "valid": false, "root": null }));
+        /* the database is still writable, and gets a root back with its next user */
+        let response = request(http::Method::PUT, "/users/2", Some(json!({ "balance": 2222 }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let expected: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(vec![(2, 2222)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["root"], json!(expected.get_root()));
+        assert_eq!(connection.snapshot().get_root(), expected.get_root());
+        assert_eq!(request(http::Method::GET, "/root/signed", None).await.status(), StatusCode::OK);
+    }
+
+    async fn bulk_insert_request(app: Router, key: &str, users: Value) -> Value {
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/users")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .header("idempotency-key", key)
+                    .body(Body::from(json!({ "users": users }).to_string()))
+                    .unwrap()
+            ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bulk_insert_idempotency() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app_with_config(connection.clone(), &Config { mode: Mode::Writable, ..Config::default() });
+        /* the first request applies */
+        let first = bulk_insert_request(app.clone(), "first", json!([[9, 9999], [10, 10000]])).await;
+        assert_eq!(first["inserted"], json!(2));
+        assert_eq!(first["root"], serde_json::to_value(connection.snapshot().get_root().unwrap()).unwrap());
+        assert_eq!(connection.snapshot().get_balance(10, DEFAULT_ASSET), Some(10000));
+        /* a retry with the same key is not applied again */
+        connection.update(|db| db.set_balance(9, 9000));
+        let root = connection.snapshot().get_root().unwrap().0;
+        let retry = bulk_insert_request(app.clone(), "first", json!([[9, 9999], [10, 10000]])).await;
+        assert_eq!(retry, first);
+        assert_eq!(connection.snapshot().get_root().unwrap().0, root);
+        assert_eq!(connection.snapshot().get_balance(9, DEFAULT_ASSET), Some(9000));
+        /* and the key cannot be reused for another request */
+        let response = mutation_request(app.clone(), http::Method::POST, "/users", Some(json!({ "users": [[9, 9998]] }))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let reused = Request::builder().method(http::Method::POST).uri("/users")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("idempotency-key", "first")
+            .body(Body::from(json!({ "users": [[9, 1], [10, 10000]] }).to_string()))
+            .unwrap();
+        assert_eq!(app.clone().oneshot(reused).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(connection.snapshot().get_balance(9, DEFAULT_ASSET), Some(9998));
+        /* a different key applies normally */
+        let second = bulk_insert_request(app.clone(), "second", json!([[9, 9999], [11, 11111]])).await;
+        assert_eq!(second["root"], serde_json::to_value(connection.snapshot().get_root().unwrap()).unwrap());
+        assert_ne!(second["root"], first["root"]);
+        assert_eq!(connection.snapshot().get_balance(9, DEFAULT_ASSET), Some(9999));
+    }
+
+    #[test]
+    fn test_idempotency_keys_bounded() {
+        let mut keys = IdempotencyKeys::default();
+        for i in 0..=MAX_IDEMPOTENCY_KEYS {
+            keys.insert(i.to_string(), [0; 32], Some((i, Some([0; 32]))));
+        }
+        assert_eq!(keys.results.len(), MAX_IDEMPOTENCY_KEYS);
+        assert!(!keys.results.contains_key("0"));
+        assert_eq!(keys.results["1"], ([0; 32], Some((1, Some([0; 32])))));
+        /* a key being applied is reserved, and answered once complete */
+        assert!(matches!(keys.reserve("new", [1; 32]), Ok(None)));
+        assert!(matches!(keys.reserve("new", [1; 32]), Err(Error::IdempotencyKeyInProgress(_))));
+        assert!(matches!(keys.reserve("new", [2; 32]), Err(Error::IdempotencyKeyReused(_))));
+        keys.complete("new", (3, None));
+        assert!(matches!(keys.reserve("new", [1; 32]), Ok(Some((3, None)))));
+        assert!(!keys.results.contains_key("1"));
+    }
+
+    #[test]
+    fn test_idempotency_key_failed_apply() {
+        let keys = Mutex::new(IdempotencyKeys::default());
+        /* a request that panics while being applied releases its key */
+        let panicked = std::panic::catch_unwind(|| apply_once(&keys, "key", [1; 32], || panic!("apply failed")));
+        assert!(panicked.is_err());
+        assert!(!keys.lock().unwrap().results.contains_key("key"));
+        assert!(keys.lock().unwrap().order.is_empty());
+        /* so that a retry is applied rather than stuck as in progress, and then answered with its result */
+        assert!(matches!(apply_once(&keys, "key", [1; 32], || (2, None)), Ok((2, None))));
+        assert!(matches!(apply_once(&keys, "key", [1; 32], || unreachable!()), Ok((2, None))));
+        /* a completed key is not released */
+        keys.lock().unwrap().release("key");
+        assert!(matches!(keys.lock().unwrap().reserve("key", [1; 32]), Ok(Some((2, None)))));
+    }
+
+    #[tokio::test]
+    async fn test_build_tree_api() {
+        let app = create_app_with_config(Arc::new(OnlineDatabase::new(create_test_db())), &Config { mode: Mode::Writable, ..Config::default() });
+        let leaves = ["0x616161", "626262", "0x636363", "0x646464", "0x656565"];
+        let request = json!({ "leaves": leaves, "leaf_tag": format!("0x{}", HEXLOWER.encode(LEAF_TAG)), "branch_tag": HEXLOWER.encode(BRANCH_TAG) });
+        let response = app.clone().oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/build")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap()
+        ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let built: Value = serde_json::from_slice(&body).unwrap();
+        let values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
+        let root = MerkleTree::<32, Sha256Algorithm>::build(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()).get_root();
+        assert_eq!(built["root"], serde_json::to_value(&root).unwrap());
+        assert_eq!(built["num_leaves"], json!(5));
+        /* the proof fetched by the returned ID verifies against the root */
+        let uri = format!("/build/{}/proof/3", built["id"]);
+        let response = app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let fetched: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(fetched["root"], built["root"]);
+        let items = fetched["proof"].as_array().unwrap().iter()
+            .map(|item| MerkleProofItem::from_parts(item[0].as_u64().unwrap(), item[1].as_str().unwrap()).unwrap())
+            .collect();
+        let computed = MerkleProof(items).compute_root::<Sha256Algorithm>(&values[3], 3, 5, LEAF_TAG, BRANCH_TAG, Padding::Duplicate);
+        assert_eq!(computed.unwrap().0, root.0);
+        /* unknown trees and leaves are not found */
+        let uri = format!("/build/{}/proof/5", built["id"]);
+        let response = app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = app.oneshot(Request::builder().uri("/build/12345/proof/0").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        /* read-only servers do not build trees */
+        let app = create_app(Arc::new(OnlineDatabase::new(create_test_db())));
+        let response = mutation_request(app, http::Method::POST, "/build", Some(request)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_build_tree_api_validation() {
+        let app = create_app_with_config(Arc::new(OnlineDatabase::new(create_test_db())), &Config { mode: Mode::Writable, ..Config::default() });
+        let build = |request: Value| app.clone().oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/build")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap()
+        );
+        let read = |response: Response| async { String::from_utf8(response.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap() };
+        let hashes: Vec<[u8; 32]> = [b"aaa", b"bbb", b"ccc"].iter().map(|value| Sha256Algorithm::tagged_hash(LEAF_TAG, *value)).collect();
+        let hex_hashes: Vec<String> = hashes.iter().map(|hash| format!("0x{}", HEXLOWER.encode(hash))).collect();
+        let tags = (HEXLOWER.encode(LEAF_TAG), HEXLOWER.encode(BRANCH_TAG));
+        /* valid leaf hashes build the same tree as their values */
+        let response = build(json!({ "leaves": hex_hashes, "leaf_tag": tags.0, "branch_tag": tags.1, "leaf_mode": "hash" })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let built: Value = serde_json::from_str(&read(response).await).unwrap();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec()], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(built["root"], json!(tree.get_root()));
+        /* a leaf hash of the wrong length is reported with its index */
+        let mut short = hex_hashes.clone();
+        short[1] = format!("0x{}", HEXLOWER.encode(&hashes[1][..31]));
+        let response = build(json!({ "leaves": short, "leaf_tag": tags.0, "branch_tag": tags.1, "leaf_mode": "hash" })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(read(response).await, "Invalid leaf at index 1: a leaf hash has 32 bytes, not 31.");
+        /* raw values may have any length, but must be hex, as the tags */
+        let response = build(json!({ "leaves": ["616161", "6262", "zz"], "leaf_tag": tags.0, "branch_tag": tags.1 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(read(response).await, "Invalid leaf at index 2: zz is not a hex string.");
+        let response = build(json!({ "leaves": ["616161"], "leaf_tag": "0xnothex", "branch_tag": tags.1 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(read(response).await, "Invalid hex string 0xnothex.");
+        let response = build(json!({ "leaves": ["616161"], "leaf_tag": tags.0, "branch_tag": "ABC" })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        /* and the mode must be known */
+        let response = build(json!({ "leaves": ["616161"], "leaf_tag": tags.0, "branch_tag": tags.1, "leaf_mode": "cooked" })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn test_ephemeral_trees_bounded() {
+        let tree = || MerkleTree::<32, Sha256Algorithm>::build(vec![b"aaa".to_vec()], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let mut trees = EphemeralTrees::default();
+        let start = Instant::now();
+        let ids: Vec<u64> = (0..=MAX_EPHEMERAL_TREES).map(|_| trees.insert(tree(), start)).collect();
+        assert_eq!(trees.trees.len(), MAX_EPHEMERAL_TREES);
+        assert!(trees.get(ids[0], start).is_none());
+        assert!(trees.get(ids[1], start).is_some());
+        /* the IDs are random rather than sequential */
+        assert!(ids.windows(2).any(|pair| pair[1] != pair[0].wrapping_add(1)));
+        /* expired trees are not served, and are dropped by the next insertion */
+        let later = start + EPHEMERAL_TREE_TTL;
+        assert!(trees.get(ids[1], later).is_none());
+        let id = trees.insert(tree(), later);
+        assert_eq!(trees.trees.len(), 1);
+        assert!(trees.get(id, later).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let config = Config::default();
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let routes = app_routes(&config).route("/panic", get(|| async { panic!("deliberate panic") as &'static str }));
+        let app = layer_routes(routes, connection, &config);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        /* the connection is answered rather than aborted */
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /panic HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(response.ends_with("Internal server error."));
+        /* the panic is caught inside merkle_headers, so the error response still carries the headers */
+        assert!(response.contains(&format!("{}: 8\r\n", LEAVES_HEADER)));
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<MerkleTree<32, Sha256Algorithm>>();
+        assert_send_sync::<InMemoryDatabase<32, Sha256Algorithm>>();
+        assert_send_sync::<InMemoryDatabase<32, Sha256Algorithm, MmrTree<32, Sha256Algorithm>>>();
+        assert_send_sync::<Connection>();
+    }
+
+    #[test]
+    fn test_build_runtime() {
+        let config = Config { worker_threads: 3, ..Config::default() };
+        let runtime = build_runtime(&config).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 3);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = ["--snapshot", "db.json", "--save-snapshot", "out.json"].map(String::from);
+        let config = parse_args(args.into_iter()).unwrap();
+        assert_eq!(config.snapshot, Some("db.json".to_string()));
+        assert_eq!(config.save_snapshot, Some("out.json".to_string()));
+        assert_eq!(config.max_body_size, DEFAULT_MAX_BODY_SIZE);
+        let config = parse_args(["--max-body-size", "1024"].map(String::from).into_iter()).unwrap();
+        assert_eq!(config.max_body_size, 1024);
+        assert!(parse_args(["--max-body-size", "lots"].map(String::from).into_iter()).is_err());
+        assert_eq!(Config::default().max_body_time, DEFAULT_MAX_BODY_TIME);
+        let config = parse_args(["--max-body-time", "5"].map(String::from).into_iter()).unwrap();
+        assert_eq!(config.max_body_time, Duration::from_secs(5));
+        assert!(parse_args(["--max-body-time", "0"].map(String::from).into_iter()).is_err());
+        let config = parse_args(["--worker-threads", "3"].map(String::from).into_iter()).unwrap();
+        assert_eq!(config.worker_threads, 3);
+        assert!(parse_args(["--worker-threads", "0"].map(String::from).into_iter()).is_err());
+        assert!(Config::default().worker_threads >= 1);
+        assert_eq!(Config::default().mode, Mode::ReadOnly);
+        assert!(!Config::default().transparency);
+        assert!(parse_args(["--transparency"].map(String::from).into_iter()).unwrap().transparency);
+        assert_eq!(parse_args(["--writable"].map(String::from).into_iter()).unwrap().mode, Mode::Writable);
+        assert_eq!(parse_args(["--writable", "--read-only"].map(String::from).into_iter()).unwrap().mode, Mode::ReadOnly);
+        assert_eq!(parse_args(std::iter::empty()).unwrap().snapshot, None);
+        assert!(parse_args(["--snapshot".to_string()].into_iter()).is_err());
+        assert!(parse_args(["--bogus".to_string()].into_iter()).is_err());
+        let path = std::env::temp_dir().join(format!("merkle-signing-key-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", HEXLOWER.encode(&[7; 32]))).unwrap();
+        let config = parse_args(["--signing-key", path.to_str().unwrap()].map(String::from).into_iter());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().signing_key.unwrap().to_bytes(), [7; 32]);
+        assert!(parse_args(["--signing-key", "/nonexistent/key"].map(String::from).into_iter()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("merkle-audit-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = parse_args(["--audit-log", path.to_str().unwrap()].map(String::from).into_iter()).unwrap();
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app_with_config(connection.clone(), &config);
+        let get = |uri: &'static str| app.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap());
+        let read_log = || std::fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str::<Value>(line).unwrap()).collect::<Vec<_>>();
+        /* a served proof appends one line, flushed by the time the response is sent */
+        assert_eq!(get("/proof/3").await.unwrap().status(), StatusCode::OK);
+        let entries = read_log();
+        assert_eq!(entries.len(), 1);
+        let db = connection.snapshot();
+        let proof_hash = <sha2::Sha256 as sha2::Digest>::digest(db.get_proof(3).unwrap().to_compact());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let timestamp = entries[0]["timestamp"].as_u64().unwrap();
+        assert!(timestamp <= now && now - timestamp < 60);
+        assert_eq!(entries[0], json!({
+            "timestamp": timestamp,
+            "user_id": 3,
+            "root": format!("0x{}", HEXLOWER.encode(&db.get_root().unwrap().0)),
+            "proof_hash": format!("0x{}", HEXLOWER.encode(&proof_hash))
+        }));
+        /* missing users disclose nothing, and a batch appends a line per proof */
+        assert_eq!(get("/proof/10").await.unwrap().status(), StatusCode::NOT_FOUND);
+        assert_eq!(read_log().len(), 1);
+        assert_eq!(get("/proofs?ids=1,10,2").await.unwrap().status(), StatusCode::OK);
+        let user_ids: Vec<Value> = read_log().iter().map(|entry| entry["user_id"].clone()).collect();
+        assert_eq!(user_ids, vec![json!(3), json!(1), json!(2)]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(parse_args(["--audit-log", "/nonexistent/audit.jsonl"].map(String::from).into_iter()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signed_root_api() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let config = Config { signing_key: Some(SigningKey::from_bytes(&[7; 32])), ..Config::default() };
+        let app = create_app_with_config(connection.clone(), &config);
+        let response = app.clone().oneshot(Request::builder().uri("/root/signed").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["root"], json!("0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"));
+        let decode = |field: &str| HEXLOWER.decode(body_json[field].as_str().unwrap().strip_prefix("0x").unwrap().as_bytes()).unwrap();
+        let pubkey = ed25519_dalek::VerifyingKey::from_bytes(&decode("pubkey").try_into().unwrap()).unwrap();
+        assert_eq!(pubkey, SigningKey::from_bytes(&[7; 32]).verifying_key());
+        /* the signature covers the root followed by the big-endian epoch and timestamp */
+        assert_eq!(body_json["epoch"], json!(0));
+        let timestamp = body_json["timestamp"].as_u64().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(timestamp <= now && now - timestamp < 60);
+        let signature = ed25519_dalek::Signature::from_slice(&decode("signature")).unwrap();
+        let mut payload = decode("root");
+        payload.extend_from_slice(&0u64.to_be_bytes());
+        payload.extend_from_slice(&timestamp.to_be_bytes());
+        assert!(pubkey.verify_strict(&payload, &signature).is_ok());
+        payload[40..].copy_from_slice(&(timestamp + 1).to_be_bytes());
+        assert!(pubkey.verify_strict(&payload, &signature).is_err());
+        /* after an update, the old attestation is identifiable as outdated, and its epoch cannot be bumped */
+        connection.update(|db| db.set_balance(9, 9999));
+        let response = app.clone().oneshot(Request::builder().uri("/params").body(Body::empty()).unwrap()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let params: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(params["epoch"], json!(1));
+        assert!(body_json["epoch"].as_u64().unwrap() < params["epoch"].as_u64().unwrap());
+        payload[40..].copy_from_slice(&timestamp.to_be_bytes());
+        payload[32..40].copy_from_slice(&1u64.to_be_bytes());
+        assert!(pubkey.verify_strict(&payload, &signature).is_err());
+        let response = app.oneshot(Request::builder().uri("/root/signed").body(Body::empty()).unwrap()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let current: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(current["epoch"], params["epoch"]);
+        assert_ne!(current["root"], body_json["root"]);
+        /* without a key, no attestation is served */
+        let app = create_app(Arc::new(OnlineDatabase::new(create_test_db())));
+        let response = app.oneshot(Request::builder().uri("/root/signed").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_root_api_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("merkle-snapshot-{}.json", std::process::id()));
+        create_test_db().save_snapshot(&path).unwrap();
+        let config = Config { snapshot: Some(path.to_str().unwrap().to_string()), ..Config::default() };
+        let db = create_db(&config);
+        std::fs::remove_file(&path).unwrap();
+
+        let app = create_app(Arc::new(db));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/root")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!("0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"))
+    }
+
+    #[test]
+    fn test_snapshot_epoch() {
+        let path = std::env::temp_dir().join(format!("merkle-snapshot-epoch-{}.json", std::process::id()));
+        let online = OnlineDatabase::new(create_test_db());
+        online.update(|db| db.set_balance(3, 3000));
+        online.update(|db| db.set_balance(9, 9999));
+        online.save_snapshot(&path).unwrap();
+        /* the loaded database carries on from the saved epoch */
+        let loaded = OnlineDatabase::<InMemoryDatabase<32, Sha256Algorithm>>::load_snapshot(&path).unwrap();
+        assert_eq!((loaded.epoch(), loaded.snapshot().get_root().unwrap()), (2, online.snapshot().get_root().unwrap()));
+        loaded.update(|db| db.set_balance(4, 4000));
+        assert_eq!(loaded.epoch(), 3);
+        /* snapshots of a bare database (or without an epoch) start at epoch 0 */
+        create_test_db().save_snapshot(&path).unwrap();
+        let loaded = OnlineDatabase::<InMemoryDatabase<32, Sha256Algorithm>>::load_snapshot(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.epoch(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_root_mismatch() {
+        let path = std::env::temp_dir().join(format!("merkle-snapshot-tampered-{}.json", std::process::id()));
+        create_test_db().save_snapshot(&path).unwrap();
+        /* tamper with a balance without updating the recorded root */
+        let tampered = std::fs::read_to_string(&path).unwrap().replace("[1,1111]", "[1,1112]");
+        std::fs::write(&path, tampered).unwrap();
+        let result = InMemoryDatabase::<32, Sha256Algorithm>::load_snapshot(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(db::SnapshotError::RootMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_object_format() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/1?format=object")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json["balance"], json!(1111));
+        let proof = body_json["proof"].as_array().unwrap();
+        assert_eq!(proof.len(), 3);
+        /* user 1 is the leftmost leaf, so all its siblings are on the right */
+        for item in proof {
+            assert_eq!(item["position"], json!("right"));
+            assert!(item["hash"].as_str().unwrap().starts_with("0x"));
+        }
+    }
+
+    #[test]
+    fn test_verify_user() {
+        let mut db = create_test_db();
+        for (user_id, _) in TEST_DATA {
+            assert_eq!(db.verify_user(user_id), Ok(true));
+        }
+        assert_eq!(db.verify_user(42), Err(ProofError::UserNotFound(42)));
+        db.remove_user(3);
+        assert_eq!(db.verify_user(3), Err(ProofError::UserNotFound(3)));
+        assert_eq!(db.verify_user(4), Ok(true));
+    }
+
+    #[test]
+    fn test_leaf_index() {
+        let mut db = create_test_db();
+        assert_eq!((db.leaf_index(1), db.leaf_index(8), db.leaf_index(42)), (Some(0), Some(7), None));
+        /* the leaves after a removed user move down, and added users go last */
+        db.remove_user(3);
+        db.set_balance(42, 4242);
+        assert_eq!((db.leaf_index(3), db.leaf_index(4), db.leaf_index(42)), (None, Some(2), Some(7)));
+        db.set_balance(4, 4000);
+        assert_eq!(db.leaf_index(4), Some(2));
+    }
+
+    #[test]
+    fn test_find_user_by_leaf_hash() {
+        let mut db = create_test_db();
+        let leaf_hash = |user_id, balance| Sha256Algorithm::tagged_hash(LEAF_TAG, &db::serialize_user(user_id, balance));
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(3, 3333)), Some(3));
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(3, 3000)), None);
+        /* the index follows updates, additions and removals */
+        db.set_balance(3, 3000);
+        db.set_balance(42, 4242);
+        db.remove_user(5);
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(3, 3333)), None);
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(3, 3000)), Some(3));
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(42, 4242)), Some(42));
+        assert_eq!(db.find_user_by_leaf_hash(&leaf_hash(5, 5555)), None);
+    }
+
+    #[test]
+    fn test_get_balance_batch() {
+        let db = create_test_db();
+        assert_eq!(db.get_balance_batch(&[3, 42, 1, 3, 0]), vec![Some(3333), None, Some(1111), Some(3333), None]);
+        assert_eq!(db.get_balance_batch(&[]), Vec::<Option<u64>>::new());
+    }
+
+    #[test]
+    fn test_total_balance_overflow() {
+        let db: InMemoryDatabase<32, Sha256Algorithm> =
+            InMemoryDatabase::create(vec![(1, u64::MAX), (2, 1)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.total_balance(DEFAULT_ASSET), None);
+        assert_eq!(create_test_db().total_balance(DEFAULT_ASSET), Some(39996));
+    }
+
+    async fn check_total_request(claimed_total: u64) -> Value {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/audit/check-total")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(json!({ "claimed_total": claimed_total }).to_string()))
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_check_total_api_matching() {
+        let body_json = check_total_request(39996).await;
+        assert_eq!(body_json, json!({ "computed_total": 39996, "claimed_total": 39996, "matches": true }));
+    }
+
+    #[tokio::test]
+    async fn test_check_total_api_mismatching() {
+        let body_json = check_total_request(40000).await;
+        assert_eq!(body_json, json!({ "computed_total": 39996, "claimed_total": 40000, "matches": false }));
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let config = Config { max_body_size: 64, ..Config::default() };
+        let app = create_app_with_config(connection, &config);
+        /* a valid request padded with whitespace beyond the limit */
+        let body = format!("{{ \"claimed_total\": 39996 }}{}", " ".repeat(64));
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/audit/check-total")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_body_time_limit() {
+        /* a body that sends its first bytes, and then nothing while the sender is kept */
+        let (mut sender, slow_body) = http_body_util::channel::Channel::<Bytes>::new(1);
+        sender.send_data(Bytes::from_static(b"{ \"claimed_total\"")).await.unwrap();
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let config = Config { max_body_time: Duration::from_millis(100), ..Config::default() };
+        let app = create_app_with_config(connection, &config);
+        let post = |body: Body| app.clone().oneshot(Request::builder()
+            .method(http::Method::POST)
+            .uri("/audit/check-total")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap());
+        let start = Instant::now();
+        let response = post(Body::new(slow_body)).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.headers()[http::header::CONNECTION], "close");
+        /* a body sent in time is served as before */
+        let response = post(Body::from("{ \"claimed_total\": 39996 }")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(sender);
+    }
+
+    #[test]
+    fn test_leaf_version_changes_root() {
+        let unversioned: Vec<Vec<u8>> = TEST_DATA.iter().map(|(id, balance)| format!("({},{})", id, balance).into_bytes()).collect();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(unversioned, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        /* this is the root before leaves were versioned */
+        assert_eq!(HEXLOWER.encode(&tree.get_root().0), "b1231de33da17c23cebd80c104b88198e0914b0463d0e14db163605b904a7ba3");
+        assert_ne!(create_test_db().get_root().unwrap().0, tree.get_root().0);
+        assert_eq!(db::serialize_user(1, 1111), b"\x01(1,1111)");
+    }
+
+    #[tokio::test]
+    async fn test_params_api() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/params")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body_json, json!({
+            "hash_algorithm": "sha256",
+            "leaf_version": 1,
+            "leaf_type": null,
+            "leaf_tag": format!("0x{}", HEXLOWER.encode(LEAF_TAG)),
+            "branch_tag": format!("0x{}", HEXLOWER.encode(BRANCH_TAG)),
+            "padding": "duplicate",
+            "sorted_pairs": false,
+            "epoch": 0
+        }));
+        /* the options of the database are reported, not the defaults */
+        let db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+            .leaf_tag(LEAF_TAG.to_vec())
+            .branch_tag(BRANCH_TAG.to_vec())
+            .padding(Padding::Promote)
+            .sorted_pairs(true)
+            .build(TEST_DATA.to_vec());
+        let app = create_app(Arc::new(OnlineDatabase::new(db)));
+        let response = app.oneshot(Request::builder().uri("/params").body(Body::empty()).unwrap()).await.unwrap();
+        let body_json: Value = serde_json::from_slice(&response.into_body().collect().await.unwrap().to_bytes()).unwrap();
+        assert_eq!((&body_json["padding"], &body_json["sorted_pairs"]), (&json!("promote"), &json!(true)));
+    }
+
+    #[test]
+    fn test_fixed_width_leaves() {
+        assert_eq!(db::serialize_user_fixed(1, 1111), [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x04, 0x57]);
+        /* every leaf is 16 bytes, however large the balance */
+        for balance in [0, 9, 10, u64::MAX] {
+            assert_eq!(db::serialize_user_fixed(u64::MAX, balance).len(), 16);
+        }
+        let mut db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+            .leaf_tag(LEAF_TAG.to_vec())
+            .branch_tag(BRANCH_TAG.to_vec())
+            .leaf_encoder(db::serialize_user_fixed)
+            .build(TEST_DATA.to_vec());
+        /* a stable root, computed independently with hashlib */
+        assert_eq!(HEXLOWER.encode(&db.get_root().unwrap().0), "976b4780434dda6c4c7dfd50a26a9f6477e4b6bb1ae310f8bfaa9b3780124000");
+        assert_eq!(db.export_leaves_with_values()[0].3, Sha256Algorithm::tagged_hash(LEAF_TAG, &1u64.to_be_bytes().into_iter().chain(1111u64.to_be_bytes()).collect::<Vec<_>>()));
+        db.set_balance(3, u64::MAX);
+        let proof = db.get_proof(3).unwrap();
+        assert!(db.verify_proof(3, u64::MAX, &proof));
+        assert!(verify_root::<32, Sha256Algorithm>(&db::serialize_user_fixed(3, u64::MAX), &proof, 2, 8, &db.get_root().unwrap(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+    }
+
+    #[tokio::test]
+    async fn test_leaf_type() {
+        let db = create_typed_test_db(db::USER_LEAF_TYPE);
+        let untyped = create_test_db();
+        assert_eq!(db.leaf_type(), Some(0));
+        assert_eq!(untyped.leaf_type(), None);
+        /* the type byte comes first, so it changes every leaf hash, and the root */
+        let typed_leaf = [&[db::USER_LEAF_TYPE][..], &db::serialize_user(1, 1111)].concat();
+        assert_eq!(db.export_leaves_with_values()[0].3, Sha256Algorithm::tagged_hash(LEAF_TAG, &typed_leaf));
+        for (typed, untyped) in db.export_leaves_with_values().iter().zip(untyped.export_leaves_with_values()) {
+            assert_ne!(typed.3, untyped.3);
+        }
+        assert_ne!(db.get_root(), untyped.get_root());
+        /* proofs of the typed leaves verify, and updates keep the type byte */
+        let mut db = db;
+        for (user_id, balance) in TEST_DATA {
+            assert!(db.verify_proof(user_id, balance, &db.get_proof(user_id).unwrap()));
+        }
+        db.set_balance(9, 9999);
+        assert!(db.verify_proof(9, 9999, &db.get_proof(9).unwrap()));
+        assert_eq!(db.verify_user(9), Ok(true));
+        /* and the type byte is reported in /params */
+        let app = create_app(Arc::new(OnlineDatabase::new(db)));
+        let response = app.oneshot(Request::builder().uri("/params").body(Body::empty()).unwrap()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["leaf_type"], json!(0));
+    }
+
+    #[test]
+    fn test_leaf_type_config() {
+        let config = parse_args(["--leaf-type", "0"].map(String::from).into_iter()).unwrap();
+        assert_eq!(config.leaf_type, Some(db::USER_LEAF_TYPE));
+        assert!(parse_args(["--leaf-type", "256"].map(String::from).into_iter()).is_err());
+        assert!(parse_args(["--leaf-type".to_string()].into_iter()).is_err());
+        let db = create_db(&config).snapshot();
+        assert_eq!(db.get_root(), create_typed_test_db(db::USER_LEAF_TYPE).get_root());
+        /* the leaf type is kept in snapshots, so a typed tree is rebuilt with the same root */
+        let path = std::env::temp_dir().join(format!("merkle-snapshot-leaf-type-{}.json", std::process::id()));
+        db.save_snapshot(&path).unwrap();
+        let loaded = create_db(&Config { snapshot: Some(path.to_str().unwrap().to_string()), ..Config::default() }).snapshot();
+        assert_eq!((loaded.leaf_type(), loaded.get_root()), (Some(db::USER_LEAF_TYPE), db.get_root()));
+        /* but a conflicting --leaf-type is refused */
+        let conflicting = Config { snapshot: Some(path.to_str().unwrap().to_string()), leaf_type: Some(1), ..Config::default() };
+        let result = std::panic::catch_unwind(|| create_db(&conflicting));
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stats_api() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app(connection.clone());
+        let get_stats = || async {
+            let response = app.clone().oneshot(Request::builder().uri("/stats").body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+        /* 8 leaves make 4 layers of 8, 4, 2 and 1 hashes */
+        let stats = get_stats().await;
+        assert_eq!(stats["num_leaves"], 8);
+        assert_eq!(stats["num_layers"], 4);
+        assert_eq!(stats["memory_bytes"], 15 * 32);
+        assert!(stats["build_time_ms"].as_f64().unwrap() > 0.0);
+        assert_eq!(stats["last_update"], Value::Null);
+        /* an update is reported, and the appended leaf adds a layer */
+        connection.update(|db| db.set_balance(9, 9999));
+        let stats = get_stats().await;
+        assert_eq!(stats["num_layers"], 5);
+        assert!(stats["last_update"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_mutable_database() {
+        let mut db = create_test_db();
+        /* updating a balance rehashes the path, and matches a database built with the new balance */
+        db.set_balance(3, 3000);
+        let mut expected = TEST_DATA.to_vec();
+        expected[2] = (3, 3000);
+        let expected_db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(expected.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), expected_db.get_root());
+        assert_eq!(db.get_balance(3, DEFAULT_ASSET), Some(3000));
+        /* adding a user appends a leaf */
+        db.set_balance(9, 9999);
+        expected.push((9, 9999));
+        let expected_db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(expected.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), expected_db.get_root());
+        /* updating the lone last leaf of a padded tree */
+        db.set_balance(9, 9000);
+        expected[8] = (9, 9000);
+        let expected_db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(expected.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), expected_db.get_root());
+        /* removing a user */
+        assert_eq!(db.remove_user(1), Some(HashMap::from([(DEFAULT_ASSET, 1111)])));
+        assert_eq!(db.remove_user(1), None);
+        expected.remove(0);
+        let expected_db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(expected.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), expected_db.get_root());
+        for (user_id, balance) in expected {
+            assert!(db.verify_proof(user_id, balance, &db.get_proof(user_id).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_absence_proof() {
+        let db: InMemoryDatabase<32, Sha256Algorithm> =
+            InMemoryDatabase::create(vec![(10, 1000), (20, 2000), (30, 3000), (40, 4000), (50, 5000)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let verify = |proof: &db::AbsenceProof<32>, user_id| {
+            proof.verify::<Sha256Algorithm>(user_id, &db.get_root().unwrap(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate, db::serialize_user)
+        };
+        /* an ID between two existing IDs is bracketed by both */
+        let proof = db.get_absence_proof(25).unwrap();
+        assert_eq!((proof.lower.as_ref().unwrap().user_id, proof.upper.as_ref().unwrap().user_id), (20, 30));
+        assert!(verify(&proof, 25));
+        /* the proof does not cover IDs outside the gap */
+        assert!(!verify(&proof, 35));
+        assert!(!verify(&proof, 20));
+        /* IDs beyond either end are bracketed by a single leaf */
+        for user_id in [5, 55] {
+            assert!(verify(&db.get_absence_proof(user_id).unwrap(), user_id));
+        }
+        /* present users have no absence proof */
+        assert!(db.get_absence_proof(30).is_none());
+
+        /* leaves that are not adjacent do not prove a gap */
+        let mut proof = db.get_absence_proof(25).unwrap();
+        proof.upper = db.get_absence_proof(35).unwrap().upper;
+        assert!(!verify(&proof, 25));
+
+        /* neither do unsorted leaves */
+        let db: InMemoryDatabase<32, Sha256Algorithm> =
+            InMemoryDatabase::create(vec![(30, 3000), (10, 1000)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert!(db.get_absence_proof(20).is_none());
+    }
+
+    #[test]
+    fn test_export_leaves_with_values() {
+        let rows = create_test_db().export_leaves_with_values();
+        assert_eq!(rows.len(), TEST_DATA.len());
+        for (row, (index, (user_id, balance))) in rows.iter().zip(TEST_DATA.iter().enumerate()) {
+            assert_eq!((row.0, row.1, row.2), (index, *user_id, *balance));
+            assert_eq!(row.3, Sha256Algorithm::tagged_hash(LEAF_TAG, &db::serialize_user(*user_id, *balance)));
+        }
+        /* the hashes are the leaves of the tree */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(
+            TEST_DATA.iter().map(|(user_id, balance)| db::serialize_user(*user_id, *balance)).collect(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(rows.iter().map(|row| row.3).collect::<Vec<_>>(), tree.layers()[0]);
+    }
+
+    #[tokio::test]
+    async fn test_transparency_api() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        for (transparency, status) in [(false, StatusCode::NOT_FOUND), (true, StatusCode::OK)] {
+            let config = Config { transparency, ..Config::default() };
+            let app = create_app_with_config(connection.clone(), &config);
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri("/transparency")
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), status);
+            if transparency {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let body_json: Value = serde_json::from_slice(&body).unwrap();
+                let leaf_hash = format!("0x{}", HEXLOWER.encode(&Sha256Algorithm::tagged_hash(LEAF_TAG, &db::serialize_user(1, 1111))));
+                assert_eq!(body_json.as_array().unwrap().len(), 8);
+                assert_eq!(body_json[0], json!({ "index": 0, "user_id": 1, "balance": 1111, "leaf_hash": leaf_hash }));
+            }
+        }
+    }
+
+    #[test]
+    fn test_database_diff() {
+        let yesterday = create_test_db();
+        let mut today = create_test_db();
+        today.set_balance(2, 2000);
+        today.set_balance(7, 8000);
+        today.remove_user(5);
+        today.set_balance(10, 1010);
+        today.set_balance(9, 999);
+        let diff = yesterday.diff(&today);
+        assert_eq!(diff.added, vec![(9, 999), (10, 1010)]);
+        assert_eq!(diff.removed, vec![(5, 5555)]);
+        assert_eq!(diff.changed.iter().map(|change| (change.user_id, change.delta())).collect::<Vec<_>>(), vec![(2, -222), (7, 223)]);
+        assert_eq!(diff.changed[0], db::BalanceChange { user_id: 2, old_balance: 2222, new_balance: 2000 });
+        /* the other way around */
+        let diff = today.diff(&yesterday);
+        assert_eq!(diff.removed, vec![(9, 999), (10, 1010)]);
+        assert_eq!(diff.added, vec![(5, 5555)]);
+        assert_eq!(yesterday.diff(&create_test_db()), db::DbDiff::default());
+    }
+
+    #[test]
+    fn test_mutable_mmr_database() {
+        let mut db: InMemoryDatabase<32, Sha256Algorithm, MmrTree<32, Sha256Algorithm>> =
+            InMemoryDatabase::create(TEST_DATA[..5].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        db.set_balance(6, 6666);
+        db.set_balance(2, 2000);
+        let expected = vec![(1, 1111), (2, 2000), (3, 3333), (4, 4444), (5, 5555), (6, 6666)];
+        let expected_db: InMemoryDatabase<32, Sha256Algorithm, MmrTree<32, Sha256Algorithm>> =
+            InMemoryDatabase::create(expected.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        assert_eq!(db.get_root(), expected_db.get_root());
+        for (user_id, balance) in expected {
+            assert!(db.verify_proof(user_id, balance, &db.get_proof(user_id).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_online_database_history() {
+        let online = OnlineDatabase::with_history(create_test_db(), 2);
+        let old_root = online.snapshot().get_root().unwrap();
+        online.update(|db| db.set_balance(3, 3000));
+        online.update(|db| db.remove_user(5));
+        /* a proof at the first epoch verifies against its root after the later updates, but not against the current one */
+        let proof = online.get_proof_at(3, 0).unwrap();
+        let value = db::serialize_user(3, 3333);
+        assert!(verify_root::<32, Sha256Algorithm>(&value, &proof, 2, 8, &old_root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        assert!(!verify_root::<32, Sha256Algorithm>(&value, &proof, 2, 8, &online.snapshot().get_root().unwrap(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+        assert_eq!(online.get_proof_at(3, 0), create_test_db().get_proof(3));
+        /* removed users have proofs at the epochs where they still existed */
+        assert!(online.get_proof_at(5, 1).is_some());
+        assert_eq!(online.get_proof_at(5, 2), None);
+        assert_eq!(online.get_proof_at(3, 2), online.snapshot().get_proof(3));
+        /* a failed update publishes nothing, so it does not push out a kept state */
+        assert_eq!(online.try_update(|db| db.remove_user(42).ok_or("not found")), Err("not found"));
+        assert_eq!((online.epoch(), online.snapshot_at(0).is_some()), (2, true));
+        /* only the last 2 replaced states are kept, and there is no state in the future */
+        online.update(|db| db.set_balance(9, 9999));
+        assert!(online.snapshot_at(0).is_none());
+        assert_eq!(online.snapshot_at(1).unwrap().get_balance(3, DEFAULT_ASSET), Some(3000));
+        assert!(online.snapshot_at(4).is_none());
+        /* without history, only the current state can be proven against */
+        let online = OnlineDatabase::new(create_test_db());
+        online.update(|db| db.set_balance(3, 3000));
+        assert_eq!(online.get_proof_at(3, 0), None);
+        assert!(online.get_proof_at(3, 1).is_some());
+    }
+
+    #[test]
+    fn test_online_database_in_place_updates() {
+        /* counts how often the database is copied */
+        struct Counted(u64, Arc<std::sync::atomic::AtomicUsize>);
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Counted(self.0, self.1.clone())
+            }
         }
+        let clones = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = || clones.load(std::sync::atomic::Ordering::Relaxed);
+        let online = OnlineDatabase::new(Counted(0, clones.clone()));
+        /* only the first update copies the database, the later ones reuse the replaced state */
+        for _ in 0..5 {
+            online.update(|db| db.0 += 1);
+        }
+        assert_eq!((online.snapshot().0, count()), (5, 1));
+        /* a replaced state still held by a reader is not reused */
+        let held = online.snapshot();
+        online.update(|db| db.0 += 1);
+        online.update(|db| db.0 += 1);
+        assert_eq!((held.0, online.snapshot().0, count()), (5, 7, 2));
+        /* a panicking update is not published, and does not prevent later updates */
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| online.update(|db| { db.0 += 100; panic!("update failed") })));
+        assert!(panicked.is_err());
+        assert_eq!((online.snapshot().0, online.epoch()), (7, 7));
+        online.update(|db| db.0 += 1);
+        assert_eq!((online.snapshot().0, online.epoch()), (8, 8));
     }
-}
-
-impl From<u64> for Error {
-    fn from(user_id: u64) -> Self {
-        Error::UserNotFound(user_id)
-    }
-}
 
-async fn get_root(State(db): State<Arc<InMemoryDatabase<32, Sha256Algorithm>>>) -> Json<MerkleRoot<32>> {
-    let root = db.get_root();
-    Json(root)
-}
-
-#[derive(Serialize)]
-struct ProofResponse {
-    balance: u64,
-    proof: MerkleProof<32>,
-}
+    #[test]
+    fn test_online_database_concurrent_reads() {
+        let online = OnlineDatabase::new(create_test_db());
+        let updates: Vec<(u64, u64)> = (0..20).map(|i| (i % 8 + 1, 10000 + i)).collect();
+        /* the roots of every state the database goes through */
+        let mut db = create_test_db();
+        let mut valid_roots = vec![db.get_root().unwrap().0];
+        for (user_id, balance) in &updates {
+            db.set_balance(*user_id, *balance);
+            valid_roots.push(db.get_root().unwrap().0);
+        }
 
-#[debug_handler(state = Arc<InMemoryDatabase<32, Sha256Algorithm>>)]
-async fn get_proof(
-    State(db): State<Arc<InMemoryDatabase<32, Sha256Algorithm>>>,
-    Path(user_id): Path<u64>
-) -> Result<Json<ProofResponse>, Error> {
-    let balance = db.get_balance(user_id).ok_or(Error::UserNotFound(user_id))?;
-    let proof = db.get_proof(user_id).unwrap();
-    Ok(Json(ProofResponse { balance, proof }))
-}
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        let snapshot = online.snapshot();
+                        let root = snapshot.get_root().unwrap();
+                        /* the root is that of a complete state, and agrees with the data of the same snapshot */
+                        assert!(valid_roots.contains(&root.0));
+                        for user_id in 1..=8 {
+                            let balance = snapshot.get_balance(user_id, DEFAULT_ASSET).unwrap();
+                            let proof = snapshot.get_proof(user_id).unwrap();
+                            assert!(snapshot.verify_proof(user_id, balance, &proof));
+                        }
+                    }
+                });
+            }
+            for (user_id, balance) in &updates {
+                online.update(|db| db.set_balance(*user_id, *balance));
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        assert_eq!(online.snapshot().get_root().unwrap().0, *valid_roots.last().unwrap());
+    }
 
-fn create_app(connection: Arc<InMemoryDatabase<32, Sha256Algorithm>>) -> Router {
-    Router::new()
-        .route("/root", get(get_root))
-        .route("/proof/{id}", get(get_proof))
-        .with_state(connection)
-}
+    /* the handlers only read shared snapshots, so any number of requests may be served concurrently */
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_requests() {
+        let db = create_test_db();
+        let root = db.get_root().unwrap();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..200u64 {
+            let app = create_app(connection.clone());
+            let uri = if i % 2 == 0 { "/root".to_string() } else { format!("/proof/{}", i % 8 + 1) };
+            tasks.spawn(async move {
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .method(http::Method::GET)
+                            .uri(&uri)
+                            .body(Body::empty())
+                            .unwrap()
+                    ).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (uri, serde_json::from_slice::<Value>(&body).unwrap())
+            });
+        }
+        let db = connection.snapshot();
+        let expected_root = serde_json::to_value(&root).unwrap();
+        let mut count = 0;
+        while let Some(result) = tasks.join_next().await {
+            let (uri, body_json) = result.unwrap();
+            match uri.strip_prefix("/proof/") {
+                /* every proof verifies against the same root */
+                Some(user_id) => {
+                    let user_id: u64 = user_id.parse().unwrap();
+                    let proof = serde_json::from_value::<Vec<(u64, String)>>(body_json["proof"].clone()).unwrap();
+                    let items = proof.iter().map(|(direction, hash)| MerkleProofItem::from_parts(*direction, hash).unwrap()).collect();
+                    assert!(merkle::verify_root::<32, Sha256Algorithm>(&db::serialize_user(user_id, user_id * 1111), &MerkleProof(items),
+                        user_id as usize - 1, 8, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+                }
+                None => assert_eq!(body_json, expected_root)
+            }
+            count += 1;
+        }
+        assert_eq!(count, 200);
+        assert_eq!(db.get_root().unwrap().0, root.0);
+    }
 
-const TEST_DATA: [(u64, u64); 8] = [(1, 1111), (2, 2222), (3, 3333), (4, 4444), (5, 5555), (6, 6666), (7, 7777), (8, 8888)];
-const LEAF_TAG: &[u8; 19] = b"ProofOfReserve_Leaf";
-const BRANCH_TAG: &[u8; 21] = b"ProofOfReserve_Branch";
+    #[test]
+    fn test_compact_proof_malformed() {
+        let proof = create_test_db().get_proof(1).unwrap();
+        let compact = proof.to_compact();
+        assert_eq!(compact.len(), 4 + 3 * 33);
+        assert_eq!(MerkleProof::<32>::from_compact(&compact), Some(proof));
+        /* truncated */
+        assert_eq!(MerkleProof::<32>::from_compact(&compact[..compact.len() - 1]), None);
+        /* bad direction byte */
+        let mut bad_direction = compact.clone();
+        bad_direction[4] = 2;
+        assert_eq!(MerkleProof::<32>::from_compact(&bad_direction), None);
+    }
 
-fn create_test_db() -> InMemoryDatabase<32, Sha256Algorithm> {
-    InMemoryDatabase::create(TEST_DATA.to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())
-}
+    #[test]
+    fn test_verify_with_policy() {
+        use db::{verify_with_policy, VerifyResult};
+        let db = create_test_db();
+        let root = db.get_root().unwrap();
+        let verify = |user_id: u64, balance: u64, min: u64, max: u64| {
+            let proof = db.get_proof(user_id).unwrap();
+            verify_with_policy::<32, Sha256Algorithm>(&db::serialize_user(user_id, balance), &proof, user_id as usize - 1, 8, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate, min, max)
+        };
+        /* in range, including the bounds */
+        assert_eq!(verify(3, 3333, 1, 10000), VerifyResult::Valid);
+        assert_eq!(verify(3, 3333, 3333, 3333), VerifyResult::Valid);
+        assert!(verify(3, 3333, 1, u64::MAX).is_valid());
+        /* out of range, on either side */
+        assert_eq!(verify(3, 3333, 1, 3332), VerifyResult::OutOfRange(3333));
+        assert_eq!(verify(3, 3333, 3334, u64::MAX), VerifyResult::OutOfRange(3333));
+        /* a balance that is not the one in the tree fails inclusion, whatever the policy */
+        assert_eq!(verify(3, 5, 1, 10), VerifyResult::NotIncluded);
+        /* a zero balance fails a nonzero policy, but only after inclusion */
+        let db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::create(vec![(1, 0), (2, 7)], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = db.get_proof(1).unwrap();
+        assert_eq!(verify_with_policy::<32, Sha256Algorithm>(&db::serialize_user(1, 0), &proof, 0, 2, &db.get_root().unwrap(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate, 1, 100), VerifyResult::OutOfRange(0));
+        /* an included leaf whose balance cannot be read */
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(vec![b"\x01(1,+5)".to_vec(), b"aaa".to_vec()], LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        let proof = tree.get_proof_at(0).unwrap();
+        assert_eq!(verify_with_policy::<32, Sha256Algorithm>(b"\x01(1,+5)", &proof, 0, 2, &tree.get_root(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate, 0, 100), VerifyResult::MalformedLeaf);
+        /* deserialize_user inverts serialize_user */
+        assert_eq!(db::deserialize_user(&db::serialize_user(42, u64::MAX)), Some((42, u64::MAX)));
+        assert_eq!(db::deserialize_user(b"\x02(1,5)"), None);
+        assert_eq!(db::deserialize_user(b"\x01(1,5"), None);
+    }
 
-#[tokio::main]
-async fn main() {
-    println!("Testing the Merkle root implementation...");
-    test_merkle_root();
-    
-    let bind_address = "0.0.0.0:3000";
-    // since our database is immutable, no need to treat it as shared state
-    let db = create_test_db();
-    let connection = Arc::new(db);
-    let app = create_app(connection);
-    let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
+    #[test]
+    fn test_hex_blob_proof() {
+        let db = create_test_db();
+        for user_id in 1..=8 {
+            let proof = db.get_proof(user_id).unwrap();
+            let blob = proof.to_hex_blob();
+            /* a byte of directions and 3 hashes, in hex */
+            assert_eq!(blob.len(), 2 + 2 * (1 + 3 * 32));
+            let parsed = MerkleProof::<32>::from_hex_blob(&blob).unwrap();
+            let value = db::serialize_user(user_id, user_id * 1111);
+            assert!(verify_root::<32, Sha256Algorithm>(&value, &parsed, user_id as usize - 1, 8, &db.get_root().unwrap(), LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+            assert_eq!(parsed, proof);
+        }
+        /* the directions of the first user are all right, and the bitmap comes first */
+        let blob = db.get_proof(1).unwrap().to_hex_blob();
+        assert!(blob.starts_with("0x07"));
+        assert_eq!(MerkleProof::<32>::from_hex_blob("0x"), Some(MerkleProof(vec![])));
+        /* no prefix, a truncated hash, uppercase hex and stray bits in the bitmap are rejected */
+        assert_eq!(MerkleProof::<32>::from_hex_blob(&blob[2..]), None);
+        assert_eq!(MerkleProof::<32>::from_hex_blob(&blob[..blob.len() - 2]), None);
+        assert_eq!(MerkleProof::<32>::from_hex_blob(&blob.to_uppercase().replace("0X", "0x")), None);
+        assert_eq!(MerkleProof::<32>::from_hex_blob(&blob.replacen("07", "0f", 1)), None);
+    }
 
-    println!("Starting the server at {}...", bind_address);
-    axum::serve(listener, app).await.unwrap();
-}
+    #[cfg(feature = "ffi")]
+    #[test]
+    fn test_ffi_verify() {
+        use code_test::ffi::{merkle_verify, MERKLE_PADDING_DUPLICATE, MERKLE_PADDING_PROMOTE};
+        let db = create_test_db();
+        let leaf = db::serialize_user(6, 6666);
+        let (directions, hashes) = db.get_proof(6).unwrap().flatten();
+        let root = db.get_root().unwrap().0;
+        let verify = |leaf: &[u8], index: usize, padding: u8, root: &[u8; 32]| unsafe {
+            merkle_verify(
+                leaf.as_ptr(), leaf.len(),
+                LEAF_TAG.as_ptr(), LEAF_TAG.len(),
+                BRANCH_TAG.as_ptr(), BRANCH_TAG.len(),
+                directions.as_ptr(), hashes.as_ptr(), directions.len(),
+                index, 8, padding, root.as_ptr()
+            )
+        };
+        assert!(verify(&leaf, 5, MERKLE_PADDING_DUPLICATE, &root));
+        assert!(!verify(&db::serialize_user(6, 6667), 5, MERKLE_PADDING_DUPLICATE, &root));
+        assert!(!verify(&leaf, 4, MERKLE_PADDING_DUPLICATE, &root));
+        assert!(!verify(&leaf, 5, MERKLE_PADDING_DUPLICATE, &[0; 32]));
+        /* the padding makes no difference for a perfect tree, but must be known */
+        assert!(verify(&leaf, 5, MERKLE_PADDING_PROMOTE, &root));
+        assert!(!verify(&leaf, 5, 2, &root));
+        /* null pointers are only accepted for empty buffers */
+        let null = std::ptr::null();
+        assert!(!unsafe { merkle_verify(null, 1, null, 0, null, 0, null, null, 0, 0, 1, MERKLE_PADDING_DUPLICATE, root.as_ptr()) });
+        assert!(!unsafe { merkle_verify(leaf.as_ptr(), leaf.len(), null, 0, null, 0, null, null, 0, 0, 1, MERKLE_PADDING_DUPLICATE, null) });
+        let tree = MerkleTree::<32, Sha256Algorithm>::build(vec![leaf.clone()], Vec::new(), Vec::new());
+        assert!(unsafe { merkle_verify(leaf.as_ptr(), leaf.len(), null, 0, null, 0, null, null, 0, 0, 1, MERKLE_PADDING_DUPLICATE, tree.root_ref().as_ptr()) });
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::{extract::Request, http, body::Body};
-    use merkle::MerkleProofItem;
-    use data_encoding::HEXLOWER;
-    use serde_json::{json, Value};
-    use tower::ServiceExt;
-    use http_body_util::BodyExt;
+    #[test]
+    fn test_audit_path() {
+        let values = user_leaves(&[1, 2, 3, 4, 5, 6, 7]);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_with_padding(values.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote);
+        let proof = tree.get_proof(values[4].clone()).unwrap();
+        let audit_path = proof.to_audit_path(4, 7);
+        let layers = tree.layers();
+        assert_eq!(audit_path.audit_path, vec![layers[0][5], layers[1][3], layers[2][0]]);
+        let base64 = |hash: &[u8; 32]| data_encoding::BASE64.encode(hash);
+        assert_eq!(serde_json::to_value(&audit_path).unwrap(), json!({
+            "leaf_index": 4,
+            "tree_size": 7,
+            "audit_path": [base64(&layers[0][5]), base64(&layers[1][3]), base64(&layers[2][0])]
+        }));
+        /* the directions are implied by the index and the size */
+        assert_eq!(audit_path.to_proof(), Some(proof));
+        let lone = tree.get_proof(values[6].clone()).unwrap();
+        assert_eq!(lone.to_audit_path(6, 7).to_proof(), Some(lone));
+        assert_eq!(audit_path.to_proof().unwrap().to_audit_path(4, 5).to_proof(), None);
+        assert_eq!(AuditPath::<32> { leaf_index: 7, tree_size: 7, audit_path: Vec::new() }.to_proof(), None);
+    }
 
     #[test]
-    fn test_merkle_root() {
-        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
-        let tag = (b"Bitcoin_Transaction").to_vec();
-        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
-        let root = tree.get_root();
-        let root_hex = HEXLOWER.encode(&root.0);
-        assert_eq!(root_hex, "4aa906745f72053498ecc74f79813370a4fe04f85e09421df2d5ef760dfa94b5");
+    fn test_direction_constants() {
+        use merkle::{LEFT, RIGHT};
+        let proof = create_test_db().get_proof(6).unwrap();
+        let expected = [LEFT, RIGHT, LEFT];
+        /* the JSON, compact and flattened forms all encode the directions with the constants */
+        let json = serde_json::to_value(&proof).unwrap();
+        let json_directions: Vec<u8> = json.as_array().unwrap().iter().map(|item| item[0].as_u64().unwrap() as u8).collect();
+        assert_eq!(json_directions, expected);
+        let compact = proof.to_compact();
+        let compact_directions: Vec<u8> = compact[4..].chunks(33).map(|item| item[0]).collect();
+        assert_eq!(compact_directions, expected);
+        assert_eq!(proof.flatten().0, expected);
+        for item in &proof.0 {
+            let (direction, hash) = item.to_parts();
+            assert_eq!(MerkleProofItem::from_direction(direction, *hash).as_ref(), Some(item));
+        }
+        assert_eq!(MerkleProofItem::<32>::from_direction(2, [0; 32]), None);
+        assert_eq!(MerkleProofItem::<32>::from_parts(256, &format!("0x{}", "00".repeat(32))), Err(ProofItemError::BadDirection(256)));
     }
 
     #[test]
-    fn test_merkle_proof_regular() {
-        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
-        let tag = (b"Bitcoin_Transaction").to_vec();
-        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
-        match tree.get_proof(b"aaa".to_vec()) {
-            Some(MerkleProof(items)) => {
-                assert_eq!(items.len(), 3);
-                assert!(matches!(items[0], MerkleProofItem::Right(_)));
-                assert!(matches!(items[1], MerkleProofItem::Right(_)));
-                assert!(matches!(items[2], MerkleProofItem::Right(_)));
-            }
-            None => {
-                panic!("Anomaly! The proof of a leaf in the tree shouldn't be None.")
+    fn test_flattened_proof() {
+        let proof = create_test_db().get_proof(6).unwrap();
+        let (directions, hashes) = proof.flatten();
+        assert_eq!(directions, vec![0, 1, 0]);
+        assert_eq!(hashes.len(), 3 * 32);
+        for (item, hash) in proof.0.iter().zip(hashes.chunks(32)) {
+            match item {
+                MerkleProofItem::Left(sibling) | MerkleProofItem::Right(sibling) => assert_eq!(sibling, hash)
             }
         }
+        assert_eq!(MerkleProof::<32>::from_flattened(&directions, &hashes), Some(proof));
+        /* the hashes must be one per direction, and the directions 0 or 1 */
+        assert_eq!(MerkleProof::<32>::from_flattened(&directions[..2], &hashes), None);
+        assert_eq!(MerkleProof::<32>::from_flattened(&[0, 1, 2], &hashes), None);
+        assert_eq!(MerkleProof::<32>::from_flattened(&[], &[]), Some(MerkleProof(Vec::new())));
     }
 
-    #[test]
-    fn test_merkle_proof_pad() {
-        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
-        let tag = (b"Bitcoin_Transaction").to_vec();
-        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
-        match tree.get_proof(b"eee".to_vec()) {
-            Some(MerkleProof(items)) => {
-                assert_eq!(items.len(), 1);
-                assert!(matches!(items[0], MerkleProofItem::Left(_)));
+    #[tokio::test]
+    async fn test_proof_api_binary() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/5.bin")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/octet-stream");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        /* 8 leaves, so 3 items of 1 + 32 bytes each */
+        assert_eq!(body[..4], [0, 0, 0, 3]);
+        let proof = MerkleProof::<32>::from_compact(&body).unwrap();
+        assert!(connection.snapshot().verify_proof(5, 5555, &proof));
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_etag() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/2")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()[http::header::ETAG].clone();
+        assert_eq!(etag, "\"10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a-2-tuple\"");
+
+        /* revalidating with the same ETag */
+        for (uri, status) in [("/proof/2", StatusCode::NOT_MODIFIED), ("/proof/3", StatusCode::OK), ("/proof/2.bin", StatusCode::OK)] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(uri)
+                        .header(http::header::IF_NONE_MATCH, etag.clone())
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+            if status == StatusCode::NOT_MODIFIED {
+                assert_eq!(response.headers()[http::header::ETAG], etag);
+                assert!(response.into_body().collect().await.unwrap().to_bytes().is_empty());
             }
-            None => {
-                panic!("Anomaly! The proof of a leaf in the tree shouldn't be None.")
+        }
+
+        /* a new root invalidates the proofs */
+        connection.update(|db| db.set_balance(9, 9999));
+        let app = create_app(connection.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/proof/2")
+                    .header(http::header::IF_NONE_MATCH, etag.clone())
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()[http::header::ETAG], etag);
+    }
+
+    #[tokio::test]
+    async fn test_proof_by_hash_api() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let leaf_hash = HEXLOWER.encode(&Sha256Algorithm::tagged_hash(LEAF_TAG, &db::serialize_user(6, 6666)));
+        let unknown = HEXLOWER.encode(&Sha256Algorithm::tagged_hash(LEAF_TAG, &db::serialize_user(6, 6667)));
+        for (uri, status) in [(format!("/proof/by-hash/0x{}", leaf_hash), StatusCode::OK), (format!("/proof/by-hash/{}", leaf_hash), StatusCode::OK),
+                              (format!("/proof/by-hash/0x{}", unknown), StatusCode::NOT_FOUND), ("/proof/by-hash/0x1234".to_string(), StatusCode::BAD_REQUEST)] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(&uri)
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+            if status == StatusCode::OK {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let body_json: Value = serde_json::from_slice(&body).unwrap();
+                let proof = connection.snapshot().get_proof(6).unwrap();
+                assert_eq!(body_json, json!({ "user_id": 6, "balance": 6666, "leaf_index": 5, "tree_size": 8, "proof": proof }));
             }
         }
     }
 
-    #[test]
-    fn test_merkle_proof_nonexistent() {
-        let test_values = vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec(), b"eee".to_vec()];
-        let tag = (b"Bitcoin_Transaction").to_vec();
-        let tree = MerkleTree::<32, Sha256Algorithm>::build(test_values, tag.clone(), tag.clone());
-        assert!(matches!(tree.get_proof(b"ggg".to_vec()), None));
+    #[tokio::test]
+    async fn test_proof_api_root_header() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let mut roots = Vec::new();
+        for uri in ["/root", "/proof/4", "/proof/4.bin"] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            if uri == "/root" {
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                roots.push(serde_json::from_slice::<Value>(&body).unwrap().as_str().unwrap().to_string());
+            } else {
+                roots.push(response.headers()["x-merkle-root"].to_str().unwrap().to_string());
+            }
+        }
+        assert_eq!(roots, vec!["0x10bf8188cae1856d04f872705bc68f6c5007a218e06606639bc84564a150bd8a"; 3]);
     }
 
     #[tokio::test]
-    async fn test_root_api() {
+    async fn test_proof_api_bad_id() {
         let db = create_test_db();
-        let connection = Arc::new(db);
+        let connection = Arc::new(OnlineDatabase::new(db));
         let app = create_app(connection);
         let response = app
             .oneshot(
                 Request::builder()
                     .method(http::Method::GET)
-                    .uri("/root")
+                    .uri("/proof/five.bin")
                     .body(Body::empty())
                     .unwrap()
             ).await.unwrap();
 
-        assert_eq!(response.status(), StatusCode::OK);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let body_json: Value = serde_json::from_slice(&body).unwrap();
-        assert_eq!(body_json, json!("0xb1231de33da17c23cebd80c104b88198e0914b0463d0e14db163605b904a7ba3"))
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_log_filter() {
+        use tracing::level_filters::LevelFilter;
+        assert_eq!(EnvFilter::try_new("info").unwrap().max_level_hint(), Some(LevelFilter::INFO));
+        let filter = EnvFilter::try_new("warn,code_test=debug,axum::rejection=trace").unwrap();
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::TRACE));
+        assert!(EnvFilter::builder().parse("code_test=loud").is_err());
     }
 
     #[tokio::test]
-    async fn test_proof_api_normal() {
+    async fn test_proof_api_user_id() {
         let db = create_test_db();
-        let connection = Arc::new(db);
-        let app = create_app(connection);
+        let connection = Arc::new(OnlineDatabase::new(db));
+        for user_id in [1, 6] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(format!("/proof/{}", user_id))
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body_json: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body_json["user_id"], json!(user_id));
+            assert_eq!(body_json["balance"], json!(user_id * 1111));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_removed_user() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        connection.update(|db| db.remove_user(3));
+        /* removed users are gone, users that never existed are not found, also when verifying */
+        for (uri, status) in [("/proof/3", StatusCode::GONE), ("/proof/3.bin", StatusCode::GONE), ("/proof/3/verify", StatusCode::GONE),
+                              ("/proof/9", StatusCode::NOT_FOUND), ("/proof/9/verify", StatusCode::NOT_FOUND), ("/proof/4", StatusCode::OK)] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), status, "{}", uri);
+        }
+
+        /* adding the user back clears the tombstone */
+        connection.update(|db| db.set_balance(3, 3000));
+        assert!(!connection.snapshot().is_removed(3));
+        let app = create_app(connection.clone());
         let response = app
             .oneshot(
                 Request::builder()
                     .method(http::Method::GET)
-                    .uri("/proof/1")
+                    .uri("/proof/3")
                     .body(Body::empty())
                     .unwrap()
             ).await.unwrap();
-        
         assert_eq!(response.status(), StatusCode::OK);
-        let body = response.into_body().collect().await.unwrap().to_bytes();
-        let body_json: Value = serde_json::from_slice(&body).unwrap();
-        match body_json {
-            Value::Object(m) => {
-                /* retrieves balance correctly */
-                assert_eq!(m.get("balance"), Some(json!(1111)).as_ref());
-                let proof = match m.get("proof") {
-                    Some(Value::Array(items)) => { items }
-                    _ => { panic!("Garbage response!") }
-                };
-                /* right proof length */
-                assert_eq!(proof.len(), 3);
-                /* correctly serializes left/right node as 0 or 1 */
-                match &proof[0] {
-                    Value::Array(item_1) => {
-                        assert_eq!(item_1[0], json!(1))
-                    }
-                    _ => { panic!("Bad proof format!") }
-                }
+    }
+
+    #[tokio::test]
+    async fn test_batch_responses_deterministic() {
+        let db = create_test_db();
+        let connection = Arc::new(OnlineDatabase::new(db));
+        for uri in ["/proofs?ids=7,2,9,5", "/users", "/users?balance=4444"] {
+            let mut bodies = Vec::new();
+            for _ in 0..2 {
+                let app = create_app(connection.clone());
+                let response = app
+                    .oneshot(
+                        Request::builder()
+                            .method(http::Method::GET)
+                            .uri(uri)
+                            .body(Body::empty())
+                            .unwrap()
+                    ).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                bodies.push(response.into_body().collect().await.unwrap().to_bytes());
             }
-            _ => {
-                panic!("Garbage response!")
+            assert_eq!(bodies[0], bodies[1], "{}", uri);
+        }
+
+        let app = create_app(connection.clone());
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/users?balance=4444")
+                    .body(Body::empty())
+                    .unwrap()
+            ).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"{\"4\":4444}");
+    }
+
+    // clients correlate the entries of a batch by position, so they must come in the order requested, not sorted
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_proofs_api_request_order() {
+        let connection = Arc::new(OnlineDatabase::new(create_test_db()));
+        let app = create_app(connection.clone());
+        for (ids, expected) in [("3,1,2", vec![(3, true), (1, true), (2, true)]), ("3,10,1,2", vec![(3, true), (10, false), (1, true), (2, true)])] {
+            let response = app.clone().oneshot(Request::builder().uri(format!("/proofs?ids={}", ids)).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let entries: Vec<Value> = serde_json::from_slice(&body).unwrap();
+            let actual: Vec<(u64, bool)> = entries.iter().map(|entry| (entry["user_id"].as_u64().unwrap(), entry.get("proof").is_some())).collect();
+            assert_eq!(actual, expected, "{}", ids);
+            for entry in entries.iter().filter(|entry| entry.get("proof").is_some()) {
+                let user_id = entry["user_id"].as_u64().unwrap();
+                assert_eq!(entry["proof"], json!(connection.snapshot().get_proof(user_id).unwrap()));
             }
         }
     }
 
     #[tokio::test]
-    async fn test_proof_api_nonexistent() {
+    async fn test_proofs_api() {
         let db = create_test_db();
-        let connection = Arc::new(db);
-        let app = create_app(connection);
+        let connection = Arc::new(OnlineDatabase::new(db));
+        let app = create_app(connection.clone());
         let response = app
             .oneshot(
                 Request::builder()
                     .method(http::Method::GET)
-                    .uri("/proof/10")
+                    .uri("/proofs?ids=7,2,9")
                     .body(Body::empty())
                     .unwrap()
             ).await.unwrap();
-        
-        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body_json: Value = serde_json::from_slice(&body).unwrap();
+        let db = connection.snapshot();
+        assert_eq!(body_json, json!([
+            { "user_id": 7, "balance": 7777, "leaf_index": 6, "tree_size": 8, "proof": db.get_proof(7).unwrap() },
+            { "user_id": 2, "balance": 2222, "leaf_index": 1, "tree_size": 8, "proof": db.get_proof(2).unwrap() },
+            { "user_id": 9, "error": "not found" }
+        ]));
+
+        for uri in ["/proofs?ids=1,two", "/proofs?ids="] {
+            let app = create_app(connection.clone());
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::GET)
+                        .uri(uri)
+                        .body(Body::empty())
+                        .unwrap()
+                ).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn test_database_from_sorted_map() {
+        let mut shuffled = TEST_DATA.to_vec();
+        shuffled.reverse();
+        shuffled.swap(1, 5);
+        let db: InMemoryDatabase<32, Sha256Algorithm> = InMemoryDatabase::from_sorted_map(shuffled.iter().copied().collect(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec());
+        /* the leaves are in key order, whatever order the map was filled in */
+        let mut sorted = shuffled.clone();
+        sorted.sort_unstable();
+        assert_eq!(db.get_root(), InMemoryDatabase::<32, Sha256Algorithm>::create(sorted, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()).get_root());
+        assert_eq!(db.get_root().unwrap(), create_test_db().get_root().unwrap());
+        assert_eq!(db.users().collect::<Vec<_>>(), TEST_DATA.to_vec());
+        assert!(db.get_absence_proof(9).is_some());
+    }
+
+    #[test]
+    fn test_database_tree() {
+        let mut db = create_test_db();
+        assert_eq!(db.tree().get_root(), db.get_root().unwrap());
+        assert_eq!(db.tree().layers()[0].len(), db.num_leaves());
+        let proof = db.tree().get_proof_at(2).unwrap();
+        assert!(db.verify_proof(TEST_DATA[2].0, TEST_DATA[2].1, &proof));
+        /* the tree follows the updates of the database */
+        db.set_balance(1, 42);
+        assert_eq!(db.tree().get_root(), db.get_root().unwrap());
+    }
+
+    #[test]
+    fn test_multi_asset_database() {
+        let build = |assets: HashMap<u32, u64>| -> InMemoryDatabase<32, Sha256Algorithm> {
+            DatabaseBuilder::default()
+                .leaf_tag(LEAF_TAG.to_vec())
+                .branch_tag(BRANCH_TAG.to_vec())
+                .build_assets(vec![(1, HashMap::from([(DEFAULT_ASSET, 1111)])), (7, assets)])
+        };
+        /* the assets are serialized sorted by ID, whatever order they were inserted in */
+        let db = build(HashMap::from([(3, 25), (DEFAULT_ASSET, 100)]));
+        assert_eq!(db::serialize_user_assets(7, db.get_balances(7).unwrap()), b"\x01(7,{0:100,3:25})");
+        assert_eq!(db.export_leaves_with_values()[1].3, <[u8; 32]>::try_from(HEXLOWER.decode(b"b1e037abd04a33786f81313c7a08d0a6ff0a8cfb34f004b8a01454ed607f7b0c").unwrap()).unwrap());
+        assert_eq!(build(HashMap::from([(DEFAULT_ASSET, 100), (3, 25)])).get_root(), db.get_root());
+        /* the balances are retrieved per asset */
+        assert_eq!(db.get_balance(7, DEFAULT_ASSET), Some(100));
+        assert_eq!(db.get_balance(7, 3), Some(25));
+        assert_eq!(db.get_balance(7, 4), None);
+        assert_eq!(db.get_balance(1, 3), None);
+        assert_eq!(db.total_balance(DEFAULT_ASSET), Some(1211));
+        assert_eq!(db.total_balance(3), Some(25));
+        assert_eq!(db.verify_user(7), Ok(true));
+        /* a user holding the default asset only keeps its single-asset leaf */
+        assert_eq!(db.export_leaves_with_values()[0], create_test_db().export_leaves_with_values()[0]);
+        /* updating an asset rehashes the leaf of the user */
+        let mut updated = db.clone();
+        updated.set_asset_balance(7, 3, 30);
+        assert_eq!(updated.get_balance(7, 3), Some(30));
+        assert_eq!(updated.get_root(), build(HashMap::from([(DEFAULT_ASSET, 100), (3, 30)])).get_root());
+        updated.set_asset_balance(7, 3, 25);
+        assert_eq!(updated.get_root(), db.get_root());
+        /* removing a user returns all its balances */
+        assert_eq!(updated.remove_user(7), Some(HashMap::from([(DEFAULT_ASSET, 100), (3, 25)])));
+    }
+
+    #[test]
+    fn test_multi_asset_snapshot() {
+        let path = std::env::temp_dir().join(format!("merkle-snapshot-assets-{}.json", std::process::id()));
+        let mut db = create_test_db();
+        db.set_asset_balance(2, 3, 25);
+        db.set_asset_balance(10, 4, 40);
+        db.save_snapshot(&path).unwrap();
+        let snapshot = std::fs::read_to_string(&path).unwrap();
+        /* users holding the default asset only keep the single-asset form */
+        assert!(snapshot.contains("[1,1111]"));
+        assert!(snapshot.contains("[2,[[0,2222],[3,25]]]"));
+        assert!(snapshot.contains("[10,[[4,40]]]"));
+        let loaded = InMemoryDatabase::<32, Sha256Algorithm>::load_snapshot(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get_root(), db.get_root());
+        assert_eq!(loaded.get_balances(2), db.get_balances(2));
+        assert_eq!(loaded.get_balance(10, 4), Some(40));
+        assert_eq!(loaded.get_balance(10, DEFAULT_ASSET), None);
+    }
+
+    #[tokio::test]
+    async fn test_proof_api_multi_asset() {
+        let mut db = create_test_db();
+        db.set_asset_balance(10, 4, 40);
+        db.set_asset_balance(2, 3, 25);
+        let root = db.get_root().unwrap();
+        let app = create_app(Arc::new(OnlineDatabase::new(db)));
+        for (user_id, balance, balances) in [(10, 0, json!({ "4": 40 })), (2, 2222, json!({ "0": 2222, "3": 25 }))] {
+            let response = app.clone().oneshot(Request::builder().uri(format!("/proof/{}", user_id)).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body_json: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!((&body_json["balance"], &body_json["balances"]), (&json!(balance), &balances));
+            /* the leaf is rebuilt from the returned balances, and the proof verifies independently */
+            let balances: HashMap<AssetId, u64> = serde_json::from_value::<BTreeMap<AssetId, u64>>(balances).unwrap().into_iter().collect();
+            let items: Vec<(u64, String)> = serde_json::from_value(body_json["proof"].clone()).unwrap();
+            let proof = MerkleProof(items.iter().map(|(direction, hash)| MerkleProofItem::from_parts(*direction, hash).unwrap()).collect());
+            let (index, size) = (body_json["leaf_index"].as_u64().unwrap() as usize, body_json["tree_size"].as_u64().unwrap() as usize);
+            assert!(verify_root::<32, Sha256Algorithm>(&db::serialize_user_assets(user_id, &balances), &proof, index, size, &root, LEAF_TAG, BRANCH_TAG, Padding::Duplicate));
+            /* and /verify accepts it with the balances, but not with the balance alone */
+            for (request, valid) in [(json!({ "user_id": user_id, "balance": balance, "balances": body_json["balances"], "proof": body_json["proof"] }), true), (json!({ "user_id": user_id, "balance": balance, "proof": body_json["proof"] }), false)] {
+                let response = app.clone().oneshot(Request::builder().method(http::Method::POST).uri("/verify").header(header::CONTENT_TYPE, "application/json").body(Body::from(request.to_string())).unwrap()).await.unwrap();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                assert_eq!(serde_json::from_slice::<Value>(&body).unwrap()["valid"], json!(valid));
+            }
+        }
+        /* single-asset users have no balances */
+        let response = app.oneshot(Request::builder().uri("/proof/1").body(Body::empty()).unwrap()).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(serde_json::from_slice::<Value>(&body).unwrap().get("balances").is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_proofs_api_large_batch() {
+        let user_data: Vec<(u64, u64)> = (0..5000u64).map(|id| (id, id * 3 + 1)).collect();
+        let connection = Arc::new(OnlineDatabase::new(InMemoryDatabase::create(user_data, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec())));
+        /* a full batch in scrambled order, with missing users (IDs from 5000) and duplicates, spread over all workers */
+        let user_ids: Vec<u64> = (0..MAX_BATCH_SIZE as u64 - 2).map(|i| i * 7919 % 6000).chain([42, 42]).collect();
+        assert_eq!(user_ids.len(), MAX_BATCH_SIZE);
+        let ids = user_ids.iter().map(u64::to_string).collect::<Vec<_>>().join(",");
+        let app = create_app(connection.clone());
+        let response = app.oneshot(Request::builder().uri(format!("/proofs?ids={}", ids)).body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let db = connection.snapshot();
+        let expected: Vec<Value> = user_ids.iter().map(|&user_id| match db.get_balance(user_id, DEFAULT_ASSET) {
+            Some(balance) => json!({ "user_id": user_id, "balance": balance, "leaf_index": user_id, "tree_size": 5000, "proof": db.get_proof(user_id).unwrap() }),
+            None => json!({ "user_id": user_id, "error": "not found" })
+        }).collect();
+        assert_eq!(serde_json::from_slice::<Value>(&body).unwrap(), Value::Array(expected));
+    }
+
+    /* concurrent batches share the permits of the blocking tasks, and all complete */
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_proofs_api_concurrent_batches() {
+        let user_data: Vec<(u64, u64)> = (0..2000u64).map(|id| (id, id + 1)).collect();
+        let app = create_app(Arc::new(OnlineDatabase::new(InMemoryDatabase::create(user_data, LEAF_TAG.to_vec(), BRANCH_TAG.to_vec()))));
+        let mut tasks = tokio::task::JoinSet::new();
+        for offset in 0..4usize {
+            let app = app.clone();
+            tasks.spawn(async move {
+                let ids = (0..MAX_BATCH_SIZE).map(|i| (i * 2 + offset).to_string()).collect::<Vec<_>>().join(",");
+                let response = app.oneshot(Request::builder().uri(format!("/proofs?ids={}", ids)).body(Body::empty()).unwrap()).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let entries: Vec<Value> = serde_json::from_slice(&body).unwrap();
+                assert_eq!(entries.len(), MAX_BATCH_SIZE);
+                assert_eq!(entries[MAX_BATCH_SIZE - 1]["user_id"], json!((MAX_BATCH_SIZE - 1) * 2 + offset));
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_database_builder() {
+        /* with the default options, the builder matches create */
+        let db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+            .leaf_tag(b"Custom_Leaf".to_vec())
+            .branch_tag(b"Custom_Branch".to_vec())
+            .build(TEST_DATA.to_vec());
+        assert_eq!(HEXLOWER.encode(&db.get_root().unwrap().0), "ab5863d3bf054aebf3992264516987b1bc08c29aefde532c52de2fadbb12afcf");
+        let created = InMemoryDatabase::<32, Sha256Algorithm>::create(TEST_DATA.to_vec(), b"Custom_Leaf".to_vec(), b"Custom_Branch".to_vec());
+        assert_eq!(db.get_root(), created.get_root());
+        assert_eq!(db.leaf_tag(), b"Custom_Leaf");
+
+        /* the options are passed on to the tree, and kept when the tree is rebuilt */
+        fn encode_leaf(user_id: u64, balance: u64) -> Vec<u8> {
+            format!("{}:{}", user_id, balance).into_bytes()
+        }
+        let mut db: InMemoryDatabase<32, Sha256Algorithm> = DatabaseBuilder::default()
+            .leaf_tag(LEAF_TAG.to_vec())
+            .branch_tag(BRANCH_TAG.to_vec())
+            .padding(Padding::Promote)
+            .leaf_encoder(encode_leaf)
+            .sorted_pairs(true)
+            .build(TEST_DATA[..7].to_vec());
+        let leaves: Vec<Vec<u8>> = TEST_DATA[..7].iter().map(|(id, balance)| encode_leaf(*id, *balance)).collect();
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_with_options(leaves.clone(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote, true);
+        assert_eq!(db.get_root().unwrap().0, tree.get_root().0);
+        let proof = db.get_proof(7).unwrap();
+        assert!(db.verify_proof(7, 7777, &proof));
+        assert!(proof.verify_sorted::<Sha256Algorithm>(&Sha256Algorithm::tagged_hash(LEAF_TAG, b"7:7777"), &db.get_root().unwrap(), BRANCH_TAG));
+        db.remove_user(7);
+        let tree = MerkleTree::<32, Sha256Algorithm>::build_with_options(leaves[..6].to_vec(), LEAF_TAG.to_vec(), BRANCH_TAG.to_vec(), Padding::Promote, true);
+        assert_eq!(db.get_root().unwrap().0, tree.get_root().0);
+
+        /* Merkle mountain ranges support sorted pairs as well */
+        let mut db: InMemoryDatabase<32, Sha256Algorithm, MmrTree<32, Sha256Algorithm>> = DatabaseBuilder::default()
+            .sorted_pairs(true)
+            .build(TEST_DATA[..7].to_vec());
+        db.set_balance(9, 9999);
+        db.set_balance(2, 2000);
+        for (user_id, balance) in [(1, 1111), (2, 2000), (7, 7777), (9, 9999)] {
+            let proof = db.get_proof(user_id).unwrap();
+            assert!(db.verify_proof(user_id, balance, &proof));
+            assert!(proof.verify_sorted::<Sha256Algorithm>(&Sha256Algorithm::tagged_hash(b"", &db::serialize_user(user_id, balance)), &db.get_root().unwrap(), b""));
+        }
+        db.remove_user(9);
+        let proof = db.get_proof(7).unwrap();
+        assert!(proof.verify_sorted::<Sha256Algorithm>(&Sha256Algorithm::tagged_hash(b"", &db::serialize_user(7, 7777)), &db.get_root().unwrap(), b""));
+    }
+
+    #[test]
+    fn test_database_builder_zero_balance() {
+        let user_data = vec![(1, 1111), (2, 0), (3, 3333)];
+        let builder = || DatabaseBuilder::<32, Sha256Algorithm>::default().leaf_tag(LEAF_TAG.to_vec()).branch_tag(BRANCH_TAG.to_vec());
+        /* zero balances are accepted by default */
+        let db = builder().try_build(user_data.clone()).unwrap();
+        assert_eq!(db.get_balance(2, DEFAULT_ASSET), Some(0));
+        assert_eq!(db.get_root(), builder().build(user_data.clone()).get_root());
+        /* and rejected with the option */
+        let result = builder().reject_zero_balance(true).try_build(user_data);
+        assert_eq!(result.err(), Some(db::BuildError::ZeroBalance(2)));
+        assert!(builder().reject_zero_balance(true).try_build(TEST_DATA.to_vec()).is_ok());
+    }
+
+    #[test]
+    fn test_database_builder_distinct_tags() {
+        let builder = || DatabaseBuilder::<32, Sha256Algorithm>::default().leaf_tag(LEAF_TAG.to_vec());
+        /* identical tags are accepted by default */
+        assert!(builder().branch_tag(LEAF_TAG.to_vec()).try_build(TEST_DATA.to_vec()).is_ok());
+        /* and rejected with the option */
+        let result = builder().branch_tag(LEAF_TAG.to_vec()).enforce_distinct_tags(true).try_build(TEST_DATA.to_vec());
+        assert_eq!(result.err(), Some(db::BuildError::IdenticalTags));
+        let db = builder().branch_tag(BRANCH_TAG.to_vec()).enforce_distinct_tags(true).try_build(TEST_DATA.to_vec()).unwrap();
+        assert_eq!(db.get_root(), create_test_db().get_root());
     }
 }
\ No newline at end of file
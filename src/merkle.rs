@@ -2,47 +2,213 @@
 //! 
 //! Given an array of byte vectors, this module provides functions to build a Merkle tree,
 //! compute the Merkle root, and compute the Merkle proof for a given leaf.
-use sha2::{digest::FixedOutputReset, Digest, Sha256};
-use serde::{ser::SerializeSeq, Serialize};
+use std::borrow::{Borrow, Cow};
+use sha2::{digest::typenum::Unsigned, Digest, Sha224, Sha256, Sha384, Sha512};
+#[cfg(feature = "serde")]
+use serde::{ser::{SerializeSeq, SerializeStruct}, Serialize};
 use data_encoding::HEXLOWER;
+#[cfg(feature = "serde")]
+use data_encoding::BASE64;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 /*
  * It is more natural to make HASH_SIZE a const field of HashAlgorithm rather than a parameter.
  * However, since using associated constants in type expressions is not supported by stable Rust
  * (requires the experimental generic_const_exprs flag), we have to make HASH_SIZE a parameter.
+ *
+ * The redundancy is checked at compile time: a tree whose HASH_SIZE the algorithm is not implemented for is
+ * rejected with a dedicated message, and so is one built with an algorithm whose OUTPUT_SIZE differs from
+ * the HASH_SIZE it is implemented for (which can only happen with an implementation generic over HASH_SIZE).
  */
-pub trait HashAlgorithm<const HASH_SIZE: usize> {
+/// An algorithm producing tagged hashes of HASH_SIZE bytes. The HASH_SIZE of a tree must match its algorithm,
+/// or the tree fails to compile (see the cases in `tests/ui`, with the diagnostics they produce).
+///
+/// Trees only hold the algorithm as `PhantomData`, but are still `Send` and `Sync` only if it is, so algorithms must
+/// be both: the server shares its database between threads.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` does not produce hashes of {HASH_SIZE} bytes",
+    label = "`{Self}` is not implemented for this HASH_SIZE",
+    note = "HASH_SIZE must be the size of the hashes produced by the algorithm, e.g. 32 for `Sha256Algorithm`"
+)]
+pub trait HashAlgorithm<const HASH_SIZE: usize>: Send + Sync + 'static {
+    /// A short name identifying the algorithm, e.g. to advertise it to verifiers.
+    const NAME: &'static str;
+    /// The size in bytes of the hashes actually produced, which must equal HASH_SIZE (see `assert_output_size`).
+    const OUTPUT_SIZE: usize;
     fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; HASH_SIZE];
+
+    /// Hashes a pair of child nodes into their parent, with the left child first.
+    fn hash_pair(tag: &[u8], left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        Self::tagged_hash(tag, &[*left, *right].concat())
+    }
+
+    /// Prepares the tag to hash many values with it, as when building a tree or folding a proof. Algorithms that hash
+    /// the tag (such as the BIP340 construction) should override this to do so only once; by default, the tag is
+    /// hashed every time.
+    fn prepare_tag(tag: &[u8]) -> PreparedTag<HASH_SIZE> {
+        let tag = tag.to_vec();
+        PreparedTag::new(move |data| Self::tagged_hash(&tag, data))
+    }
+}
+
+/// A tag ready to hash values with, computing the same hashes as `tagged_hash` with the tag
+/// (see `HashAlgorithm::prepare_tag`).
+pub struct PreparedTag<const HASH_SIZE: usize>(Box<TaggedHashFn<HASH_SIZE>>);
+
+type TaggedHashFn<const HASH_SIZE: usize> = dyn Fn(&[u8]) -> [u8; HASH_SIZE] + Send + Sync;
+
+impl<const HASH_SIZE: usize> PreparedTag<HASH_SIZE> {
+    pub fn new(hash: impl Fn(&[u8]) -> [u8; HASH_SIZE] + Send + Sync + 'static) -> PreparedTag<HASH_SIZE> {
+        PreparedTag(Box::new(hash))
+    }
+
+    /// Hashes the data with the tag.
+    pub fn hash(&self, data: &[u8]) -> [u8; HASH_SIZE] {
+        (self.0)(data)
+    }
+
+    /// Hashes a pair of child nodes into their parent with the tag, as `HashAlgorithm::hash_pair` does.
+    pub fn hash_pair(&self, left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        self.hash(&[*left, *right].concat())
+    }
 }
 
+/// Fails to compile (when instantiated) if `H` does not produce hashes of HASH_SIZE bytes, or if HASH_SIZE is zero:
+/// all zero-size hashes are equal, so a tree of them would commit to nothing (see `tests/ui/zero_hash_size.rs`).
+pub const fn assert_output_size<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>() {
+    const { assert!(HASH_SIZE > 0, "HASH_SIZE must not be zero") }
+    const { assert!(H::OUTPUT_SIZE == HASH_SIZE, "the output size of the hash algorithm differs from HASH_SIZE") }
+}
+
+#[derive(Debug, Clone)]
 pub struct Sha256Algorithm {}
 impl HashAlgorithm<32> for Sha256Algorithm {
+    const NAME: &'static str = "sha256";
+    const OUTPUT_SIZE: usize = 32;
+    // SHA256(SHA256(tag) || SHA256(tag) || data), as in BIP340. Each hash uses its own hasher,
+    // so no digest state can carry over between the two hashes or between calls.
     fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; 32]{
+        let tag_hash = Sha256::digest(tag);
         let mut hasher = Sha256::new();
-        hasher.update(tag);
-        let tag_hash: [u8; 32] = hasher.finalize_fixed_reset().into();
-        let concatenated = [tag_hash.to_vec(), tag_hash.to_vec(), data.to_vec()].concat();
-        hasher.reset();
-        hasher.update(concatenated);
+        hasher.update(tag_hash);
+        hasher.update(tag_hash);
+        hasher.update(data);
         hasher.finalize().into()
     }
+
+    // the hasher having absorbed the (64-byte) tag prefix is computed once, and each hash starts from a clone of it
+    fn prepare_tag(tag: &[u8]) -> PreparedTag<32> {
+        let tag_hash = Sha256::digest(tag);
+        let mut prefix = Sha256::new();
+        prefix.update(tag_hash);
+        prefix.update(tag_hash);
+        PreparedTag::new(move |data| {
+            let mut hasher = prefix.clone();
+            hasher.update(data);
+            hasher.finalize().into()
+        })
+    }
 }
 
-fn concat_hashes<const HASH_SIZE: usize>(hashes: &Vec<[u8; HASH_SIZE]>) -> Vec<Vec<u8>> {
-    let mut concatenated_hashes = Vec::new();
-    for i in (0..hashes.len()).step_by(2) {
-        if i != hashes.len() - 1 {
-            let concatenated = [hashes[i].to_vec(), hashes[i+1].to_vec()].concat();
-            concatenated_hashes.push(concatenated);
-        } else {
-            let last = hashes.last().unwrap();
-            concatenated_hashes.push([last.to_vec(), last.to_vec()].concat());
+/*
+ * Any digest can be used with the BIP340 tagged construction through this wrapper, e.g. DigestAlgorithm<Sha512> with
+ * HASH_SIZE 64, rather than writing a struct per algorithm. It is implemented for every HASH_SIZE, but its OUTPUT_SIZE
+ * is the output size of the digest, so trees with a HASH_SIZE that does not match are rejected at compile time.
+ * DigestAlgorithm<Sha256> computes the same hashes as Sha256Algorithm.
+ */
+/// A digest with a short name identifying it (see `HashAlgorithm::NAME`), so that it can be used with `DigestAlgorithm`.
+pub trait NamedDigest: Digest + Clone + Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+impl NamedDigest for Sha224 {
+    const NAME: &'static str = "sha224";
+}
+
+impl NamedDigest for Sha256 {
+    const NAME: &'static str = "sha256";
+}
+
+impl NamedDigest for Sha384 {
+    const NAME: &'static str = "sha384";
+}
+
+impl NamedDigest for Sha512 {
+    const NAME: &'static str = "sha512";
+}
+
+// never holds a digest, so it is Send and Sync regardless of D
+#[derive(Debug, Clone)]
+pub struct DigestAlgorithm<D: NamedDigest>(std::marker::PhantomData<fn() -> D>);
+
+impl<const HASH_SIZE: usize, D: NamedDigest> HashAlgorithm<HASH_SIZE> for DigestAlgorithm<D> {
+    const NAME: &'static str = D::NAME;
+    const OUTPUT_SIZE: usize = D::OutputSize::USIZE;
+    fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; HASH_SIZE] {
+        let tag_hash = D::digest(tag);
+        let mut hasher = D::new();
+        hasher.update(&tag_hash);
+        hasher.update(&tag_hash);
+        hasher.update(data);
+        hasher.finalize().as_slice().try_into().expect("the output size of the digest differs from HASH_SIZE")
+    }
+
+    fn prepare_tag(tag: &[u8]) -> PreparedTag<HASH_SIZE> {
+        let tag_hash = D::digest(tag);
+        let mut prefix = D::new();
+        prefix.update(&tag_hash);
+        prefix.update(&tag_hash);
+        PreparedTag::new(move |data| {
+            let mut hasher = prefix.clone();
+            hasher.update(data);
+            hasher.finalize().as_slice().try_into().expect("the output size of the digest differs from HASH_SIZE")
+        })
+    }
+}
+
+/*
+ * Bitcoin hashes Merkle tree nodes with plain double SHA256, without any tag, so the tag is ignored.
+ * See MerkleTree::build_bitcoin for building trees that match Bitcoin block Merkle roots.
+ */
+#[derive(Debug, Clone)]
+pub struct DoubleSha256Algorithm {}
+impl HashAlgorithm<32> for DoubleSha256Algorithm {
+    const NAME: &'static str = "sha256d";
+    const OUTPUT_SIZE: usize = 32;
+    fn tagged_hash(_tag: &[u8], data: &[u8]) -> [u8; 32] {
+        Sha256::digest(Sha256::digest(data)).into()
+    }
+}
+
+/// How a lone node (the last node of a layer with an odd number of nodes) is carried to the next layer.
+/// Proofs never contain an item for a lone node, so the verifier must use the same strategy as the builder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(rename_all = "lowercase"))]
+pub enum Padding {
+    /// The lone node is paired with itself, as in Bitcoin.
+    #[default]
+    Duplicate,
+    /// The lone node is promoted to the next layer unchanged.
+    Promote
+}
+
+impl Padding {
+    // the parent of a lone node
+    pub(crate) fn lone_parent<const HASH_SIZE: usize>(self, hash: [u8; HASH_SIZE], branch_tag: &PreparedTag<HASH_SIZE>) -> [u8; HASH_SIZE] {
+        match self {
+            Padding::Duplicate => branch_tag.hash_pair(&hash, &hash),
+            Padding::Promote => hash
         }
     }
-    concatenated_hashes
 }
 
-fn hash_values<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(values: Vec<Vec<u8>>, tag: &Vec<u8>) -> Vec<[u8; HASH_SIZE]> {
-    values.iter().map(|x| H::tagged_hash(&tag, x)).collect::<Vec<_>>()
+// layers with fewer nodes than this are hashed sequentially even when building in parallel
+#[cfg(feature = "parallel")]
+const PARALLEL_THRESHOLD: usize = 1024;
+
+fn hash_values<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, V: AsRef<[u8]>>(values: &[V], tag: &[u8]) -> Vec<[u8; HASH_SIZE]> {
+    let tag = H::prepare_tag(tag);
+    values.iter().map(|x| tag.hash(x.as_ref())).collect::<Vec<_>>()
 }
 
 /*
@@ -55,79 +221,390 @@ fn hash_values<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(values: Vec<
  * parameters in const expressions is not supported by stable Rust, so we have to do
  * with a bit of redundancy. 
  */
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MerkleTree<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
     layers: Vec<Vec<[u8; HASH_SIZE]>>,
     leaf_tag: Vec<u8>,
     branch_tag: Vec<u8>,
+    padding: Padding,
+    sorted_pairs: bool, // whether the children are sorted before hashing them (see MerkleProof::verify_sorted)
+    pruned: bool, // whether the branch layers below the root were dropped (see prune)
     _hasher: std::marker::PhantomData<H> // a phantom field that serves as evidence for H
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum MerkleError {
+    NotPowerOfTwo(usize)
+}
+
+impl std::fmt::Display for MerkleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MerkleError::NotPowerOfTwo(num_leaves) => write!(f, "the number of leaves ({}) is not a power of two", num_leaves)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MerkleProofItem<const HASH_SIZE: usize> {
     Left([u8; HASH_SIZE]),
     Right([u8; HASH_SIZE])
 }
 
-#[derive(Debug, Serialize)]
+/// The direction of a proof item whose hash is that of a left sibling, in the serialized forms of proofs.
+pub const LEFT: u8 = 0;
+/// The direction of a proof item whose hash is that of a right sibling, in the serialized forms of proofs.
+pub const RIGHT: u8 = 1;
+
+/// Why a submitted proof item is not in the canonical `[0 or 1, "0x..."]` form.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProofItemError {
+    BadDirection(u64),
+    BadHash(String)
+}
+
+impl std::fmt::Display for ProofItemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProofItemError::BadDirection(direction) => write!(f, "direction {} is neither {} (left) nor {} (right)", direction, LEFT, RIGHT),
+            ProofItemError::BadHash(hash) => write!(f, "{} is not a 0x-prefixed lowercase hex hash", hash)
+        }
+    }
+}
+
+impl<const HASH_SIZE: usize> MerkleProofItem<HASH_SIZE> {
+    /// Returns the direction of the item (`LEFT` or `RIGHT`) and its hash.
+    pub fn to_parts(&self) -> (u8, &[u8; HASH_SIZE]) {
+        match self {
+            MerkleProofItem::Left(hash) => (LEFT, hash),
+            MerkleProofItem::Right(hash) => (RIGHT, hash)
+        }
+    }
+
+    /// Builds an item from its direction and hash, or returns None if the direction is neither `LEFT` nor `RIGHT`.
+    pub fn from_direction(direction: u8, hash: [u8; HASH_SIZE]) -> Option<MerkleProofItem<HASH_SIZE>> {
+        match direction {
+            LEFT => Some(MerkleProofItem::Left(hash)),
+            RIGHT => Some(MerkleProofItem::Right(hash)),
+            _ => None
+        }
+    }
+
+    /// Parses a proof item from its serialized parts, the direction (0 for left, 1 for right) and the hex-encoded hash,
+    /// which must be `0x` followed by exactly HASH_SIZE lowercase hex-encoded bytes.
+    pub fn from_parts(direction: u64, hash: &str) -> Result<MerkleProofItem<HASH_SIZE>, ProofItemError> {
+        let bad_hash = || ProofItemError::BadHash(hash.to_string());
+        let digits = hash.strip_prefix("0x").ok_or_else(bad_hash)?;
+        let bytes = HEXLOWER.decode(digits.as_bytes()).map_err(|_| bad_hash())?;
+        let hash: [u8; HASH_SIZE] = bytes.try_into().map_err(|_| bad_hash())?;
+        u8::try_from(direction).ok()
+            .and_then(|direction| MerkleProofItem::from_direction(direction, hash))
+            .ok_or(ProofItemError::BadDirection(direction))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub struct MerkleProof<const HASH_SIZE: usize>(pub Vec<MerkleProofItem<HASH_SIZE>>);
 
-#[derive(Debug)]
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Returns the number of items of the proof.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether the proof has no items, as the proof of the only leaf of a tree.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns an iterator over the items of the proof, from the leaf up.
+    pub fn iter(&self) -> std::slice::Iter<'_, MerkleProofItem<HASH_SIZE>> {
+        self.0.iter()
+    }
+}
+
+impl<'a, const HASH_SIZE: usize> IntoIterator for &'a MerkleProof<HASH_SIZE> {
+    type Item = &'a MerkleProofItem<HASH_SIZE>;
+    type IntoIter = std::slice::Iter<'a, MerkleProofItem<HASH_SIZE>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct MerkleRoot<const HASH_SIZE: usize>(pub [u8; HASH_SIZE]);
 
+impl<const HASH_SIZE: usize> MerkleRoot<HASH_SIZE> {
+    /// Returns the number of bits in which the two roots differ, for debugging mismatching roots: a few bits point to
+    /// a corrupted copy of the root, whereas about half of the bits (as for any two distinct hashes) to another tree.
+    pub fn diff_bits(&self, other: &MerkleRoot<HASH_SIZE>) -> u32 {
+        self.0.iter().zip(&other.0).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+}
+
 impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTree<HASH_SIZE, H> {
-    fn build_rec(&mut self, values: Vec<Vec<u8>>, is_leaf: bool) {
-        let tag = if is_leaf { &self.leaf_tag } else { &self.branch_tag };
-        let hashes = hash_values::<HASH_SIZE, H>(values, tag);
-        if hashes.len() > 1 {
-            let concatenated_hashes = concat_hashes::<HASH_SIZE>(&hashes);
-            self.layers.push(hashes);
-            self.build_rec(concatenated_hashes, false);
+    // hash two children into their parent with the prepared branch tag, sorting them first in sorted-pair mode
+    fn hash_children_with(&self, branch_tag: &PreparedTag<HASH_SIZE>, left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        if self.sorted_pairs && left > right {
+            branch_tag.hash_pair(right, left)
+        } else {
+            branch_tag.hash_pair(left, right)
+        }
+    }
+
+    // hash each pair of children into the parents layer, carrying a lone node up according to the padding strategy
+    fn hash_parents(&self, branch_tag: &PreparedTag<HASH_SIZE>, children: &[[u8; HASH_SIZE]], parents: &mut Vec<[u8; HASH_SIZE]>) {
+        for pair in children.chunks(2) {
+            match pair {
+                [left, right] => parents.push(self.hash_children_with(branch_tag, left, right)),
+                _ => parents.push(self.padding.lone_parent(pair[0], branch_tag))
+            }
+        }
+    }
+
+    // same as hash_parents, but hashes the pairs on the rayon thread pool, unless the layer is too small to be worth it
+    #[cfg(feature = "parallel")]
+    fn hash_parents_parallel(&self, branch_tag: &PreparedTag<HASH_SIZE>, children: &[[u8; HASH_SIZE]], parents: &mut Vec<[u8; HASH_SIZE]>) {
+        if children.len() < PARALLEL_THRESHOLD {
+            return self.hash_parents(branch_tag, children, parents);
+        }
+        children.par_chunks(2).map(|pair| match pair {
+            [left, right] => self.hash_children_with(branch_tag, left, right),
+            _ => self.padding.lone_parent(pair[0], branch_tag)
+        }).collect_into_vec(parents);
+    }
+
+    // hashes is the current (already hashed) layer; the branch tag is prepared once for all the layers above
+    fn hash_layers(&self, hashes: Vec<[u8; HASH_SIZE]>) -> Vec<Vec<[u8; HASH_SIZE]>> {
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        let mut layers = Vec::new();
+        let mut hashes = hashes;
+        while hashes.len() > 1 {
+            let mut parent_hashes = Vec::with_capacity(hashes.len().div_ceil(2));
+            self.hash_parents(&branch_tag, &hashes, &mut parent_hashes);
+            layers.push(std::mem::replace(&mut hashes, parent_hashes));
+        }
+        layers.push(hashes); // we've just got to the root, done
+        layers
+    }
+
+    fn build_layers(&mut self, hashes: Vec<[u8; HASH_SIZE]>) {
+        self.layers = self.hash_layers(hashes);
+    }
+
+    // all the layers, rehashed from the leaves if the tree was pruned
+    fn full_layers(&self) -> Cow<'_, [Vec<[u8; HASH_SIZE]>]> {
+        if self.pruned {
+            Cow::Owned(self.hash_layers(self.layers[0].clone()))
         } else {
-            self.layers.push(hashes); // we've just got to the root, done
-        }        
+            Cow::Borrowed(&self.layers)
+        }
+    }
+
+    fn from_leaf_hashes(leaf_hashes: Vec<[u8; HASH_SIZE]>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> MerkleTree<HASH_SIZE, H> {
+        assert_output_size::<HASH_SIZE, H>();
+        let mut tree = MerkleTree {
+            layers: Vec::new(),
+            leaf_tag,
+            branch_tag,
+            padding,
+            sorted_pairs,
+            pruned: false,
+            _hasher: std::marker::PhantomData
+        };
+        tree.build_layers(leaf_hashes);
+        tree
     }
 
     /// This function builds a Merkle tree from a vector of byte vectors, which represent the leaf values (unhashed!).
     /// BIP340 compatible tagged hashing is used.
     /// `leaf_tag` is the tag used for hashing the leaf nodes, and `branch_tag` is the tag used for hashing the branch nodes.
     pub fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MerkleTree<HASH_SIZE, H> {
+        let leaf_hashes = hash_values::<HASH_SIZE, H, _>(&values, &leaf_tag);
+        Self::from_leaf_hashes(leaf_hashes, leaf_tag, branch_tag, Padding::Duplicate, false)
+    }
+
+    /// Same as `build`, but hashes the leaves and then each branch layer in parallel. The layers still have to be
+    /// built one after the other, since each is hashed from the one below.
+    #[cfg(feature = "parallel")]
+    pub fn build_parallel(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MerkleTree<HASH_SIZE, H> {
+        assert_output_size::<HASH_SIZE, H>();
+        let prepared_leaf_tag = H::prepare_tag(&leaf_tag);
+        let leaf_hashes = values.par_iter().map(|value| prepared_leaf_tag.hash(value)).collect();
         let mut tree = MerkleTree {
             layers: Vec::new(),
             leaf_tag,
             branch_tag,
+            padding: Padding::Duplicate,
+            sorted_pairs: false,
+            pruned: false,
             _hasher: std::marker::PhantomData
         };
-        tree.build_rec(values, true);
+        let branch_tag = H::prepare_tag(&tree.branch_tag);
+        let mut hashes: Vec<[u8; HASH_SIZE]> = leaf_hashes;
+        while hashes.len() > 1 {
+            let mut parent_hashes = Vec::with_capacity(hashes.len().div_ceil(2));
+            tree.hash_parents_parallel(&branch_tag, &hashes, &mut parent_hashes);
+            tree.layers.push(std::mem::replace(&mut hashes, parent_hashes));
+        }
+        tree.layers.push(hashes);
         tree
     }
 
+    /// Same as `build`, but lone nodes are carried to the next layer with the given padding strategy
+    /// (`build` always duplicates them).
+    pub fn build_with_padding(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding) -> MerkleTree<HASH_SIZE, H> {
+        Self::build_with_options(values, leaf_tag, branch_tag, padding, false)
+    }
+
+    /// Same as `build_with_padding`, but if `sorted_pairs` is set, the two children of each node are sorted before
+    /// hashing them, so that proofs can be verified without directions by `MerkleProof::verify_sorted`.
+    /// Multiproofs of such trees cannot be verified, as `MultiProof::compute_root` folds by position.
+    pub fn build_with_options(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>, padding: Padding, sorted_pairs: bool) -> MerkleTree<HASH_SIZE, H> {
+        let leaf_hashes = hash_values::<HASH_SIZE, H, _>(&values, &leaf_tag);
+        Self::from_leaf_hashes(leaf_hashes, leaf_tag, branch_tag, padding, sorted_pairs)
+    }
+
+    /// Same as `build`, but fails unless the number of leaves is a power of two. The resulting tree is perfectly
+    /// balanced: no lone node is ever padded, and all proofs have the same length.
+    pub fn build_strict(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> Result<MerkleTree<HASH_SIZE, H>, MerkleError> {
+        if !values.len().is_power_of_two() {
+            return Err(MerkleError::NotPowerOfTwo(values.len()));
+        }
+        Ok(Self::build(values, leaf_tag, branch_tag))
+    }
+
+    /// Same as `build`, but hashes borrowed leaf values directly, so callers whose leaves already live
+    /// in a (contiguous) buffer need not allocate a vector for each leaf.
+    pub fn build_from_slices(values: &[&[u8]], leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MerkleTree<HASH_SIZE, H> {
+        let leaf_hashes = hash_values::<HASH_SIZE, H, _>(values, &leaf_tag);
+        Self::from_leaf_hashes(leaf_hashes, leaf_tag, branch_tag, Padding::Duplicate, false)
+    }
+
+    /// Creates an empty tree with room for `capacity` leaves pre-allocated. Like a tree built from no values, it has a
+    /// single empty layer, and no root until it is filled with `rebuild_in_place`, which allocates the branch layers
+    /// (and reuses them when the tree is rebuilt again).
+    pub fn with_capacity(capacity: usize, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MerkleTree<HASH_SIZE, H> {
+        assert_output_size::<HASH_SIZE, H>();
+        MerkleTree {
+            layers: vec![Vec::with_capacity(capacity)],
+            leaf_tag,
+            branch_tag,
+            padding: Padding::Duplicate,
+            sorted_pairs: false,
+            pruned: false,
+            _hasher: std::marker::PhantomData
+        }
+    }
+
+    /// Rebuilds the tree from new leaf values (unhashed!), with the same tags, padding strategy and pair ordering. Unlike `build`, this reuses the memory
+    /// already allocated for the layers, which avoids churning memory when a tree is rebuilt frequently.
+    pub fn rebuild_in_place(&mut self, values: Vec<Vec<u8>>) {
+        self.pruned = false;
+        if self.layers.is_empty() {
+            self.layers.push(Vec::new());
+        }
+        self.layers[0].clear();
+        let leaf_tag = H::prepare_tag(&self.leaf_tag);
+        self.layers[0].extend(values.iter().map(|value| leaf_tag.hash(value)));
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        let mut layer = 0;
+        while self.layers[layer].len() > 1 {
+            if self.layers.len() == layer + 1 {
+                self.layers.push(Vec::new());
+            }
+            let mut parents = std::mem::take(&mut self.layers[layer + 1]);
+            parents.clear();
+            self.hash_parents(&branch_tag, &self.layers[layer], &mut parents);
+            self.layers[layer + 1] = parents;
+            layer += 1;
+        }
+        self.layers.truncate(layer + 1);
+    }
+
+    /// Appends a leaf with the given value and returns its index. Since appending may change the shape of
+    /// the tree (and the padding of lone nodes), all branch layers are rebuilt, but the leaf hashes are reused.
+    pub fn append(&mut self, value: &[u8]) -> usize {
+        self.pruned = false;
+        let mut leaf_hashes = self.layers.swap_remove(0);
+        self.layers.clear();
+        leaf_hashes.push(H::tagged_hash(&self.leaf_tag, value));
+        self.build_layers(leaf_hashes);
+        self.num_leaves() - 1
+    }
+
+    /// Replaces the value of the leaf with the given index, rehashing only the path up to the root (or the whole tree
+    /// if it was pruned, which stays pruned).
+    pub fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        self.layers[0][index] = H::tagged_hash(&self.leaf_tag, value);
+        if self.pruned {
+            let root = self.hash_layers(self.layers[0].clone()).pop().unwrap();
+            *self.layers.last_mut().unwrap() = root;
+            return;
+        }
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        let mut curr_index = index;
+        for layer in 1..self.layers.len() {
+            let children = &self.layers[layer - 1];
+            let left = curr_index - curr_index % 2;
+            let parent = if left + 1 < children.len() {
+                self.hash_children_with(&branch_tag, &children[left], &children[left + 1])
+            } else {
+                self.padding.lone_parent(children[left], &branch_tag)
+            };
+            curr_index /= 2;
+            self.layers[layer][curr_index] = parent;
+        }
+    }
+
     /// Returns the Merkle root of a given Merkle tree as a byte array of length 32 (i.e., 256 bits).
+    ///
+    /// # Panics
+    ///
+    /// If the tree has no leaves (e.g. it was built from no values, or created by `with_capacity` and not filled yet),
+    /// since it then has no root. Use `try_get_root` for trees that may be empty.
     pub fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
-        MerkleRoot(self.layers.last().unwrap()[0])
+        MerkleRoot(*self.root_ref())
+    }
+
+    /// Same as `get_root`, but returns None if the tree has no leaves instead of panicking.
+    pub fn try_get_root(&self) -> Option<MerkleRoot<HASH_SIZE>> {
+        self.layers.last()?.first().map(|root| MerkleRoot(*root))
     }
 
-    // Get the proof item for a given node in the tree
-    fn get_proof_item(&self, layer: usize, index: usize) -> Option<MerkleProofItem<HASH_SIZE>> {
+    /// Same as `get_root`, but borrows the root stored in the tree instead of copying it. The reference lives as long
+    /// as the borrow of the tree, so the tree cannot be updated while it is held.
+    ///
+    /// # Panics
+    ///
+    /// If the tree has no leaves, as `get_root`.
+    pub fn root_ref(&self) -> &[u8; HASH_SIZE] {
+        self.layers.last().and_then(|layer| layer.first()).expect("the Merkle tree is empty")
+    }
+
+    // Get the proof item for a given node in the layer
+    fn get_proof_item(layer: &[[u8; HASH_SIZE]], index: usize) -> Option<MerkleProofItem<HASH_SIZE>> {
         // this is a right node
         if index % 2 == 1 {
-            Some(MerkleProofItem::Left(self.layers[layer][index - 1]))
-        } else if index == self.layers[layer].len() - 1 {
+            Some(MerkleProofItem::Left(layer[index - 1]))
+        } else if index == layer.len() - 1 {
             // the number of nodes in this level is odd, so this is a lone node without a sibling
             None
         } else {
-            Some(MerkleProofItem::Right(self.layers[layer][index + 1]))
+            Some(MerkleProofItem::Right(layer[index + 1]))
 
         }
     }
 
-    // build the proof by moving up the tree
-    fn build_proof(&self, index: usize) -> MerkleProof<HASH_SIZE> {
+    // build the proof by moving up the (full) layers
+    fn build_proof(layers: &[Vec<[u8; HASH_SIZE]>], index: usize) -> MerkleProof<HASH_SIZE> {
         let mut proof = Vec::new();
         let mut curr_index = index;
         // the -1 is important, because the root is not needed
-        for i in 0..(self.layers.len() - 1) {
-            let proof_item = self.get_proof_item(i, curr_index);
+        for layer in &layers[..layers.len() - 1] {
+            let proof_item = Self::get_proof_item(layer, curr_index);
             curr_index /= 2;
             match proof_item {
                 None => {
@@ -144,40 +621,882 @@ impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTree<HASH_SIZE,
 
     /// Given a value, return the Merkle proof for the leaf with that value if
     /// the value is in the tree, or None if the value is not in the tree.
+    /// If several leaves have the value, this is the proof of the first one (see `get_proof_nth`).
     pub fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
-        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, &value);
-        match self.layers[0].iter().position(|&x| x == hash) {
-            Some(index) => Some(self.build_proof(index)),
-            None => None
+        self.get_leaf_index(&value).map(|index| Self::build_proof(&self.full_layers(), index))
+    }
+
+    /// Returns the proof of the `n`-th leaf (counting from 0) with the given value, or None if fewer than `n + 1`
+    /// leaves have the value. Since `verify` only checks against the first such leaf, the proofs of the others
+    /// have to be verified with `verify_root` and the position of their leaf.
+    pub fn get_proof_nth(&self, value: &[u8], n: usize) -> Option<MerkleProof<HASH_SIZE>> {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, value);
+        self.layers[0].iter().enumerate()
+            .filter(|(_, leaf)| **leaf == hash)
+            .nth(n)
+            .map(|(index, _)| Self::build_proof(&self.full_layers(), index))
+    }
+
+    /// Returns the Merkle proof for the leaf at the given position, or None if there is no such leaf. Unlike
+    /// `get_proof`, this proves the given leaf even if an earlier leaf has the same value.
+    pub fn get_proof_at(&self, index: usize) -> Option<MerkleProof<HASH_SIZE>> {
+        (index < self.num_leaves()).then(|| Self::build_proof(&self.full_layers(), index))
+    }
+
+    /// Same as `get_proof`, but instead of omitting lone nodes, the proof contains a right item with the lone node's own
+    /// hash, which is what it is paired with. Such proofs have the same length for all leaves, and fold without knowing
+    /// the position of the leaf or the size of the tree (e.g. with `mmr::verify_root`). Returns None if the tree
+    /// promotes lone nodes, since they are not hashed then.
+    pub fn get_proof_explicit(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        if self.padding != Padding::Duplicate {
+            return None;
+        }
+        let mut curr_index = self.get_leaf_index(&value)?;
+        let layers = self.full_layers();
+        let mut proof = Vec::with_capacity(layers.len() - 1);
+        for layer in &layers[..layers.len() - 1] {
+            let item = Self::get_proof_item(layer, curr_index).unwrap_or(MerkleProofItem::Right(layer[curr_index]));
+            proof.push(item);
+            curr_index /= 2;
+        }
+        Some(MerkleProof(proof))
+    }
+
+    /// Returns the proofs of all the leaves with the given value, in the order of the leaves (empty if the value is
+    /// not in the tree). Unlike `get_proof`, which only proves the first such leaf, this is useful for generic trees
+    /// where the same value may occur several times. Note that `verify` only checks against the first leaf, so the
+    /// other proofs have to be verified with `verify_root` and the position of their leaf.
+    pub fn get_all_proofs(&self, value: &[u8]) -> Vec<MerkleProof<HASH_SIZE>> {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, value);
+        let layers = self.full_layers();
+        self.layers[0].iter().enumerate()
+            .filter(|(_, leaf)| **leaf == hash)
+            .map(|(index, _)| Self::build_proof(&layers, index))
+            .collect()
+    }
+
+    /// Returns the index of the (first) leaf with the given value, or None if the value is not in the tree.
+    pub fn get_leaf_index(&self, value: &[u8]) -> Option<usize> {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, value);
+        self.layers[0].iter().position(|&x| x == hash)
+    }
+
+    /// Returns the number of leaves in the tree.
+    pub fn num_leaves(&self) -> usize {
+        self.layers[0].len()
+    }
+
+    /// Returns the depth of the tree, counted in layers: a single leaf has depth 1, and each halving adds a layer.
+    pub fn depth(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the (hashed) layers of the tree, e.g. to render the full tree. Layer 0 holds the leaves, and the last
+    /// layer holds the root as its single element. The layers in between are empty if the tree was pruned.
+    pub fn layers(&self) -> &[Vec<[u8; HASH_SIZE]>] {
+        &self.layers
+    }
+
+    /*
+     * A pruned tree only stores its leaves and its root, which halves its memory (the branch layers hold about as many
+     * hashes as the leaves). The price is time: anything that needs a branch node (proofs, verify_with_tree,
+     * pretty_print) rehashes the whole tree from the leaves, i.e., takes O(n) hashes instead of O(log(n)) lookups.
+     * Pruning suits trees that are stored (e.g. in snapshots) much more often than they are asked for proofs.
+     */
+
+    /// Drops the branch layers below the root, keeping only the leaves and the root. The tree behaves the same, but
+    /// the branch nodes are rehashed whenever they are needed. Appending or rebuilding restores the branch layers.
+    pub fn prune(&mut self) {
+        if self.layers.len() > 2 {
+            let depth = self.layers.len();
+            for layer in &mut self.layers[1..depth - 1] {
+                *layer = Vec::new(); // not just cleared, to free the memory
+            }
+            self.pruned = true;
+        }
+    }
+
+    /// Returns whether the tree was pruned (see `prune`).
+    pub fn is_pruned(&self) -> bool {
+        self.pruned
+    }
+
+    /// Renders the tree for debugging, one layer after the other from the root down to the leaves, with each
+    /// hash in hex on its own indented line.
+    pub fn pretty_print(&self) -> String {
+        let mut output = String::new();
+        let layers = self.full_layers();
+        for (i, layer) in layers.iter().enumerate().rev() {
+            let name = if i + 1 == layers.len() { "root" } else if i == 0 { "leaves" } else { "branches" };
+            output.push_str(&format!("layer {} ({}):\n", i, name));
+            for hash in layer {
+                output.push_str(&format!("  0x{}\n", HEXLOWER.encode(hash)));
+            }
+        }
+        output
+    }
+
+    /// Returns the tag used for hashing the leaf nodes.
+    pub fn leaf_tag(&self) -> &[u8] {
+        &self.leaf_tag
+    }
+
+    /// Returns the tag used for hashing the branch nodes.
+    pub fn branch_tag(&self) -> &[u8] {
+        &self.branch_tag
+    }
+
+    /// Returns the padding strategy used for lone nodes.
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// Returns whether the children of each node are sorted before hashing them.
+    pub fn sorted_pairs(&self) -> bool {
+        self.sorted_pairs
+    }
+
+    /// Given a set of values, return a multiproof proving all the leaves with these values at once, or None
+    /// if some value is not in the tree (or no value is given).
+    pub fn get_multiproof(&self, values: &[Vec<u8>]) -> Option<MultiProof<HASH_SIZE>> {
+        let indices = values.iter().map(|value| self.get_leaf_index(value)).collect::<Option<Vec<_>>>()?;
+        let mut known: Vec<(usize, ())> = indices.iter().map(|&index| (index, ())).collect();
+        known.sort_by_key(|(index, _)| *index);
+        known.dedup_by_key(|(index, _)| *index);
+
+        let layers = self.full_layers();
+        let mut siblings = Vec::new();
+        walk_multiproof(known, self.num_leaves(), |layer, width, known, k, sibling_known| {
+            let index = known[k].0;
+            let lone = index.is_multiple_of(2) && index == width - 1;
+            if !sibling_known && !lone {
+                siblings.push(layers[layer][index ^ 1]);
+            }
+            Some(())
+        })?;
+        Some(MultiProof { indices, num_leaves: self.num_leaves(), siblings })
+    }
+
+    /// Verifies a proof for the leaf with the given value at position `index`, comparing each intermediate hash with
+    /// the node of this tree it should equal, so that verification stops at the first divergence. Returns the level
+    /// (0 for the leaf) of the first node that differs, or the number of layers if the proof has leftover items.
+    pub fn verify_with_tree(&self, value: &[u8], index: usize, proof: &MerkleProof<HASH_SIZE>) -> Result<(), usize> {
+        if index >= self.num_leaves() {
+            return Err(0);
+        }
+        let mut hash = H::tagged_hash(&self.leaf_tag, value);
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        let layers = self.full_layers();
+        let mut items = proof.0.iter();
+        let mut curr_index = index;
+        for level in 0..layers.len() {
+            if hash != layers[level][curr_index] {
+                return Err(level);
+            }
+            if level + 1 == layers.len() {
+                break;
+            }
+            hash = if curr_index.is_multiple_of(2) && curr_index == layers[level].len() - 1 {
+                self.padding.lone_parent(hash, &branch_tag)
+            } else {
+                match (curr_index % 2, items.next()) {
+                    (1, Some(MerkleProofItem::Left(sibling))) => self.hash_children_with(&branch_tag, sibling, &hash),
+                    (0, Some(MerkleProofItem::Right(sibling))) => self.hash_children_with(&branch_tag, &hash, sibling),
+                    _ => return Err(level + 1) // a missing item or one on the wrong side, so the parent cannot be computed
+                }
+            };
+            curr_index /= 2;
+        }
+        match items.next() {
+            Some(_) => Err(layers.len()),
+            None => Ok(())
+        }
+    }
+
+    /// Verifies a proof for the leaf with the given value by walking the stored layers instead of hashing: each item must
+    /// be the sibling stored at its position on the path of the leaf (or of one of the leaves with the value), on the
+    /// right side. Unlike `verify`, it rejects any proof that is not a real path of the tree, even one whose fabricated
+    /// items fold to the root anyway (e.g. the explicit items of `get_proof_explicit`). It scans every leaf for the
+    /// value, so it takes O(n) comparisons, like `verify`.
+    pub fn verify_proof(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, value);
+        let layers = self.full_layers();
+        let is_path_of = |index: usize| {
+            let mut items = proof.0.iter();
+            let mut curr_index = index;
+            for layer in &layers[..layers.len() - 1] {
+                if let Some(sibling) = Self::get_proof_item(layer, curr_index) {
+                    if items.next() != Some(&sibling) {
+                        return false;
+                    }
+                }
+                curr_index /= 2;
+            }
+            items.next().is_none()
+        };
+        layers[0].iter().enumerate().any(|(index, leaf)| *leaf == hash && is_path_of(index))
+    }
+
+    /// Same as `verify`, but also checks that the position implied by the directions of the proof items is `claimed_index`
+    /// (see `MerkleProof::implied_index_in`).
+    pub fn verify_at_index(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>, claimed_index: usize) -> bool {
+        proof.implied_index_in(self.num_leaves()) == Some(claimed_index) && self.verify(value, proof)
+    }
+
+    /// Verifies a Merkle proof for the leaf with the given value against the root of this tree.
+    /// Only the position of the leaf, the size of the tree and the padding strategy are taken from the tree; the proof itself is
+    /// folded independently by `verify_root` (or by `MerkleProof::verify_sorted` in sorted-pair mode).
+    pub fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        if self.sorted_pairs {
+            return self.get_leaf_index(value).is_some() && proof.verify_sorted::<H>(&H::tagged_hash(&self.leaf_tag, value), &self.get_root(), &self.branch_tag);
+        }
+        match self.get_leaf_index(value) {
+            Some(index) => verify_root::<HASH_SIZE, H>(value, proof, index, self.num_leaves(), &self.get_root(), &self.leaf_tag, &self.branch_tag, self.padding),
+            None => false
         }
     }
 }
 
-impl<const HASH_SIZE: usize> Serialize for MerkleProofItem<HASH_SIZE> {
+/*
+ * Building a tree with `build` takes all the leaf values at once, so they must all be in memory together. When the
+ * leaves are large (e.g. documents rather than balances), the builder below hashes each leaf as soon as it is pushed
+ * and keeps only the hash, so the values can be read and dropped one at a time.
+ */
+pub struct MerkleTreeBuilder<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
+    leaf_hashes: Vec<[u8; HASH_SIZE]>,
+    prepared_leaf_tag: PreparedTag<HASH_SIZE>,
+    leaf_tag: Vec<u8>,
+    branch_tag: Vec<u8>,
+    padding: Padding,
+    sorted_pairs: bool,
+    _hasher: std::marker::PhantomData<H>
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MerkleTreeBuilder<HASH_SIZE, H> {
+    /// Creates a builder with no leaves, for a tree with the given tags and the options of `build` (duplicate padding,
+    /// unsorted pairs).
+    pub fn new(leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MerkleTreeBuilder<HASH_SIZE, H> {
+        MerkleTreeBuilder {
+            leaf_hashes: Vec::new(),
+            prepared_leaf_tag: H::prepare_tag(&leaf_tag),
+            leaf_tag,
+            branch_tag,
+            padding: Padding::Duplicate,
+            sorted_pairs: false,
+            _hasher: std::marker::PhantomData
+        }
+    }
+
+    /// Sets the padding strategy of the tree (see `MerkleTree::build_with_padding`).
+    pub fn padding(mut self, padding: Padding) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Sets whether the tree is built in sorted-pair mode (see `MerkleTree::build_with_options`).
+    pub fn sorted_pairs(mut self, sorted_pairs: bool) -> Self {
+        self.sorted_pairs = sorted_pairs;
+        self
+    }
+
+    /// Hashes the value (unhashed!) of the next leaf and returns its index. Only the hash is kept.
+    pub fn push_leaf(&mut self, value: &[u8]) -> usize {
+        self.leaf_hashes.push(self.prepared_leaf_tag.hash(value));
+        self.leaf_hashes.len() - 1
+    }
+
+    /// Pushes the hash of the next leaf, already hashed with the leaf tag, and returns its index.
+    pub fn push_leaf_hash(&mut self, hash: [u8; HASH_SIZE]) -> usize {
+        self.leaf_hashes.push(hash);
+        self.leaf_hashes.len() - 1
+    }
+
+    /// Returns the number of leaves pushed so far.
+    pub fn num_leaves(&self) -> usize {
+        self.leaf_hashes.len()
+    }
+
+    /// Builds the tree from the leaves pushed so far, in order. The tree is the same as if it were built from all
+    /// their values at once.
+    pub fn finish(self) -> MerkleTree<HASH_SIZE, H> {
+        MerkleTree::from_leaf_hashes(self.leaf_hashes, self.leaf_tag, self.branch_tag, self.padding, self.sorted_pairs)
+    }
+}
+
+/*
+ * In a Bitcoin block, the leaves of the Merkle tree are the transaction IDs themselves (they are not hashed again),
+ * and the last node of a layer with an odd number of nodes is paired with itself, as in our trees. Bitcoin displays
+ * hashes (transaction IDs as well as the Merkle root) in reversed byte order, i.e., as little-endian numbers,
+ * so we reverse the bytes on the way in and out.
+ */
+impl MerkleTree<32, DoubleSha256Algorithm> {
+    /// Builds the Merkle tree of a Bitcoin block from its transaction IDs, given in the usual display (reversed) byte order.
+    pub fn build_bitcoin(txids: &[[u8; 32]]) -> MerkleTree<32, DoubleSha256Algorithm> {
+        let leaf_hashes = txids.iter().map(|txid| {
+            let mut hash = *txid;
+            hash.reverse();
+            hash
+        }).collect();
+        Self::from_leaf_hashes(leaf_hashes, Vec::new(), Vec::new(), Padding::Duplicate, false)
+    }
+
+    /// Returns the Merkle root in Bitcoin's display (reversed) byte order, as found in block explorers.
+    pub fn get_bitcoin_root(&self) -> [u8; 32] {
+        let mut root = self.get_root().0;
+        root.reverse();
+        root
+    }
+}
+
+/*
+ * A server holding several pools (each with its own tree) can commit to all of them at once with a small Merkle tree
+ * whose leaves are the pool roots themselves (they are not hashed again), so a single aggregate root is published.
+ */
+/// Aggregates the roots of several pools into a single commitment, using `tag` for hashing the branch nodes.
+/// Panics if no root is given.
+pub fn aggregate_roots<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(roots: &[MerkleRoot<HASH_SIZE>], tag: &[u8]) -> MerkleRoot<HASH_SIZE> {
+    assert!(!roots.is_empty(), "no roots to aggregate");
+    let leaf_hashes = roots.iter().map(|root| root.0).collect();
+    MerkleTree::<HASH_SIZE, H>::from_leaf_hashes(leaf_hashes, tag.to_vec(), tag.to_vec(), Padding::Duplicate, false).get_root()
+}
+
+/*
+ * A multiproof proves several leaves at once. Walking up the tree layer by layer, we only include the
+ * siblings that cannot be computed from the proven leaves themselves, so leaves that share paths share
+ * siblings. The siblings are listed layer by layer, from left to right within a layer.
+ *
+ * `indices` are the positions of the proven leaves, in the same order as the values they were requested
+ * for (so the verifier can match values to positions).
+ */
+#[derive(Debug, PartialEq, Eq)]
+pub struct MultiProof<const HASH_SIZE: usize> {
+    pub indices: Vec<usize>,
+    pub num_leaves: usize,
+    pub siblings: Vec<[u8; HASH_SIZE]>,
+}
+
+// Walks up the tree from the given leaves (sorted by index, without duplicates), calling `step` for each known node
+// to compute its parent. `step` is given the layer, the layer width, the known nodes of the layer, the position of the
+// node among them, and whether the node's sibling is known as well (in which case it is the next known node).
+fn walk_multiproof<T: Copy>(mut known: Vec<(usize, T)>, num_leaves: usize, mut step: impl FnMut(usize, usize, &[(usize, T)], usize, bool) -> Option<T>) -> Option<T> {
+    let mut layer = 0;
+    let mut width = num_leaves;
+    while width > 1 {
+        let mut parents = Vec::with_capacity(known.len());
+        let mut k = 0;
+        while k < known.len() {
+            let index = known[k].0;
+            let sibling_known = index.is_multiple_of(2) && k + 1 < known.len() && known[k + 1].0 == index + 1;
+            let parent = step(layer, width, &known, k, sibling_known)?;
+            parents.push((index / 2, parent));
+            k += if sibling_known { 2 } else { 1 };
+        }
+        known = parents;
+        layer += 1;
+        width = width.div_ceil(2);
+    }
+    known.pop().map(|(_, root)| root)
+}
+
+impl<const HASH_SIZE: usize> MultiProof<HASH_SIZE> {
+    /// Folds the multiproof starting from the given leaf values, which must be in the same order as `indices`.
+    /// Lone nodes are carried up with the given padding strategy. Returns None if the proof is malformed.
+    pub fn compute_root<H: HashAlgorithm<HASH_SIZE>>(&self, values: &[Vec<u8>], leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> Option<MerkleRoot<HASH_SIZE>> {
+        if values.len() != self.indices.len() || values.is_empty() || self.indices.iter().any(|&i| i >= self.num_leaves) {
+            return None;
+        }
+        let leaf_tag = H::prepare_tag(leaf_tag);
+        let mut leaves: Vec<(usize, [u8; HASH_SIZE])> = self.indices.iter().zip(values)
+            .map(|(&index, value)| (index, leaf_tag.hash(value)))
+            .collect();
+        leaves.sort_by_key(|(index, _)| *index);
+        // the same index may be requested twice, but then the values must agree
+        for pair in leaves.windows(2) {
+            if pair[0].0 == pair[1].0 && pair[0].1 != pair[1].1 {
+                return None;
+            }
+        }
+        leaves.dedup_by_key(|(index, _)| *index);
+
+        let branch_tag = H::prepare_tag(branch_tag);
+        let mut siblings = self.siblings.iter();
+        let root = walk_multiproof(leaves, self.num_leaves, |_, width, known, k, sibling_known| {
+            let (index, hash) = known[k];
+            let concatenated = if sibling_known {
+                [hash, known[k + 1].1].concat()
+            } else if index.is_multiple_of(2) && index == width - 1 {
+                return Some(padding.lone_parent(hash, &branch_tag));
+            } else if index.is_multiple_of(2) {
+                [hash, *siblings.next()?].concat()
+            } else {
+                [*siblings.next()?, hash].concat()
+            };
+            Some(branch_tag.hash(&concatenated))
+        })?;
+        match siblings.next() {
+            Some(_) => None, // there are leftover siblings
+            None => Some(MerkleRoot(root))
+        }
+    }
+}
+
+/// Verifies that the multiproof for the given leaf values (in the same order as `proof.indices`) folds to the given root.
+pub fn verify_multiproof<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(values: &[Vec<u8>], proof: &MultiProof<HASH_SIZE>, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> bool {
+    match proof.compute_root::<H>(values, leaf_tag, branch_tag, padding) {
+        Some(computed) => computed.0 == root.0,
+        None => false
+    }
+}
+
+/*
+ * Lone nodes (the last node of a layer with an odd number of nodes) are either paired with themselves or
+ * promoted unchanged, depending on the padding strategy, and do not contribute an item to the proof. Hence the
+ * verifier needs to know the index of the leaf, the number of leaves in the tree and the padding strategy to tell
+ * at which levels the current node is lone and how it is carried up.
+ */
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Folds the proof starting from the leaf with the given value, which is at position `index` in a tree
+    /// with `num_leaves` leaves built with the given padding strategy. Returns None if the proof is malformed, i.e.,
+    /// it has the wrong length or a proof item is on the wrong side of the current node.
+    pub fn compute_root<H: HashAlgorithm<HASH_SIZE>>(&self, value: &[u8], index: usize, num_leaves: usize, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> Option<MerkleRoot<HASH_SIZE>> {
+        self.fold::<H>(value, index, num_leaves, leaf_tag, branch_tag, padding, false).map(|(_, hash)| MerkleRoot(hash))
+    }
+
+    /// Returns the proof made of the first `n` items of this one (all of them if there are fewer), i.e., the proof
+    /// of the leaf within the subtree whose root is the node reached by folding these items.
+    pub fn prefix(&self, n: usize) -> MerkleProof<HASH_SIZE> {
+        MerkleProof(self.0.iter().take(n).cloned().collect())
+    }
+
+    /// Same as `compute_root`, but stops as soon as all the items have been folded instead of requiring the proof to
+    /// reach the root, which is useful to check the hash of a subtree (e.g. with a `prefix` of a proof). Returns the
+    /// level of the node reached (0 for the leaf) and its hash, or None if an item is on the wrong side of the
+    /// current node or there are more items than levels.
+    pub fn compute_partial_root<H: HashAlgorithm<HASH_SIZE>>(&self, value: &[u8], index: usize, num_leaves: usize, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> Option<(usize, [u8; HASH_SIZE])> {
+        self.fold::<H>(value, index, num_leaves, leaf_tag, branch_tag, padding, true)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fold<H: HashAlgorithm<HASH_SIZE>>(&self, value: &[u8], index: usize, num_leaves: usize, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding, partial: bool) -> Option<(usize, [u8; HASH_SIZE])> {
+        fold_items::<HASH_SIZE, H, _>(value, index, num_leaves, leaf_tag, branch_tag, padding, self.0.iter(), partial)
+    }
+}
+
+// folds the items level by level, up to the root or, if `partial`, only until the items run out
+#[allow(clippy::too_many_arguments)]
+fn fold_items<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>, I: Borrow<MerkleProofItem<HASH_SIZE>>>(value: &[u8], index: usize, num_leaves: usize, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding, items: impl Iterator<Item = I>, partial: bool) -> Option<(usize, [u8; HASH_SIZE])> {
+    if index >= num_leaves {
+        return None;
+    }
+    let mut hash = H::tagged_hash(leaf_tag, value);
+    // the branch tag is prepared once for all the levels, as when building
+    let branch_tag = H::prepare_tag(branch_tag);
+    let mut items = items.peekable();
+    let mut curr_index = index;
+    let mut width = num_leaves;
+    let mut level = 0;
+    while width > 1 && !(partial && items.peek().is_none()) {
+        hash = if curr_index.is_multiple_of(2) && curr_index == width - 1 {
+            padding.lone_parent(hash, &branch_tag)
+        } else {
+            match (curr_index % 2, items.next()?.borrow()) {
+                (1, MerkleProofItem::Left(sibling)) => branch_tag.hash_pair(sibling, &hash),
+                (0, MerkleProofItem::Right(sibling)) => branch_tag.hash_pair(&hash, sibling),
+                _ => return None
+            }
+        };
+        curr_index /= 2;
+        width = width.div_ceil(2);
+        level += 1;
+    }
+    match items.next() {
+        Some(_) => None, // there are leftover items
+        None => Some((level, hash))
+    }
+}
+
+// the directions of the proof items of the leaf at position `index` in a tree with `num_leaves` leaves (true for a
+// left item), skipping the levels at which the node is lone
+fn item_directions(index: usize, num_leaves: usize) -> Vec<bool> {
+    let mut directions = Vec::new();
+    let (mut curr_index, mut width) = (index, num_leaves);
+    while width > 1 {
+        if !(curr_index.is_multiple_of(2) && curr_index == width - 1) {
+            directions.push(curr_index % 2 == 1);
+        }
+        curr_index /= 2;
+        width = width.div_ceil(2);
+    }
+    directions
+}
+
+/*
+ * The directions of the proof items spell out the position of the leaf in binary, from the least significant bit:
+ * a left item means the current node is a right child (bit 1). Lone nodes contribute no item, so in a tree whose size
+ * is not a power of two some bits may be missing; however, at the first lone node on the path, all the remaining
+ * bits of the index equal those of the last leaf, so the index can still be recovered given the size of the tree.
+ */
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Reconstructs the position of the leaf from the directions of the items, assuming that no level was skipped,
+    /// which holds for trees whose number of leaves is a power of two and for proofs from `get_proof_explicit`.
+    /// Returns None if the proof has more items than an index has bits, so that it cannot come from any tree.
+    pub fn implied_index(&self) -> Option<usize> {
+        if self.0.len() > usize::BITS as usize {
+            return None;
+        }
+        self.0.iter().enumerate().try_fold(0, |index, (level, item)| match item {
+            MerkleProofItem::Left(_) => Some(index | 1usize.checked_shl(level as u32)?),
+            MerkleProofItem::Right(_) => Some(index)
+        })
+    }
+
+    /// Reconstructs the position of the leaf in a tree with `num_leaves` leaves, taking the skipped levels of lone
+    /// nodes into account. Returns None if no leaf of such a tree has a proof with these directions.
+    pub fn implied_index_in(&self, num_leaves: usize) -> Option<usize> {
+        let directions: Vec<bool> = self.0.iter().map(|item| matches!(item, MerkleProofItem::Left(_))).collect();
+        let matches = |index: usize| index < num_leaves && item_directions(index, num_leaves) == directions;
+        let last = num_leaves.checked_sub(1)?;
+        if directions.len() >= usize::BITS as usize {
+            return None;
+        }
+        let mut low_bits = 0;
+        for (level, &left) in directions.iter().enumerate() {
+            // the node is lone at this level, so the higher bits are those of the last leaf
+            let candidate = ((last >> level) << level) | low_bits;
+            if matches(candidate) {
+                return Some(candidate);
+            }
+            low_bits |= (left as usize) << level;
+        }
+        let level = directions.len();
+        [((last >> level) << level) | low_bits, low_bits].into_iter().find(|&candidate| matches(candidate))
+    }
+}
+
+/*
+ * Certificate Transparency (RFC 6962) represents an inclusion proof as an audit path: the bare sibling hashes from
+ * the leaf up, whose directions are implied by the index of the leaf and the size of the tree. Trees padded with
+ * Padding::Promote have the shape of RFC 6962 trees (the left subtree of each node is the largest perfect one), so
+ * their proofs carry over to CT tooling, although the nodes are tagged hashes rather than RFC 6962 hashes.
+ */
+/// A proof in the RFC 6962 audit-path form.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuditPath<const HASH_SIZE: usize> {
+    pub leaf_index: usize,
+    pub tree_size: usize,
+    pub audit_path: Vec<[u8; HASH_SIZE]>,
+}
+
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Returns the audit path of the proof for the leaf at position `leaf_index` in a tree with `tree_size` leaves,
+    /// which drops the directions of the items since they are implied by both.
+    pub fn to_audit_path(&self, leaf_index: usize, tree_size: usize) -> AuditPath<HASH_SIZE> {
+        let audit_path = self.0.iter().map(|item| match item {
+            MerkleProofItem::Left(hash) | MerkleProofItem::Right(hash) => *hash
+        }).collect();
+        AuditPath { leaf_index, tree_size, audit_path }
+    }
+}
+
+impl<const HASH_SIZE: usize> AuditPath<HASH_SIZE> {
+    /// Restores the directions of the items, or returns None if the audit path does not have the length of the
+    /// proofs of the leaf (or there is no such leaf).
+    pub fn to_proof(&self) -> Option<MerkleProof<HASH_SIZE>> {
+        if self.leaf_index >= self.tree_size {
+            return None;
+        }
+        let directions = item_directions(self.leaf_index, self.tree_size);
+        if directions.len() != self.audit_path.len() {
+            return None;
+        }
+        Some(MerkleProof(directions.into_iter().zip(&self.audit_path).map(|(left, hash)| match left {
+            true => MerkleProofItem::Left(*hash),
+            false => MerkleProofItem::Right(*hash)
+        }).collect()))
+    }
+}
+
+// hashes are base64-encoded, as in the JSON API of CT logs
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for AuditPath<HASH_SIZE> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
-        match self {
-            MerkleProofItem::Left(hash) => {
-                let mut seq = serializer.serialize_seq(Some(2))?;
-                seq.serialize_element(&0)?;
-                seq.serialize_element(&format!("0x{}", HEXLOWER.encode(hash)))?;
-                seq.end()
-            },
-            MerkleProofItem::Right(hash) => {
-                let mut seq = serializer.serialize_seq(Some(2))?;
-                seq.serialize_element(&1)?;
-                seq.serialize_element(&format!("0x{}", HEXLOWER.encode(hash)))?;
-                seq.end()
+        let audit_path: Vec<String> = self.audit_path.iter().map(|hash| BASE64.encode(hash)).collect();
+        let mut path = serializer.serialize_struct("AuditPath", 3)?;
+        path.serialize_field("leaf_index", &self.leaf_index)?;
+        path.serialize_field("tree_size", &self.tree_size)?;
+        path.serialize_field("audit_path", &audit_path)?;
+        path.end()
+    }
+}
+
+/*
+ * In sorted-pair mode (as in OpenZeppelin's MerkleProof.verify), the two children are sorted before being hashed,
+ * so the parent does not depend on which child is on the left. A verifier then needs neither the directions nor
+ * the position of the leaf, at the cost of not proving the position.
+ */
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Verifies the proof in sorted-pair mode: starting from the given (already hashed) leaf, each sibling is sorted
+    /// together with the current node before hashing them, ignoring the direction of the proof items.
+    pub fn verify_sorted<H: HashAlgorithm<HASH_SIZE>>(&self, leaf_hash: &[u8; HASH_SIZE], root: &MerkleRoot<HASH_SIZE>, branch_tag: &[u8]) -> bool {
+        let branch_tag = H::prepare_tag(branch_tag);
+        let computed = self.0.iter().fold(*leaf_hash, |hash, item| {
+            let (MerkleProofItem::Left(sibling) | MerkleProofItem::Right(sibling)) = item;
+            if hash <= *sibling {
+                branch_tag.hash_pair(&hash, sibling)
+            } else {
+                branch_tag.hash_pair(sibling, &hash)
             }
-        }
+        });
+        computed == root.0
+    }
+}
+
+/// Verifies that the proof for the leaf with the given value (at position `index` in a tree with `num_leaves` leaves,
+/// built with the given padding strategy) folds to the given root.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_root<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(value: &[u8], proof: &MerkleProof<HASH_SIZE>, index: usize, num_leaves: usize, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> bool {
+    match proof.compute_root::<H>(value, index, num_leaves, leaf_tag, branch_tag, padding) {
+        Some(computed) => computed.0 == root.0,
+        None => false
     }
 }
 
+/// Same as `verify_root`, but takes the proof items from an iterator (e.g. as they arrive over the network), folding
+/// them one at a time instead of collecting them into a `MerkleProof` first.
+#[allow(clippy::too_many_arguments)]
+pub fn verify_stream<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(value: &[u8], items: impl Iterator<Item = MerkleProofItem<HASH_SIZE>>, index: usize, num_leaves: usize, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8], padding: Padding) -> bool {
+    match fold_items::<HASH_SIZE, H, _>(value, index, num_leaves, leaf_tag, branch_tag, padding, items, false) {
+        Some((_, computed)) => computed == root.0,
+        None => false
+    }
+}
+
+// hashes are serialized as lowercase hex, with the 0x prefix unless bare
+#[cfg(feature = "serde")]
+fn hex_hash(hash: &[u8], bare: bool) -> String {
+    let prefix = if bare { "" } else { "0x" };
+    format!("{}{}", prefix, HEXLOWER.encode(hash))
+}
+
+#[cfg(feature = "serde")]
+fn serialize_item<S: serde::Serializer, const HASH_SIZE: usize>(item: &MerkleProofItem<HASH_SIZE>, serializer: S, bare: bool) -> Result<S::Ok, S::Error> {
+    let (direction, hash) = item.to_parts();
+    let mut seq = serializer.serialize_seq(Some(2))?;
+    seq.serialize_element(&direction)?;
+    seq.serialize_element(&hex_hash(hash, bare))?;
+    seq.end()
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for MerkleProofItem<HASH_SIZE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serialize_item(self, serializer, false)
+    }
+}
+
+#[cfg(feature = "serde")]
 impl<const HASH_SIZE: usize> Serialize for MerkleRoot<HASH_SIZE> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: serde::Serializer {
-        serializer.serialize_str(&format!("0x{}", HEXLOWER.encode(&self.0)))
+        serializer.serialize_str(&hex_hash(&self.0, false))
+    }
+}
+
+/*
+ * Hashes are serialized with the 0x prefix by default. Verifiers that want bare hex can serialize roots and proofs
+ * through this wrapper instead, which renders the same hashes without the prefix.
+ */
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct BareHex<'a, T>(pub &'a T);
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> MerkleRoot<HASH_SIZE> {
+    /// Returns a view of the root that serializes it as hex without the 0x prefix.
+    pub fn as_bare_hex(&self) -> BareHex<'_, MerkleRoot<HASH_SIZE>> {
+        BareHex(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Returns a view of the proof that serializes its hashes as hex without the 0x prefix.
+    pub fn as_bare_hex(&self) -> BareHex<'_, MerkleProof<HASH_SIZE>> {
+        BareHex(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for BareHex<'_, MerkleRoot<HASH_SIZE>> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.serialize_str(&hex_hash(&self.0.0, true))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for BareHex<'_, MerkleProofItem<HASH_SIZE>> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serialize_item(self.0, serializer, true)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for BareHex<'_, MerkleProof<HASH_SIZE>> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        let items = &(self.0).0;
+        let mut seq = serializer.serialize_seq(Some(items.len()))?;
+        for item in items {
+            seq.serialize_element(&BareHex(item))?;
+        }
+        seq.end()
+    }
+}
+
+/*
+ * The compact binary form of a proof is a 4-byte big-endian item count, followed by each item as a direction
+ * byte (`LEFT` or `RIGHT`, as in the JSON form) and the HASH_SIZE bytes of the hash.
+ */
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Encodes the proof in the compact binary form.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.0.len() * (1 + HASH_SIZE));
+        bytes.extend_from_slice(&(self.0.len() as u32).to_be_bytes());
+        for item in &self.0 {
+            let (direction, hash) = item.to_parts();
+            bytes.push(direction);
+            bytes.extend_from_slice(hash);
+        }
+        bytes
+    }
+
+    /// Decodes a proof from the compact binary form, or returns None if the bytes are malformed.
+    pub fn from_compact(bytes: &[u8]) -> Option<MerkleProof<HASH_SIZE>> {
+        let (count, items) = bytes.split_first_chunk::<4>()?;
+        let count = u32::from_be_bytes(*count) as usize;
+        if items.len() != count.checked_mul(1 + HASH_SIZE)? {
+            return None;
+        }
+        let proof = items.chunks_exact(1 + HASH_SIZE)
+            .map(|item| MerkleProofItem::from_direction(item[0], item[1..].try_into().unwrap()))
+            .collect::<Option<Vec<_>>>()?;
+        Some(MerkleProof(proof))
+    }
+
+    /// Splits the proof into a byte per item giving its direction (`LEFT` or `RIGHT`, as in the compact form) and the
+    /// concatenation of the hashes of the items, e.g. to pass it across an FFI boundary.
+    pub fn flatten(&self) -> (Vec<u8>, Vec<u8>) {
+        let mut directions = Vec::with_capacity(self.0.len());
+        let mut hashes = Vec::with_capacity(self.0.len() * HASH_SIZE);
+        for item in &self.0 {
+            let (direction, hash) = item.to_parts();
+            directions.push(direction);
+            hashes.extend_from_slice(hash);
+        }
+        (directions, hashes)
+    }
+
+    /// Rebuilds a proof from its flattened form (see `flatten`), or returns None if a direction is neither `LEFT` nor
+    /// `RIGHT` or the hashes are not one per direction.
+    pub fn from_flattened(directions: &[u8], hashes: &[u8]) -> Option<MerkleProof<HASH_SIZE>> {
+        if hashes.len() != directions.len().checked_mul(HASH_SIZE)? {
+            return None;
+        }
+        let proof = directions.iter().zip(hashes.chunks_exact(HASH_SIZE))
+            .map(|(&direction, hash)| MerkleProofItem::from_direction(direction, hash.try_into().unwrap()))
+            .collect::<Option<Vec<_>>>()?;
+        Some(MerkleProof(proof))
+    }
+}
+
+/*
+ * The hex blob form of a proof is a single string, for systems storing proofs as opaque values: "0x", followed by a
+ * bitmap of the directions (bit i % 8 of byte i / 8 set if item i is `RIGHT`, the unused bits of the last byte clear)
+ * and the concatenated hashes of the items, all in lowercase hex. The number of items is implied by the length.
+ */
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Encodes the proof in the hex blob form.
+    pub fn to_hex_blob(&self) -> String {
+        let mut bytes = vec![0; self.0.len().div_ceil(8)];
+        for (i, item) in self.0.iter().enumerate() {
+            let (direction, hash) = item.to_parts();
+            bytes[i / 8] |= direction << (i % 8);
+            bytes.extend_from_slice(hash);
+        }
+        format!("0x{}", HEXLOWER.encode(&bytes))
+    }
+
+    /// Decodes a proof from the hex blob form, or returns None if the string is malformed.
+    pub fn from_hex_blob(blob: &str) -> Option<MerkleProof<HASH_SIZE>> {
+        let bytes = HEXLOWER.decode(blob.strip_prefix("0x")?.as_bytes()).ok()?;
+        let count = (0..=bytes.len() / HASH_SIZE).find(|count| count.div_ceil(8) + count * HASH_SIZE == bytes.len())?;
+        let (bitmap, hashes) = bytes.split_at(count.div_ceil(8));
+        if count % 8 != 0 && bitmap[bitmap.len() - 1] >> (count % 8) != 0 {
+            return None;
+        }
+        let proof = hashes.chunks_exact(HASH_SIZE).enumerate()
+            .map(|(i, hash)| MerkleProofItem::from_direction((bitmap[i / 8] >> (i % 8)) & 1, hash.try_into().unwrap()).unwrap())
+            .collect();
+        Some(MerkleProof(proof))
+    }
+}
+
+/*
+ * The default serialization of a proof item is the compact tuple [0 or 1, "0x..."]. Clients that prefer
+ * named fields can serialize a proof through this wrapper instead, which renders each item as
+ * { "position": "left" or "right", "hash": "0x..." }.
+ */
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct ObjectProof<'a, const HASH_SIZE: usize>(pub &'a MerkleProof<HASH_SIZE>);
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> MerkleProof<HASH_SIZE> {
+    /// Returns a view of the proof that serializes its items as objects with named fields.
+    pub fn as_objects(&self) -> ObjectProof<'_, HASH_SIZE> {
+        ObjectProof(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct ObjectProofItem<'a, const HASH_SIZE: usize>(&'a MerkleProofItem<HASH_SIZE>);
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for ObjectProofItem<'_, HASH_SIZE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        let (position, hash) = match self.0 {
+            MerkleProofItem::Left(hash) => ("left", hash),
+            MerkleProofItem::Right(hash) => ("right", hash)
+        };
+        let mut item = serializer.serialize_struct("MerkleProofItem", 2)?;
+        item.serialize_field("position", position)?;
+        item.serialize_field("hash", &hex_hash(hash, false))?;
+        item.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const HASH_SIZE: usize> Serialize for ObjectProof<'_, HASH_SIZE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        let items = &(self.0).0;
+        let mut seq = serializer.serialize_seq(Some(items.len()))?;
+        for item in items {
+            seq.serialize_element(&ObjectProofItem(item))?;
+        }
+        seq.end()
     }
 }
\ No newline at end of file
@@ -0,0 +1,250 @@
+//! A Merkle mountain range (MMR) implementation
+//!
+//! An MMR is an append-only accumulator: a list of perfect binary Merkle trees ("mountains") of
+//! strictly decreasing heights. Appending a leaf takes O(log n) hashes, and the root is obtained by
+//! "bagging" the peaks of the mountains.
+use crate::merkle::{assert_output_size, HashAlgorithm, MerkleProof, MerkleProofItem, MerkleRoot, PreparedTag};
+
+/*
+ * All nodes are stored in a single vector in post-order, which is the usual layout for MMRs: appending
+ * a leaf pushes the leaf followed by every parent node that it completes, so existing nodes never move.
+ * In a mountain of height h rooted at position r, the left child of the root is at r - 2^h and the right
+ * child at r - 1.
+ *
+ * The peaks are bagged from right to left, i.e., for peaks p0, p1, p2 (left to right) the root is
+ * H(p0 || H(p1 || p2)). This makes a proof a plain sequence of left/right siblings (the path up to the
+ * peak, then the bag of the peaks to the right, then the peaks to the left), with no padding.
+ */
+#[derive(Debug, Clone)]
+pub struct MmrTree<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> {
+    nodes: Vec<[u8; HASH_SIZE]>,
+    peaks: Vec<(usize, u32)>, // (position, height) of each peak, from left to right
+    num_leaves: usize,
+    leaf_tag: Vec<u8>,
+    branch_tag: Vec<u8>,
+    sorted_pairs: bool, // whether the children are sorted before hashing them (see MerkleProof::verify_sorted)
+    _hasher: std::marker::PhantomData<H>
+}
+
+// position of the i-th leaf in the post-order layout
+fn leaf_position(index: usize) -> usize {
+    2 * index - index.count_ones() as usize
+}
+
+impl<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>> MmrTree<HASH_SIZE, H> {
+    /// Creates an empty Merkle mountain range.
+    /// `leaf_tag` is the tag used for hashing the leaf nodes, and `branch_tag` is the tag used for hashing the branch nodes.
+    pub fn new(leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MmrTree<HASH_SIZE, H> {
+        assert_output_size::<HASH_SIZE, H>();
+        MmrTree {
+            nodes: Vec::new(),
+            peaks: Vec::new(),
+            num_leaves: 0,
+            leaf_tag,
+            branch_tag,
+            sorted_pairs: false,
+            _hasher: std::marker::PhantomData
+        }
+    }
+
+    /// Creates an empty Merkle mountain range in sorted-pair mode: the two children of each node (and each pair of
+    /// bagged peaks) are sorted before hashing them, so that proofs can be verified by `MerkleProof::verify_sorted`.
+    pub fn new_sorted(leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MmrTree<HASH_SIZE, H> {
+        MmrTree { sorted_pairs: true, ..MmrTree::new(leaf_tag, branch_tag) }
+    }
+
+    // hash two children into their parent, sorting them first in sorted-pair mode
+    fn hash_children_with(&self, branch_tag: &PreparedTag<HASH_SIZE>, left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+        if self.sorted_pairs && left > right {
+            branch_tag.hash_pair(right, left)
+        } else {
+            branch_tag.hash_pair(left, right)
+        }
+    }
+
+    /// Builds a Merkle mountain range by appending the given leaf values (unhashed!) in order.
+    pub fn build(values: Vec<Vec<u8>>, leaf_tag: Vec<u8>, branch_tag: Vec<u8>) -> MmrTree<HASH_SIZE, H> {
+        let mut tree = MmrTree::new(leaf_tag, branch_tag);
+        let branch_tag = H::prepare_tag(&tree.branch_tag);
+        for value in values {
+            tree.append_with(&branch_tag, &value);
+        }
+        tree
+    }
+
+    /// Appends a leaf with the given value and returns its index. Takes O(log n) hashes.
+    pub fn append(&mut self, value: &[u8]) -> usize {
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        self.append_with(&branch_tag, value)
+    }
+
+    fn append_with(&mut self, branch_tag: &PreparedTag<HASH_SIZE>, value: &[u8]) -> usize {
+        let mut hash = H::tagged_hash(&self.leaf_tag, value);
+        let mut height = 0;
+        self.nodes.push(hash);
+        // merge with the mountains to the left as long as they have the same height
+        while let Some(&(pos, peak_height)) = self.peaks.last() {
+            if peak_height != height {
+                break;
+            }
+            self.peaks.pop();
+            hash = self.hash_children_with(branch_tag, &self.nodes[pos], &hash);
+            self.nodes.push(hash);
+            height += 1;
+        }
+        self.peaks.push((self.nodes.len() - 1, height));
+        self.num_leaves += 1;
+        self.num_leaves - 1
+    }
+
+    /// Returns the number of leaves in the Merkle mountain range.
+    pub fn num_leaves(&self) -> usize {
+        self.num_leaves
+    }
+
+    /// Returns the number of nodes at each height, from the leaves up, summed over the mountains (the bagging of the
+    /// peaks is not stored, so it is not counted).
+    pub fn layer_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![0; self.peaks.first().map_or(0, |&(_, height)| height as usize + 1)];
+        for &(_, height) in &self.peaks {
+            for (level, size) in sizes.iter_mut().take(height as usize + 1).enumerate() {
+                *size += 1 << (height as usize - level);
+            }
+        }
+        sizes
+    }
+
+    /// Returns the tag used for hashing the leaf nodes.
+    pub fn leaf_tag(&self) -> &[u8] {
+        &self.leaf_tag
+    }
+
+    /// Returns the tag used for hashing the branch nodes.
+    pub fn branch_tag(&self) -> &[u8] {
+        &self.branch_tag
+    }
+
+    /// Returns whether the children of each node are sorted before hashing them.
+    pub fn sorted_pairs(&self) -> bool {
+        self.sorted_pairs
+    }
+
+    // bag the given peaks from right to left
+    fn bag_peaks(&self, peaks: &[(usize, u32)]) -> Option<[u8; HASH_SIZE]> {
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        peaks.iter().rev().map(|&(pos, _)| self.nodes[pos]).reduce(|bag, peak| self.hash_children_with(&branch_tag, &peak, &bag))
+    }
+
+    /// Returns the root of the Merkle mountain range, i.e., the bagged peaks.
+    ///
+    /// # Panics
+    ///
+    /// If the Merkle mountain range is empty. Use `try_get_root` for ranges that may be empty.
+    pub fn get_root(&self) -> MerkleRoot<HASH_SIZE> {
+        self.try_get_root().expect("the Merkle mountain range is empty")
+    }
+
+    /// Same as `get_root`, but returns None if the Merkle mountain range is empty instead of panicking.
+    pub fn try_get_root(&self) -> Option<MerkleRoot<HASH_SIZE>> {
+        self.bag_peaks(&self.peaks).map(MerkleRoot)
+    }
+
+    // Find the mountain containing the leaf with the given index, and return the index of its peak together with
+    // the (position, height) of each node on the path from the peak down to the leaf
+    fn descend(&self, index: usize) -> (usize, Vec<(usize, u32)>) {
+        let mut first_leaf = 0;
+        let mut peak_index = 0;
+        for (i, &(_, height)) in self.peaks.iter().enumerate() {
+            if index < first_leaf + (1 << height) {
+                peak_index = i;
+                break;
+            }
+            first_leaf += 1 << height;
+        }
+
+        let (mut pos, mut height) = self.peaks[peak_index];
+        let mut offset = index - first_leaf;
+        let mut path = vec![(pos, height)];
+        while height > 0 {
+            let half = 1 << (height - 1);
+            if offset < half {
+                pos -= 1 << height; // left child
+            } else {
+                pos -= 1; // right child
+                offset -= half;
+            }
+            height -= 1;
+            path.push((pos, height));
+        }
+        (peak_index, path)
+    }
+
+    // build the proof by descending from the peak to the leaf, then adding the other peaks
+    fn build_proof(&self, index: usize) -> MerkleProof<HASH_SIZE> {
+        let (peak_index, descent) = self.descend(index);
+        let mut path = Vec::new();
+        for pair in descent.windows(2) {
+            let ((parent, height), (child, _)) = (pair[0], pair[1]);
+            let left = parent - (1 << height);
+            if child == left {
+                path.push(MerkleProofItem::Right(self.nodes[parent - 1]));
+            } else {
+                path.push(MerkleProofItem::Left(self.nodes[left]));
+            }
+        }
+        // the path was built top-down, but proofs go bottom-up
+        path.reverse();
+
+        if let Some(bag) = self.bag_peaks(&self.peaks[(peak_index + 1)..]) {
+            path.push(MerkleProofItem::Right(bag));
+        }
+        for &(pos, _) in self.peaks[..peak_index].iter().rev() {
+            path.push(MerkleProofItem::Left(self.nodes[pos]));
+        }
+        MerkleProof(path)
+    }
+
+    /// Given a value, return the Merkle proof for the leaf with that value if
+    /// the value is in the Merkle mountain range, or None if it is not.
+    pub fn get_proof(&self, value: Vec<u8>) -> Option<MerkleProof<HASH_SIZE>> {
+        let hash: [u8; HASH_SIZE] = H::tagged_hash(&self.leaf_tag, &value);
+        (0..self.num_leaves)
+            .position(|i| self.nodes[leaf_position(i)] == hash)
+            .map(|index| self.build_proof(index))
+    }
+
+    /// Replaces the value of the leaf with the given index, rehashing only the path up to its peak.
+    pub fn update_leaf(&mut self, index: usize, value: &[u8]) {
+        let (_, descent) = self.descend(index);
+        let (leaf, _) = *descent.last().unwrap();
+        self.nodes[leaf] = H::tagged_hash(&self.leaf_tag, value);
+        let branch_tag = H::prepare_tag(&self.branch_tag);
+        for &(pos, height) in descent.iter().rev().skip(1) {
+            self.nodes[pos] = self.hash_children_with(&branch_tag, &self.nodes[pos - (1 << height)], &self.nodes[pos - 1]);
+        }
+    }
+
+    /// Verifies a Merkle proof for the leaf with the given value against the current root.
+    pub fn verify(&self, value: &[u8], proof: &MerkleProof<HASH_SIZE>) -> bool {
+        if self.peaks.is_empty() {
+            false
+        } else if self.sorted_pairs {
+            proof.verify_sorted::<H>(&H::tagged_hash(&self.leaf_tag, value), &self.get_root(), &self.branch_tag)
+        } else {
+            verify_root::<HASH_SIZE, H>(value, proof, &self.get_root(), &self.leaf_tag, &self.branch_tag)
+        }
+    }
+}
+
+/// Verifies that the proof for the leaf with the given value folds to the given root of a Merkle mountain range.
+/// Unlike proofs of `MerkleTree`, MMR proofs contain no padding, so neither the leaf index nor the size is needed.
+pub fn verify_root<const HASH_SIZE: usize, H: HashAlgorithm<HASH_SIZE>>(value: &[u8], proof: &MerkleProof<HASH_SIZE>, root: &MerkleRoot<HASH_SIZE>, leaf_tag: &[u8], branch_tag: &[u8]) -> bool {
+    let branch_tag = H::prepare_tag(branch_tag);
+    let computed = proof.0.iter().fold(H::tagged_hash(leaf_tag, value), |hash, item| {
+        match item {
+            MerkleProofItem::Left(sibling) => branch_tag.hash_pair(sibling, &hash),
+            MerkleProofItem::Right(sibling) => branch_tag.hash_pair(&hash, sibling)
+        }
+    });
+    computed == root.0
+}
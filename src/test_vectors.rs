@@ -0,0 +1,43 @@
+//! Known answers of tagged hashes, to validate implementations of `HashAlgorithm<32>` against
+//!
+//! Each vector is a tag, the data hashed with it and the expected hash in hex. The expected hashes were computed
+//! independently of the crate (e.g. in Python, with hashlib), as SHA256(SHA256(tag) || SHA256(tag) || data), the
+//! BIP340 construction of `Sha256Algorithm`. A new algorithm gets its own vectors, checked with `check_vectors`.
+use crate::merkle::{DigestAlgorithm, HashAlgorithm, Sha256Algorithm};
+use data_encoding::HEXLOWER;
+
+pub struct TestVector {
+    pub tag: &'static [u8],
+    pub data: &'static [u8],
+    pub expected: &'static str,
+}
+
+pub const SHA256_VECTORS: &[TestVector] = &[
+    // the empty tag and data
+    TestVector { tag: b"", data: b"", expected: "2dba5dbc339e7316aea2683faf839c1b7b1ee2313db792112588118df066aa35" },
+    TestVector { tag: b"Leaf", data: b"aaa", expected: "57ee467d5bcca2163c9e535bbbef978f3c29c36ea45e444cb00e5701cb367351" },
+    // a leaf of test_merkle_root_two_leaves
+    TestVector { tag: b"Bitcoin_Transaction", data: b"bbb", expected: "7cdf701413062eaba020af83441a6762ee2910e36b1805bad072103b0257f441" },
+    // a user leaf (version 1) with the leaf tag of the server
+    TestVector { tag: b"ProofOfReserve_Leaf", data: b"\x01(1,1111)", expected: "02ae1f25fdac25e34aef6ea389bba10188c02979c9402caa954dd9b26e58eecb" },
+    // a pair of zero hashes
+    TestVector { tag: b"Branch", data: &[0; 64], expected: "d9aad3d3d969929a02157db5624824bb581eaf06e18eaacfb4510a690db0a6d2" },
+    // data spanning several blocks
+    TestVector { tag: b"BIP0340/challenge", data: &[0xab; 150], expected: "ce13a0336eb6b777600a014b1c11bda1e3b986d0c550f3294de76344311b5e44" },
+];
+
+/// Panics, naming the vector, unless the algorithm hashes every vector to its expected hash, both directly and with
+/// a prepared tag.
+pub fn check_vectors<H: HashAlgorithm<32>>(vectors: &[TestVector]) {
+    for (i, vector) in vectors.iter().enumerate() {
+        let expected = HEXLOWER.decode(vector.expected.as_bytes()).unwrap();
+        assert_eq!(H::tagged_hash(vector.tag, vector.data).to_vec(), expected, "vector {} of {}", i, H::NAME);
+        assert_eq!(H::prepare_tag(vector.tag).hash(vector.data).to_vec(), expected, "vector {} of {} (prepared tag)", i, H::NAME);
+    }
+}
+
+#[test]
+fn test_sha256_vectors() {
+    check_vectors::<Sha256Algorithm>(SHA256_VECTORS);
+    check_vectors::<DigestAlgorithm<sha2::Sha256>>(SHA256_VECTORS);
+}
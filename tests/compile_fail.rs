@@ -0,0 +1,7 @@
+// The compile-time checks of HASH_SIZE (see merkle::assert_output_size), with the diagnostics they produce
+// recorded in the .stderr file next to each case. Run with TRYBUILD=overwrite to update them.
+#[test]
+fn hash_size_mismatch() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
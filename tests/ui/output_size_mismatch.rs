@@ -0,0 +1,18 @@
+use code_test::{merkle, HashAlgorithm, Sha256Algorithm};
+
+struct Truncated;
+
+impl<const N: usize> HashAlgorithm<N> for Truncated {
+    const NAME: &'static str = "truncated";
+    const OUTPUT_SIZE: usize = 32;
+    fn tagged_hash(tag: &[u8], data: &[u8]) -> [u8; N] {
+        Sha256Algorithm::tagged_hash(tag, data)[..N].try_into().unwrap()
+    }
+}
+
+// trybuild only runs `cargo check` on the cases, which does not instantiate generic functions such as
+// MerkleTree::build, so the check they make is evaluated in a constant instead
+// error: the output size of the hash algorithm differs from HASH_SIZE
+const _: () = merkle::assert_output_size::<16, Truncated>();
+
+fn main() {}
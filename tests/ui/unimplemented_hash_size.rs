@@ -0,0 +1,6 @@
+use code_test::{MerkleTree, Sha256Algorithm};
+
+fn main() {
+    // error: `Sha256Algorithm` does not produce hashes of 16 bytes
+    let _tree = MerkleTree::<16, Sha256Algorithm>::build(vec![b"aaa".to_vec()], Vec::new(), Vec::new());
+}
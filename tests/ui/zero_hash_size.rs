@@ -0,0 +1,18 @@
+use code_test::{merkle, HashAlgorithm};
+
+struct Empty;
+
+impl HashAlgorithm<0> for Empty {
+    const NAME: &'static str = "empty";
+    const OUTPUT_SIZE: usize = 0;
+    fn tagged_hash(_tag: &[u8], _data: &[u8]) -> [u8; 0] {
+        []
+    }
+}
+
+// trybuild only runs `cargo check` on the cases, which does not instantiate generic functions such as
+// MerkleTree::build, so the check they make is evaluated in a constant instead
+// error: HASH_SIZE must not be zero
+const _: () = merkle::assert_output_size::<0, Empty>();
+
+fn main() {}